@@ -0,0 +1,172 @@
+//! Bus arbitration policies, which choose whose message the bus carries next
+//! when several endpoints are waiting for it.
+//!
+//! Requesters are numbered as in `bus::Endpoint::all`: main memory first, then
+//! each cache in order of id. Each requester's messages always go out in the
+//! order it sent them, so an arbiter only ever chooses between the oldest
+//! waiting message of each.
+
+extern crate rand;
+use self::rand::{Rng, SeedableRng, StdRng};
+
+/// The arbitration policies a bus can be built with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArbiterKind {
+    /// Grant the bus to whichever message has waited longest.
+    Fifo,
+
+    /// Take turns: grant the bus to the first waiting requester after the one
+    /// that last won it.
+    RoundRobin,
+
+    /// Grant the bus to the waiting requester numbered lowest, so main memory
+    /// always goes first, and caches with higher ids can be starved.
+    FixedPriority,
+
+    /// Grant the bus to a waiting requester drawn at random, each holding one
+    /// ticket.
+    Lottery,
+}
+
+impl ArbiterKind {
+    /// Every arbitration policy.
+    pub const ALL: [ArbiterKind; 4] = [
+        ArbiterKind::Fifo,
+        ArbiterKind::RoundRobin,
+        ArbiterKind::FixedPriority,
+        ArbiterKind::Lottery,
+    ];
+
+    /// Parse an arbitration policy from its name.
+    pub fn parse(name: &str) -> Result<ArbiterKind, String> {
+        let lowercase = name.to_lowercase();
+        ArbiterKind::ALL.iter()
+            .find(|kind| kind.name() == lowercase)
+            .cloned()
+            .ok_or_else(|| {
+                let names: Vec<_> = ArbiterKind::ALL.iter().map(|kind| kind.name()).collect();
+                format!("Unknown arbitration policy: {}\nExpected one of: {}", name, names.join(", "))
+            })
+    }
+
+    /// Get this policy's name.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ArbiterKind::Fifo => "fifo",
+            ArbiterKind::RoundRobin => "round-robin",
+            ArbiterKind::FixedPriority => "fixed-priority",
+            ArbiterKind::Lottery => "lottery",
+        }
+    }
+}
+
+/// Chooses which requester wins the bus, as its policy says.
+pub struct Arbiter {
+    kind: ArbiterKind,
+    // The requester that won the bus last, for round-robin.
+    last: usize,
+    rng: StdRng,
+}
+
+impl Arbiter {
+    /// Create an arbiter following the policy `kind`. A lottery draws its
+    /// winners from the given `seed`, so that a run replays exactly.
+    pub fn new(kind: ArbiterKind, seed: usize) -> Arbiter {
+        Arbiter {
+            kind,
+            last: 0,
+            // Processors draw from `[seed, id]`, so this never repeats one.
+            rng: StdRng::from_seed(&[seed, usize::MAX][..]),
+        }
+    }
+
+    /// Get the arbiter's policy.
+    pub fn kind(&self) -> ArbiterKind {
+        self.kind
+    }
+
+    /// Choose which requester wins the bus. `waiting` has one entry per
+    /// requester: the position its oldest waiting message arrived in, among
+    /// every message sent over the bus, or `None` if it has nothing to send.
+    /// Returns `None` if nobody is waiting.
+    pub fn grant(&mut self, waiting: &[Option<u64>]) -> Option<usize> {
+        let mut requesters = waiting.iter().enumerate()
+            .filter_map(|(requester, arrival)| arrival.map(|arrival| (requester, arrival)));
+
+        let winner = match self.kind {
+            ArbiterKind::Fifo => requesters.min_by_key(|&(_, arrival)| arrival).map(|(requester, _)| requester),
+            ArbiterKind::RoundRobin => {
+                let first = (self.last + 1) % waiting.len();
+                requesters.map(|(requester, _)| requester)
+                    .min_by_key(|&requester| (requester + waiting.len() - first) % waiting.len())
+            },
+            ArbiterKind::FixedPriority => requesters.next().map(|(requester, _)| requester),
+            ArbiterKind::Lottery => {
+                let tickets: Vec<_> = requesters.map(|(requester, _)| requester).collect();
+                if tickets.is_empty() {
+                    None
+                } else {
+                    Some(tickets[self.rng.gen_range(0, tickets.len())])
+                }
+            },
+        }?;
+
+        self.last = winner;
+        Some(winner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Grant the bus `rounds` times while every requester in `waiting` keeps
+    /// waiting, and return the winners in order.
+    fn winners(arbiter: &mut Arbiter, waiting: &[Option<u64>], rounds: usize) -> Vec<usize> {
+        (0..rounds).map(|_| arbiter.grant(waiting).unwrap()).collect()
+    }
+
+    #[test]
+    fn nobody_waiting_wins_nothing() {
+        for &kind in &ArbiterKind::ALL {
+            assert_eq!(Arbiter::new(kind, 0).grant(&[None, None, None]), None, "{}", kind.name());
+        }
+    }
+
+    #[test]
+    fn fifo_grants_the_oldest_message() {
+        let mut arbiter = Arbiter::new(ArbiterKind::Fifo, 0);
+        assert_eq!(arbiter.grant(&[Some(7), Some(3), None, Some(5)]), Some(1));
+        assert_eq!(arbiter.grant(&[Some(7), Some(8), None, Some(5)]), Some(3));
+        assert_eq!(arbiter.grant(&[Some(7), Some(8), Some(9), None]), Some(0));
+    }
+
+    #[test]
+    fn round_robin_takes_turns_after_the_last_winner() {
+        let mut arbiter = Arbiter::new(ArbiterKind::RoundRobin, 0);
+        // Requester 0 counts as the last winner, so 1 goes first.
+        assert_eq!(winners(&mut arbiter, &[Some(0); 4], 6), vec![1, 2, 3, 0, 1, 2]);
+        // Requesters not waiting are skipped, and the turn wraps around.
+        assert_eq!(winners(&mut arbiter, &[Some(0), None, Some(0), None], 3), vec![0, 2, 0]);
+    }
+
+    #[test]
+    fn fixed_priority_starves_higher_requesters() {
+        let mut arbiter = Arbiter::new(ArbiterKind::FixedPriority, 0);
+        assert_eq!(winners(&mut arbiter, &[None, Some(9), Some(0), Some(0)], 3), vec![1, 1, 1]);
+        assert_eq!(arbiter.grant(&[Some(9), Some(0), None, Some(0)]), Some(0));
+        assert_eq!(arbiter.grant(&[None, None, Some(9), Some(0)]), Some(2));
+    }
+
+    #[test]
+    fn lottery_replays_from_its_seed() {
+        let waiting = [Some(0), None, Some(0), Some(0), Some(0)];
+        let draws = winners(&mut Arbiter::new(ArbiterKind::Lottery, 3), &waiting, 50);
+        assert_eq!(winners(&mut Arbiter::new(ArbiterKind::Lottery, 3), &waiting, 50), draws);
+        assert!(draws.iter().all(|&winner| winner != 1));
+        for requester in &[0, 2, 3, 4] {
+            assert!(draws.contains(requester), "Requester {} never won", requester);
+        }
+        assert_ne!(winners(&mut Arbiter::new(ArbiterKind::Lottery, 4), &waiting, 50), draws);
+    }
+}
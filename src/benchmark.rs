@@ -1,151 +1,492 @@
 //! Provides benchmarks for various memory reading and writing scenarios.
 
-extern crate chrono;
-
 extern crate rand;
 use self::rand::distributions::IndependentSample;
+use self::rand::{SeedableRng, StdRng};
 
-use std::mem;
-use std::sync::atomic;
+use std::cell::Cell;
 
+use bus;
+use coherence;
+use config;
+use engine::{Access, Cycle, Engine, Workload};
+use hierarchy::InclusionStats;
 use main_memory;
 use memory_cache;
-
-static EPOCH: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
-
-/// Synchronize each phase of the benchmark between memory cache threads. We
-/// want them to run each phase concurrently, and not let some get too far ahead
-/// and others get behind. The benchmark tests interaction between sharing (or
-/// lack thereof) so it would not tell us much if the phases aren't
-/// synchronized! Synchronization works by waiting for the appropriate `EPOCH`
-/// value. As each memory cache thread finishes a phase, it increments `EPOCH`
-/// and waits for all the others to increment it as well before continuing.
-fn synchronize_phase(cache: &mut memory_cache::MemoryCache,
-                     timer: &mut chrono::DateTime<chrono::UTC>,
-                     phase: &mut usize, phase_name: &str) {
-    assert!(*phase > 0);
-
-    cache.flush();
-
-    let start_of_this_phase = (*phase - 1) * memory_cache::NUMBER_OF_CACHES;
-    let start_of_next_phase = *phase * memory_cache::NUMBER_OF_CACHES;
-    let epoch = EPOCH.load(atomic::Ordering::SeqCst);
-    assert!(start_of_this_phase <= epoch && epoch < start_of_next_phase,
-            "epoch in correct phase: {} <= {} < {}", start_of_this_phase, epoch, start_of_next_phase);
-
-    if EPOCH.fetch_add(1, atomic::Ordering::SeqCst) != start_of_next_phase - 1 {
-        loop {
-            let epoch = EPOCH.load(atomic::Ordering::SeqCst);
-            if epoch >= start_of_next_phase {
-                break;
-            }
-        }
-    }
-
-    let now = chrono::UTC::now();
-    println!("Cache {}: {}:\n\t{} ms\n\t{:.*} % cache miss\n",
-             cache.id, phase_name, (now - *timer).num_milliseconds(), 3, cache.miss_percent());
-    cache.reset_stats();
-    mem::replace(timer, now);
-
-    // Continue on to the next phase!
-    cache.empty();
-    *phase += 1;
+use prefetch;
+use system::SystemConfig;
+
+/// Counts of one class of caches' reads and writes.
+struct ClassCounts {
+    name: String,
+    caches: usize,
+    accesses: Cell<usize>,
+    misses: Cell<usize>,
+    lookup_cycles: Cell<usize>,
+    access_cycles: Cell<usize>,
 }
 
-/// Benchmark the various scenarios using the given cache.
-pub fn benchmark(mut cache: memory_cache::MemoryCache) {
-    let mut timer = chrono::UTC::now();
-    let mut phase = 1;
-    let id = cache.id;
-
-    // Read every byte in memory sequentially.
+/// Counts of each class of caches' reads and writes during a phase, added to
+/// for each cache at the end of the phase, so that the classes can be
+/// compared.
+pub struct ClassStats {
+    classes: Vec<ClassCounts>,
+}
 
-    for i in 0..main_memory::MAIN_MEMORY_SIZE {
-        cache.read(main_memory::Address(i));
+impl ClassStats {
+    /// Create the counts for the given classes of caches, all zero.
+    pub fn new(classes: &[config::CacheClass]) -> ClassStats {
+        ClassStats {
+            classes: classes.iter()
+                .map(|class| ClassCounts {
+                    name: class.name.clone(),
+                    caches: class.count,
+                    accesses: Cell::new(0),
+                    misses: Cell::new(0),
+                    lookup_cycles: Cell::new(0),
+                    access_cycles: Cell::new(0),
+                })
+                .collect(),
+        }
     }
 
-    synchronize_phase(&mut cache, &mut timer, &mut phase, "Sequential Read");
-
-    // Write to every byte in memory sequentially.
-
-    for i in 0..main_memory::MAIN_MEMORY_SIZE {
-        cache.write(main_memory::Address(i), id);
+    /// Are there several classes to compare?
+    pub fn is_heterogeneous(&self) -> bool {
+        self.classes.len() > 1
     }
 
-    synchronize_phase(&mut cache, &mut timer, &mut phase, "Sequential Write");
+    /// Get the class of the cache with the given id. Caches are numbered in
+    /// the order of their classes.
+    fn class_of(&self, id: memory_cache::MemoryCacheId) -> usize {
+        let mut first = 0;
+        for (class, counts) in self.classes.iter().enumerate() {
+            first += counts.caches;
+            if (id as usize) < first {
+                return class;
+            }
+        }
+        panic!("Cache {} should belong to a class", id);
+    }
 
-    // Read MAIN_MEMORY_SIZE random bytes.
+    /// Get the name of the given class.
+    pub fn name(&self, class: usize) -> &str {
+        &self.classes[class].name
+    }
 
-    let memory_range = rand::distributions::Range::new(0, main_memory::MAIN_MEMORY_SIZE);
-    let mut rng = rand::thread_rng();
+    /// Add the reads and writes `cache` has made so far this phase to its
+    /// class's counts.
+    pub fn record<P>(&self, cache: &memory_cache::MemoryCache<P>)
+        where P: coherence::CoherenceProtocol
+    {
+        let counts = &self.classes[self.class_of(cache.id)];
+        counts.accesses.set(counts.accesses.get() + cache.accesses());
+        counts.misses.set(counts.misses.get() + cache.misses());
+        counts.lookup_cycles.set(counts.lookup_cycles.get() + cache.lookup_cycles());
+        counts.access_cycles.set(counts.access_cycles.get() + cache.access_cycles());
+    }
 
-    for _ in 0..main_memory::MAIN_MEMORY_SIZE {
-        let addr = main_memory::Address(memory_range.ind_sample(&mut rng));
-        cache.read(addr);
+    /// Report every class's counts for the phase, and reset them to zero.
+    fn take_report(&self, phase_name: &str) -> String {
+        let mut report = format!("Classes: {}:\n", phase_name);
+        for counts in &self.classes {
+            let accesses = counts.accesses.replace(0);
+            let misses = counts.misses.replace(0);
+            let lookup_cycles = counts.lookup_cycles.replace(0);
+            let access_cycles = counts.access_cycles.replace(0);
+            report.push_str(&format!("\t{}: {} caches, {} accesses, {:.*} % cache miss, {:.*} cycles average lookup latency, {:.*} cycles average memory access time\n",
+                                     counts.name, counts.caches, accesses,
+                                     3, misses as f64 / accesses.max(1) as f64 * 100.0,
+                                     1, lookup_cycles as f64 / accesses.max(1) as f64,
+                                     1, access_cycles as f64 / accesses.max(1) as f64));
+        }
+        report
     }
+}
 
-    synchronize_phase(&mut cache, &mut timer, &mut phase, "Random Read");
+/// One phase of the benchmark, as a workload for every cache's processor.
+struct PhaseWorkload<'a> {
+    phase: Phase,
+    system: SystemConfig,
+    rngs: &'a mut [StdRng],
+    memory_stats: &'a main_memory::MemoryStats,
+    // The number of accesses each processor has made.
+    made: Vec<usize>,
+    // How each cache's inclusion policy had moved blocks, and when it was done.
+    inclusion: Vec<InclusionStats>,
+    finished: Vec<Cycle>,
+}
 
-    // Write MAIN_MEMORY_SIZE random bytes.
+impl<'a, P: coherence::CoherenceProtocol> Workload<P> for PhaseWorkload<'a> {
+    fn next_access(&mut self, id: memory_cache::MemoryCacheId) -> Option<Access> {
+        let index = id as usize;
+        let i = self.made[index];
+        if i == self.system.main_memory_size {
+            return None;
+        }
 
-    for _ in 0..main_memory::MAIN_MEMORY_SIZE {
-        let addr = main_memory::Address(memory_range.ind_sample(&mut rng));
-        cache.write(addr, id);
+        self.made[index] += 1;
+        Some(self.phase.access(&self.system, id, i, &mut self.rngs[index]))
     }
 
-    synchronize_phase(&mut cache, &mut timer, &mut phase, "Random Write");
-
-    // Read a thread-unique chunk of bytes sequentially and repeatedly, for a
-    // total of MAIN_MEMORY_SIZE reads.
+    fn finish(&mut self, cache: &mut memory_cache::MemoryCache<P>, now: Cycle) {
+        // Measure how much capacity is spent on duplicates before the flush
+        // changes what we hold.
+        let index = cache.id as usize;
+        self.inclusion[index] = cache.inclusion_stats();
+        self.memory_stats.record_llc_duplicated(cache.blocks().into_iter()
+            .filter(|&block| self.memory_stats.is_in_llc(block))
+            .count());
+
+        cache.flush();
+        self.finished[index] = now;
+    }
+}
 
-    let chunk_size = memory_cache::CACHE_SIZE * main_memory::BLOCK_SIZE;
-    let unique_chunk_offset = id as usize * chunk_size;
+/// The bus traffic of one phase of a machine, for comparing one coherence
+/// protocol's with another's on the same machine.
+#[derive(Clone, Copy, Debug)]
+pub struct PhaseTraffic {
+    /// The phase.
+    pub phase: Phase,
+    /// The number of messages sent over the bus.
+    pub messages: usize,
+    /// The number of blocks sent in response to reads.
+    pub read_responses: usize,
+    /// The number of blocks written to main memory.
+    pub writes: usize,
+}
 
-    for i in 0..main_memory::MAIN_MEMORY_SIZE {
-        let addr = main_memory::Address(unique_chunk_offset + (i % chunk_size));
-        cache.read(addr);
+/// Report how much more or less bus traffic each phase of a machine following
+/// `protocol` made than the same phase of the same machine following
+/// `baseline`.
+pub fn report_comparison(protocol: &str, traffic: &[PhaseTraffic], baseline: &str, baseline_traffic: &[PhaseTraffic]) {
+    // As a percentage of the baseline's count, or as a count if the baseline
+    // made none.
+    let change = |count: usize, baseline: usize| match (count, baseline) {
+        (0, 0) => "+0.0 %".to_string(),
+        (_, 0) => format!("{} more", count),
+        _ => format!("{:+.1} %", 100.0 * (count as f64 - baseline as f64) / baseline as f64),
+    };
+
+    let mut report = format!("Compared with {}: {}:\n", baseline, protocol);
+    for (phase, base) in traffic.iter().zip(baseline_traffic) {
+        report.push_str(&format!("\t{}: {} messages, {} read responses, {} writes to main memory\n",
+                                 phase.phase.name(),
+                                 change(phase.messages, base.messages),
+                                 change(phase.read_responses, base.read_responses),
+                                 change(phase.writes, base.writes)));
     }
+    println!("{}", report);
+}
 
-    synchronize_phase(&mut cache, &mut timer, &mut phase, "Thread-Unique Chunk Read");
+/// Report the bus traffic, main memory accesses and, if there are several
+/// classes of caches, each class's accesses for a phase of a machine with the
+/// given number of `caches`, which took `cycles` cycles, and reset them.
+/// Returns the phase's bus traffic.
+fn report_machine(phase: Phase,
+                  caches: usize,
+                  cycles: Cycle,
+                  class_stats: &ClassStats,
+                  bus_stats: &bus::BusStats,
+                  memory_stats: &main_memory::MemoryStats)
+                  -> PhaseTraffic {
+    let phase_name = phase.name();
+    let traffic = PhaseTraffic {
+        phase,
+        messages: bus_stats.total(),
+        read_responses: bus_stats.count(bus::MessageKind::ReadResponse),
+        writes: bus_stats.count(bus::MessageKind::WriteRequest),
+    };
+
+    // Of every link's cycles, on a point-to-point network.
+    let utilization = |occupied: usize| 100.0 * occupied as f64 / (cycles.max(1) as f64 * bus_stats.links() as f64);
+    let mut contention = String::new();
+    for endpoint in bus::Endpoint::all(caches) {
+        let name = match endpoint {
+            bus::Endpoint::MainMemory => "Main memory".to_string(),
+            bus::Endpoint::Cache(id) => format!("Cache {}", id),
+        };
+        contention.push_str(&format!("\t{}: {} messages, {:.1} cycles average queuing delay, {:.1} % utilization\n",
+                                     name, bus_stats.granted(endpoint), bus_stats.queuing_delay(endpoint),
+                                     utilization(bus_stats.occupied(endpoint))));
+    }
 
-    // Write a thread-unique chunk of bytes sequentially and repeatedly, for a
-    // total of MAIN_MEMORY_SIZE writes.
+    if bus_stats.unfiltered() > 0 {
+        let saved = bus_stats.unfiltered().saturating_sub(bus_stats.deliveries());
+        contention.push_str(&format!("\tSnoop filter: {} deliveries saved, {:.1} % of them, {} back-invalidations\n",
+                                     saved, 100.0 * saved as f64 / bus_stats.unfiltered() as f64,
+                                     bus_stats.filter_back_invalidations()));
+    }
+    if bus_stats.links() > 1 {
+        contention.push_str(&format!("\t{:.1} hops, {:.1} cycles average network latency\n",
+                                     bus_stats.average_hops(), bus_stats.average_network_latency()));
+    }
 
-    for i in 0..main_memory::MAIN_MEMORY_SIZE {
-        let addr = main_memory::Address(unique_chunk_offset + (i % chunk_size));
-        cache.write(addr, id);
+    println!("Bus: {}:\n\t{} messages\n\t{} deliveries\n\t{} extra deliveries\n\t{} back-invalidations\n\t{} read responses\n\t{} upgrades\n\t{} updates\n\t{} writes to main memory\n\t{} clean eviction notices\n\t{:.1} % utilization\n{}",
+             phase_name,
+             bus_stats.total(),
+             bus_stats.deliveries(),
+             bus_stats.extra_deliveries(),
+             bus_stats.count(bus::MessageKind::BackInvalidate),
+             bus_stats.count(bus::MessageKind::ReadResponse),
+             bus_stats.count(bus::MessageKind::UpgradeRequest),
+             bus_stats.count(bus::MessageKind::Update),
+             bus_stats.count(bus::MessageKind::WriteRequest),
+             bus_stats.count(bus::MessageKind::PutClean),
+             utilization(bus_stats.total_occupied()),
+             contention);
+    bus_stats.reset();
+
+    println!("Memory: {}:\n\t{} last-level cache hits\n\t{} last-level cache misses\n\t{} last-level cache back-invalidations\n\t{} last-level cache victim fills\n\t{} blocks duplicated in the last-level cache\n\t{} DRAM reads\n\t{} DRAM writes\n\t{} row buffer hits, {} misses, {} conflicts\n\t{:.1} cycles average DRAM read latency\n",
+             phase_name,
+             memory_stats.llc_hits(),
+             memory_stats.llc_misses(),
+             memory_stats.llc_back_invalidations(),
+             memory_stats.llc_victim_fills(),
+             memory_stats.llc_duplicated(),
+             memory_stats.dram_reads(),
+             memory_stats.dram_writes(),
+             memory_stats.row_hits(),
+             memory_stats.row_misses(),
+             memory_stats.row_conflicts(),
+             memory_stats.average_dram_read_latency());
+    memory_stats.reset();
+
+    if class_stats.is_heterogeneous() {
+        println!("{}", class_stats.take_report(phase_name));
     }
+    traffic
+}
 
-    synchronize_phase(&mut cache, &mut timer, &mut phase, "Thread-Unique Chunk Write");
+/// Report how `cache` did during a phase, which took it `cycles` cycles, and
+/// during which its inclusion policy moved blocks as `inclusion` says.
+fn report_cache<P>(cache: &memory_cache::MemoryCache<P>,
+                   class_stats: &ClassStats,
+                   inclusion: InclusionStats,
+                   cycles: Cycle,
+                   phase_name: &str)
+    where P: coherence::CoherenceProtocol
+{
+    let name = if class_stats.is_heterogeneous() {
+        format!("Cache {} ({})", cache.id, class_stats.name(class_stats.class_of(cache.id)))
+    } else {
+        format!("Cache {}", cache.id)
+    };
+    let mut report = format!("{}: {}:\n\t{} cycles\n\t{:.*} cycles average memory access time\n\t{:.*} % cache miss\n\t{:.*} cycles average lookup latency\n\t{} read-exclusive misses, {} upgrades, {} upgrades lost to another cache\n",
+                             name, phase_name, cycles, 1, cache.average_access_latency(), 3, cache.miss_percent(),
+                             1, cache.average_lookup_latency(), cache.exclusive_misses(), cache.upgrades(),
+                             cache.lost_upgrades());
+    for (level, (config, stats)) in cache.levels().iter().zip(cache.level_stats()).enumerate() {
+        report.push_str(&format!("\tL{}: {} hits, {} misses, {} fills, {} {} evictions\n",
+                                 level + 1, stats.hits, stats.misses, stats.replacement.fills,
+                                 stats.replacement.evictions, config.replacement.name()));
+    }
+    if cache.levels().len() > 1 {
+        report.push_str(&format!("\t{}: {} back-invalidations, {} victim fills, {} blocks duplicated\n",
+                                 cache.inclusion().name(), inclusion.back_invalidations,
+                                 inclusion.victim_fills, inclusion.duplicated));
+    }
+    if let Some(victims) = cache.victim_stats() {
+        report.push_str(&format!("\tVictim buffer: {} entries, {} hits, {} fills, {} evictions\n",
+                                 cache.victim_entries(), cache.victim_hits(), victims.fills, victims.evictions));
+    }
+    if cache.prefetcher() != prefetch::PrefetcherKind::None {
+        let prefetches = cache.prefetch_stats();
+        report.push_str(&format!("\tPrefetcher {}: {} issued, {} useful, {} late, {:.*} % accuracy, {:.*} % coverage, {} evicted unused, {} invalidated unused\n",
+                                 cache.prefetcher().name(), prefetches.issued, prefetches.useful, prefetches.late,
+                                 1, prefetches.accuracy_percent(), 1, cache.prefetch_coverage_percent(),
+                                 prefetches.evicted_unused, prefetches.invalidated_unused));
+    }
+    if cache.outstanding() > 1 {
+        report.push_str(&format!("\t{} outstanding misses, {} conflicting requests in flight\n",
+                                 cache.outstanding(), cache.conflicts()));
+    }
+    println!("{}", report);
+}
 
-    // Read the same chunk of bytes across all threads, sequentially and
-    // repeatedly, for a total of MAIN_MEMORY_SIZE reads.
+/// The phases of the benchmark, each a different memory access scenario.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Read every byte in memory sequentially.
+    SequentialRead,
+    /// Write to every byte in memory sequentially.
+    SequentialWrite,
+    /// Read random bytes.
+    RandomRead,
+    /// Write random bytes.
+    RandomWrite,
+    /// Read a chunk of bytes unique to each cache, repeatedly.
+    UniqueChunkRead,
+    /// Write a chunk of bytes unique to each cache, repeatedly.
+    UniqueChunkWrite,
+    /// Read the same chunk of bytes in every cache, repeatedly.
+    SharedChunkRead,
+    /// Write the same chunk of bytes in every cache, repeatedly.
+    SharedChunkWrite,
+    /// Write different bytes of the same chunk in every cache, repeatedly.
+    FalseSharingChunkWrite,
+    /// Write a shared chunk of bytes in one cache while every other cache
+    /// reads it, repeatedly.
+    ProducerConsumerChunk,
+}
 
-    for i in 0..main_memory::MAIN_MEMORY_SIZE {
-        let addr = main_memory::Address(i % chunk_size);
-        cache.read(addr);
+impl Phase {
+    /// Every phase, in the order the full benchmark runs them.
+    pub const ALL: [Phase; 10] = [
+        Phase::SequentialRead,
+        Phase::SequentialWrite,
+        Phase::RandomRead,
+        Phase::RandomWrite,
+        Phase::UniqueChunkRead,
+        Phase::UniqueChunkWrite,
+        Phase::SharedChunkRead,
+        Phase::SharedChunkWrite,
+        Phase::FalseSharingChunkWrite,
+        Phase::ProducerConsumerChunk,
+    ];
+
+    /// Parse a phase from its name, in lowercase with words separated by
+    /// dashes, such as `sequential-read`.
+    pub fn parse(name: &str) -> Result<Phase, String> {
+        let lowercase = name.to_lowercase();
+        Phase::ALL.iter()
+            .find(|phase| phase.key() == lowercase)
+            .cloned()
+            .ok_or_else(|| {
+                let names: Vec<_> = Phase::ALL.iter().map(|phase| phase.key()).collect();
+                format!("Unknown benchmark phase: {}\nExpected one of: {}", name, names.join(", "))
+            })
     }
 
-    synchronize_phase(&mut cache, &mut timer, &mut phase, "Shared Chunk Read");
-
-    // Write the same chunk of bytes across all threads, sequentially and
-    // repeatedly, for a total of MAIN_MEMORY_SIZE writes.
+    /// Get this phase's name.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Phase::SequentialRead => "Sequential Read",
+            Phase::SequentialWrite => "Sequential Write",
+            Phase::RandomRead => "Random Read",
+            Phase::RandomWrite => "Random Write",
+            Phase::UniqueChunkRead => "Thread-Unique Chunk Read",
+            Phase::UniqueChunkWrite => "Thread-Unique Chunk Write",
+            Phase::SharedChunkRead => "Shared Chunk Read",
+            Phase::SharedChunkWrite => "Shared Chunk Write",
+            Phase::FalseSharingChunkWrite => "False-Sharing Chunk Write",
+            Phase::ProducerConsumerChunk => "Producer-Consumer Chunk",
+        }
+    }
 
-    for i in 0..main_memory::MAIN_MEMORY_SIZE {
-        let addr = main_memory::Address(i % chunk_size);
-        cache.write(addr, id);
+    /// This phase's name as `parse` expects it.
+    fn key(&self) -> String {
+        self.name().to_lowercase().replace(' ', "-")
     }
 
-    synchronize_phase(&mut cache, &mut timer, &mut phase, "Shared Chunk Write");
+    /// The `i`th access the processor of cache `id` makes during this phase in
+    /// the given system. Each processor makes as many accesses as main memory
+    /// has bytes.
+    fn access<R: rand::Rng>(&self, system: &SystemConfig, id: memory_cache::MemoryCacheId, i: usize, rng: &mut R)
+                            -> Access {
+        let memory_range = rand::distributions::Range::new(0, system.main_memory_size);
+        let chunk_size = system.cache_size * system.block_size;
+        let unique_chunk_offset = id as usize * chunk_size;
+
+        match *self {
+            // Read every byte in memory sequentially.
+            Phase::SequentialRead => Access::Read(main_memory::Address(i)),
+
+            // Write to every byte in memory sequentially.
+            Phase::SequentialWrite => Access::Write(main_memory::Address(i), id),
+
+            // Read random bytes.
+            Phase::RandomRead => Access::Read(main_memory::Address(memory_range.ind_sample(rng))),
+
+            // Write random bytes.
+            Phase::RandomWrite => Access::Write(main_memory::Address(memory_range.ind_sample(rng)), id),
+
+            // Read a chunk of bytes unique to each cache, sequentially and
+            // repeatedly.
+            Phase::UniqueChunkRead => Access::Read(main_memory::Address(unique_chunk_offset + (i % chunk_size))),
+
+            // Write a chunk of bytes unique to each cache, sequentially and
+            // repeatedly.
+            Phase::UniqueChunkWrite => {
+                Access::Write(main_memory::Address(unique_chunk_offset + (i % chunk_size)), id)
+            },
+
+            // Read the same chunk of bytes in every cache, sequentially and
+            // repeatedly.
+            Phase::SharedChunkRead => Access::Read(main_memory::Address(i % chunk_size)),
+
+            // Write the same chunk of bytes in every cache, sequentially and
+            // repeatedly.
+            Phase::SharedChunkWrite => Access::Write(main_memory::Address(i % chunk_size), id),
+
+            // Write the same chunk of bytes in every cache, sequentially and
+            // repeatedly.
+            Phase::FalseSharingChunkWrite => {
+                Access::Write(main_memory::Address((i * id as usize) % chunk_size), id)
+            },
+
+            // Cache 0 produces a shared chunk of bytes by writing to it
+            // sequentially and repeatedly, while every other cache consumes it
+            // by reading it sequentially and repeatedly.
+            Phase::ProducerConsumerChunk => {
+                if id == 0 {
+                    Access::Write(main_memory::Address(i % chunk_size), i as u8)
+                } else {
+                    Access::Read(main_memory::Address(i % chunk_size))
+                }
+            },
+        }
+    }
+}
 
-    // Write the same chunk of bytes across all threads, sequentially and
-    // repeatedly, for a total of MAIN_MEMORY_SIZE writes.
+/// Benchmark the given `phases`, in order, on the machine `engine` runs. The
+/// processor of each cache draws its random addresses from its own generator,
+/// seeded with `seed` and the cache's id, so that the same seed always makes
+/// the same accesses. Returns each phase's bus traffic, in order.
+pub fn benchmark<P, T>(engine: &mut Engine<P, T>,
+                       phases: &[Phase],
+                       seed: usize,
+                       class_stats: &ClassStats,
+                       bus_stats: &bus::BusStats,
+                       memory_stats: &main_memory::MemoryStats)
+                       -> Vec<PhaseTraffic>
+    where P: coherence::CoherenceProtocol,
+          T: bus::Topology
+{
+    let system = *engine.caches()[0].system();
+    let caches = system.number_of_caches;
+    let mut rngs: Vec<StdRng> = (0..caches)
+        .map(|id| StdRng::from_seed(&[seed, id][..]))
+        .collect();
+
+    let mut traffic = vec![];
+    for &phase in phases {
+        let start = engine.now();
+        let mut workload = PhaseWorkload {
+            phase,
+            system,
+            rngs: &mut rngs,
+            memory_stats,
+            made: vec![0; caches],
+            inclusion: vec![InclusionStats::default(); caches],
+            finished: vec![start; caches],
+        };
+        engine.run(&mut workload);
+
+        for cache in engine.caches() {
+            class_stats.record(cache);
+        }
+        traffic.push(report_machine(phase, caches, engine.now() - start, class_stats, bus_stats, memory_stats));
+        for cache in engine.caches() {
+            let index = cache.id as usize;
+            report_cache(cache, class_stats, workload.inclusion[index], workload.finished[index] - start, phase.name());
+        }
 
-    for i in 0..main_memory::MAIN_MEMORY_SIZE {
-        let addr = main_memory::Address((i * id as usize) % chunk_size);
-        cache.write(addr, id);
+        // Continue on to the next phase!
+        for cache in engine.caches_mut() {
+            cache.reset_stats();
+            cache.empty();
+        }
     }
-
-    synchronize_phase(&mut cache, &mut timer, &mut phase, "False-Sharing Chunk Write");
+    traffic
 }
@@ -1,22 +1,52 @@
 //! The bus connects each memory cache to each other and main memory. It
-//! forwards messages sent from one of these actors to all the others.
+//! forwards messages sent from one of these actors to others, as decided by
+//! its `Topology`: either broadcasting them to everyone, like a snooping bus,
+//! or sending them only to the actors involved, like a directory.
+//!
+//! Actors do not hold the bus themselves. Each puts what it sends in its
+//! `Outbox`, which the simulation engine empties onto the bus after every
+//! step the actor takes. Messages then wait for the bus with their sender,
+//! until its `Arbiter` lets them go.
+//!
+//! Physically, the bus may be a single shared bus, or a point-to-point
+//! `Network` that delivers each message after its own delay. Either way, the
+//! actors neither know nor care.
+//!
+//! The bus is split-transaction: a request and its response are separate
+//! messages, and the bus carries others in between. Each cache may have
+//! several requests in flight, so requests carry a `Tag`, which every retry of
+//! them and every response to them carries too.
 
-use std::sync::mpsc;
-use std::thread;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::mem;
+use std::rc::Rc;
 
+use arbiter::Arbiter;
+use directory::sharers::SharerEncoding;
+use engine::Cycle;
 use main_memory;
 use memory_cache;
+use network::{self, Network, NetworkKind};
+use snoop_filter::{SnoopFilter, SnoopFilterKind};
+use system::SystemConfig;
 
-#[inline(always)]
-fn ignore<T>(_: T) { }
+/// Identifies one of a cache's transactions on the bus, from its request
+/// until the response that completes it. Each cache numbers its own, so a
+/// transaction is only identified by the requester's id together with its
+/// tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tag(pub u32);
 
 /// The various types of messages we can send on the bus.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum BusMessage {
     /// A request to read a block from main memory.
     ReadRequest {
         /// Which memory cache is requesting the read.
         who: memory_cache::MemoryCacheId,
+        /// The requester's transaction.
+        tag: Tag,
         /// Which block of memory.
         block: main_memory::Block,
     },
@@ -25,13 +55,18 @@ pub enum BusMessage {
     ReadResponse {
         /// Which memory cache the response is for.
         who: memory_cache::MemoryCacheId,
+        /// The requester's transaction.
+        tag: Tag,
         /// Who sent the response.
         from: ResponseSender,
         /// Which block of memory.
         block: main_memory::Block,
         /// The block's data. If `None`, the data is unavailable due to another
         /// cache holding it exclusively for writing.
-        data: Option<[u8; main_memory::BLOCK_SIZE]>,
+        data: Option<Vec<u8>>,
+        /// Whether other caches may also hold the block. Always true when the
+        /// response comes from another cache.
+        shared: bool,
     },
 
     /// A request to exclusively read a block from main memory, with intent to
@@ -39,6 +74,8 @@ pub enum BusMessage {
     ReadExclusiveRequest {
         /// Which memory cache is requesting the exclusive read.
         who: memory_cache::MemoryCacheId,
+        /// The requester's transaction.
+        tag: Tag,
         /// Which block of memory.
         block: main_memory::Block,
     },
@@ -47,11 +84,13 @@ pub enum BusMessage {
     ReadExclusiveResponse {
         /// Which memory cache the response is for.
         who: memory_cache::MemoryCacheId,
+        /// The requester's transaction.
+        tag: Tag,
         /// Which block of memory.
         block: main_memory::Block,
         /// The block's data. If `None`, the data is unavailable due to another
         /// cache holding it exclusively for writing.
-        data: Option<[u8; main_memory::BLOCK_SIZE]>,
+        data: Option<Vec<u8>>,
     },
 
     /// A request to write a block back to main memory.
@@ -59,24 +98,314 @@ pub enum BusMessage {
         /// Which block of memory.
         block: main_memory::Block,
         /// The data to be written to the block.
-        data: [u8; main_memory::BLOCK_SIZE],
+        data: Vec<u8>,
+        /// The memory cache writing the block back, if it no longer holds it,
+        /// so that a snoop filter can stop sending it messages about the
+        /// block.
+        evicted: Option<memory_cache::MemoryCacheId>,
     },
 
-    /// Request from one cache telling others to invalidate the given block if
-    /// they have it.
-    InvalidateRequest {
-        /// Which memory cache is requesting invalidation.
+    /// A request from a cache holding a valid copy of a block for the right
+    /// to write it, without sending it the data again (a BusUpgr). Every other
+    /// cache invalidates its copy. Main memory decides whether the upgrade
+    /// succeeds: it fails if another cache was given the block to write since
+    /// the requester read it, and the requester must then read it exclusively.
+    UpgradeRequest {
+        /// Which memory cache is upgrading.
         who: memory_cache::MemoryCacheId,
-        /// Which block should be invalidated.
+        /// The requester's transaction.
+        tag: Tag,
+        /// Which block of memory.
         block: main_memory::Block,
     },
 
-    /// The response to a `InvalidateRequest`.
-    InvalidateResponse {
+    /// The response to an `UpgradeRequest`.
+    UpgradeResponse {
         /// The memory cache this is a response to.
         who: memory_cache::MemoryCacheId,
-        /// Whether or not the block was successfully invalidated.
+        /// The requester's transaction.
+        tag: Tag,
+        /// Whether or not the upgrade succeeded.
         ok: bool,
+    },
+
+    /// A request from the memory side of the bus, telling every cache that
+    /// receives it to invalidate the given block, writing it back first if it
+    /// is dirty.
+    BackInvalidate {
+        /// Which block should be invalidated.
+        block: main_memory::Block,
+    },
+
+    /// A write to a shared block, broadcast by an update-based protocol so
+    /// that the other caches holding the block can update their copies.
+    Update {
+        /// Which memory cache wrote to the block.
+        who: memory_cache::MemoryCacheId,
+        /// The address that was written.
+        address: main_memory::Address,
+        /// The value written to the address.
+        value: u8,
+        /// The bus' shared line: whether any other cache held the block as the
+        /// update went over the bus. The writer sends it lowered, and every
+        /// other cache holding the block raises it.
+        shared: bool,
+    },
+
+    /// A notice that a cache evicted its clean copy of a block (a PutS or
+    /// PutE), so that a directory can stop sending it messages about the
+    /// block. Caches only send these when configured to.
+    PutClean {
+        /// Which memory cache evicted the block.
+        who: memory_cache::MemoryCacheId,
+        /// Which block was evicted.
+        block: main_memory::Block,
+    },
+}
+
+impl BusMessage {
+    /// Get the kind of this message.
+    pub fn kind(&self) -> MessageKind {
+        match *self {
+            BusMessage::ReadRequest { .. } => MessageKind::ReadRequest,
+            BusMessage::ReadResponse { .. } => MessageKind::ReadResponse,
+            BusMessage::ReadExclusiveRequest { .. } => MessageKind::ReadExclusiveRequest,
+            BusMessage::ReadExclusiveResponse { .. } => MessageKind::ReadExclusiveResponse,
+            BusMessage::WriteRequest { .. } => MessageKind::WriteRequest,
+            BusMessage::UpgradeRequest { .. } => MessageKind::UpgradeRequest,
+            BusMessage::UpgradeResponse { .. } => MessageKind::UpgradeResponse,
+            BusMessage::BackInvalidate { .. } => MessageKind::BackInvalidate,
+            BusMessage::Update { .. } => MessageKind::Update,
+            BusMessage::PutClean { .. } => MessageKind::PutClean,
+        }
+    }
+
+    /// Does this message carry a block's data?
+    pub fn carries_block(&self) -> bool {
+        match *self {
+            BusMessage::ReadResponse { ref data, .. } |
+            BusMessage::ReadExclusiveResponse { ref data, .. } => data.is_some(),
+            BusMessage::WriteRequest { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// The kinds of `BusMessage`, without their payloads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    /// A `BusMessage::ReadRequest`.
+    ReadRequest,
+    /// A `BusMessage::ReadResponse`.
+    ReadResponse,
+    /// A `BusMessage::ReadExclusiveRequest`.
+    ReadExclusiveRequest,
+    /// A `BusMessage::ReadExclusiveResponse`.
+    ReadExclusiveResponse,
+    /// A `BusMessage::WriteRequest`.
+    WriteRequest,
+    /// A `BusMessage::UpgradeRequest`.
+    UpgradeRequest,
+    /// A `BusMessage::UpgradeResponse`.
+    UpgradeResponse,
+    /// A `BusMessage::BackInvalidate`.
+    BackInvalidate,
+    /// A `BusMessage::Update`.
+    Update,
+    /// A `BusMessage::PutClean`.
+    PutClean,
+}
+
+impl MessageKind {
+    /// Every kind of message.
+    pub const ALL: &'static [MessageKind] = &[
+        MessageKind::ReadRequest,
+        MessageKind::ReadResponse,
+        MessageKind::ReadExclusiveRequest,
+        MessageKind::ReadExclusiveResponse,
+        MessageKind::WriteRequest,
+        MessageKind::UpgradeRequest,
+        MessageKind::UpgradeResponse,
+        MessageKind::BackInvalidate,
+        MessageKind::Update,
+        MessageKind::PutClean,
+    ];
+}
+
+/// Add `count` to `counter`.
+fn add(counter: &Cell<usize>, count: usize) {
+    counter.set(counter.get() + count);
+}
+
+/// Counts of the messages sent over the bus, by kind, and how long each
+/// endpoint waited for and held the bus. Shared between the bus, which records
+/// each message as it forwards it, and whoever is reporting on the traffic.
+pub struct BusStats {
+    counts: Vec<Cell<usize>>,
+    deliveries: Cell<usize>,
+    extra_deliveries: Cell<usize>,
+    unfiltered: Cell<usize>,
+    filter_back_invalidations: Cell<usize>,
+    links: usize,
+    hops: Cell<usize>,
+    network_latency: Cell<usize>,
+    // Per endpoint, in the order of `Endpoint::all`.
+    granted: Vec<Cell<usize>>,
+    waited: Vec<Cell<usize>>,
+    occupied: Vec<Cell<usize>>,
+}
+
+impl BusStats {
+    /// Create empty counts for a bus connecting main memory and the given
+    /// number of caches over the given number of `links`.
+    pub fn new(caches: usize, links: usize) -> BusStats {
+        let per_endpoint = || (0..caches + 1).map(|_| Cell::new(0)).collect();
+        BusStats {
+            counts: MessageKind::ALL.iter().map(|_| Cell::new(0)).collect(),
+            deliveries: Cell::new(0),
+            extra_deliveries: Cell::new(0),
+            unfiltered: Cell::new(0),
+            filter_back_invalidations: Cell::new(0),
+            links,
+            hops: Cell::new(0),
+            network_latency: Cell::new(0),
+            granted: per_endpoint(),
+            waited: per_endpoint(),
+            occupied: per_endpoint(),
+        }
+    }
+
+    /// Record that `msg` was sent over the bus.
+    pub fn record(&self, msg: &BusMessage) {
+        add(&self.counts[msg.kind() as usize], 1);
+    }
+
+    /// Record that a message was delivered to one of the bus' endpoints.
+    pub fn record_delivery(&self) {
+        add(&self.deliveries, 1);
+    }
+
+    /// Get the number of times a message has been delivered to an endpoint so
+    /// far. A message broadcast to every endpoint counts once for each.
+    pub fn deliveries(&self) -> usize {
+        self.deliveries.get()
+    }
+
+    /// Record that a message about a block was delivered to a cache that the
+    /// topology should have known does not hold it, due to imprecise
+    /// bookkeeping.
+    pub fn record_extra_delivery(&self) {
+        add(&self.extra_deliveries, 1);
+    }
+
+    /// Get the number of extra deliveries so far.
+    pub fn extra_deliveries(&self) -> usize {
+        self.extra_deliveries.get()
+    }
+
+    /// Record that a snooping bus without a snoop filter would have made
+    /// `deliveries` deliveries for a message the filter routed.
+    pub fn record_unfiltered(&self, deliveries: usize) {
+        add(&self.unfiltered, deliveries);
+    }
+
+    /// Get the number of deliveries a snooping bus without its snoop filter
+    /// would have made so far. Zero if it has none.
+    pub fn unfiltered(&self) -> usize {
+        self.unfiltered.get()
+    }
+
+    /// Record that a snoop filter made room for a block by back-invalidating
+    /// another from every cache holding it.
+    pub fn record_filter_back_invalidation(&self) {
+        add(&self.filter_back_invalidations, 1);
+    }
+
+    /// Get the number of back-invalidations a snoop filter has made so far.
+    pub fn filter_back_invalidations(&self) -> usize {
+        self.filter_back_invalidations.get()
+    }
+
+    /// Get the number of links the bus is made of. A shared bus is one link.
+    pub fn links(&self) -> usize {
+        self.links
+    }
+
+    /// Record that a message crossed `hops` links of a point-to-point network
+    /// to be delivered, arriving `latency` cycles after entering it.
+    pub fn record_traversal(&self, hops: usize, latency: Cycle) {
+        add(&self.hops, hops);
+        add(&self.network_latency, latency as usize);
+    }
+
+    /// Get the average number of links each delivery has crossed so far.
+    pub fn average_hops(&self) -> f64 {
+        self.hops.get() as f64 / self.deliveries().max(1) as f64
+    }
+
+    /// Get the average number of cycles each delivery has taken to cross the
+    /// network so far.
+    pub fn average_network_latency(&self) -> f64 {
+        self.network_latency.get() as f64 / self.deliveries().max(1) as f64
+    }
+
+    /// Get the number of messages of the given kind sent so far.
+    pub fn count(&self, kind: MessageKind) -> usize {
+        self.counts[kind as usize].get()
+    }
+
+    /// Get the total number of messages sent so far.
+    pub fn total(&self) -> usize {
+        MessageKind::ALL.iter().map(|&kind| self.count(kind)).sum()
+    }
+
+    /// Record that a message from `endpoint` won the bus after waiting for it
+    /// for `waited` cycles, and held it for `occupied` cycles.
+    pub fn record_grant(&self, endpoint: Endpoint, waited: Cycle, occupied: usize) {
+        let index = endpoint.index();
+        add(&self.granted[index], 1);
+        add(&self.waited[index], waited as usize);
+        add(&self.occupied[index], occupied);
+    }
+
+    /// Get the number of messages `endpoint` has sent over the bus so far.
+    pub fn granted(&self, endpoint: Endpoint) -> usize {
+        self.granted[endpoint.index()].get()
+    }
+
+    /// Get the average number of cycles `endpoint`'s messages have waited for
+    /// the bus so far, from being sent until winning it.
+    pub fn queuing_delay(&self, endpoint: Endpoint) -> f64 {
+        let granted = self.granted(endpoint);
+        if granted == 0 {
+            return 0.0;
+        }
+        self.waited[endpoint.index()].get() as f64 / granted as f64
+    }
+
+    /// Get the number of cycles `endpoint`'s messages have held the bus for
+    /// so far, summed over every link they held.
+    pub fn occupied(&self, endpoint: Endpoint) -> usize {
+        self.occupied[endpoint.index()].get()
+    }
+
+    /// Get the number of cycles every endpoint's messages have held the bus
+    /// for so far.
+    pub fn total_occupied(&self) -> usize {
+        self.occupied.iter().map(|occupied| occupied.get()).sum()
+    }
+
+    /// Reset every count to zero.
+    pub fn reset(&self) {
+        for count in self.counts.iter().chain(&self.granted).chain(&self.waited).chain(&self.occupied) {
+            count.set(0);
+        }
+        self.deliveries.set(0);
+        self.extra_deliveries.set(0);
+        self.unfiltered.set(0);
+        self.filter_back_invalidations.set(0);
+        self.hops.set(0);
+        self.network_latency.set(0);
     }
 }
 
@@ -89,31 +418,282 @@ pub enum ResponseSender {
     MainMemory,
 }
 
+/// An actor that messages on the bus can be delivered to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// Main memory.
+    MainMemory,
+    /// The memory cache with the given id.
+    Cache(memory_cache::MemoryCacheId),
+}
+
+impl Endpoint {
+    /// Every endpoint: main memory, followed by each of the given number of
+    /// memory caches.
+    pub fn all(caches: usize) -> Vec<Endpoint> {
+        let caches = (0..caches)
+            .map(|id| Endpoint::Cache(id as memory_cache::MemoryCacheId));
+        Some(Endpoint::MainMemory).into_iter().chain(caches).collect()
+    }
+
+    /// Get the endpoint's position in `Endpoint::all`.
+    pub fn index(self) -> usize {
+        match self {
+            Endpoint::MainMemory => 0,
+            Endpoint::Cache(id) => id as usize + 1,
+        }
+    }
+
+    /// Get the endpoint at the given position in `Endpoint::all`.
+    pub fn from_index(index: usize) -> Endpoint {
+        match index {
+            0 => Endpoint::MainMemory,
+            _ => Endpoint::Cache((index - 1) as memory_cache::MemoryCacheId),
+        }
+    }
+}
+
+/// The messages an actor has sent, waiting to be put on the bus.
+#[derive(Default)]
+pub struct Outbox {
+    messages: Vec<BusMessage>,
+}
+
+impl Outbox {
+    /// Send `msg` over the bus.
+    pub fn send(&mut self, msg: BusMessage) {
+        self.messages.push(msg);
+    }
+
+    /// Take every message sent since the last call, in the order they were
+    /// sent.
+    pub fn take(&mut self) -> Vec<BusMessage> {
+        mem::take(&mut self.messages)
+    }
+}
+
+/// Decides which endpoints each message sent over the bus is delivered to.
+pub trait Topology: 'static + Send {
+    /// The topology's name, for reporting.
+    fn name(&self) -> &'static str;
+
+    /// Route a message sent over the bus. Returns each message to deliver,
+    /// along with its destination. Any new messages the topology sends of its
+    /// own accord are recorded in `stats`.
+    fn route(&mut self, msg: BusMessage, stats: &BusStats) -> Vec<(Endpoint, BusMessage)>;
+}
+
+/// The topologies a machine's bus can be built with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopologyKind {
+    /// See `Broadcast`, with the given snoop filter, if any.
+    Bus(Option<SnoopFilterKind>),
+    /// See `directory::Directory`, storing sharers with the given encoding.
+    Directory(SharerEncoding),
+}
+
+impl TopologyKind {
+    /// Parse a topology from its name: `bus` optionally followed by a colon
+    /// and the name of its snoop filter, or `directory` optionally followed by
+    /// a colon and the name of its sharer encoding, which defaults to `full`.
+    pub fn parse(name: &str) -> Result<TopologyKind, String> {
+        let mut parts = name.splitn(2, ':');
+        match (&parts.next().unwrap().to_lowercase()[..], parts.next()) {
+            ("bus", filter) => Ok(TopologyKind::Bus(filter.map(SnoopFilterKind::parse).transpose()?)),
+            ("directory", encoding) => Ok(TopologyKind::Directory(SharerEncoding::parse(encoding.unwrap_or("full"))?)),
+            _ => Err(format!("Unknown topology: {}\nExpected one of: bus[:presence<n>|:inclusive<n>], directory[:full|:dir<i>b|:dir<i>nb|:coarse<k>]",
+                             name)),
+        }
+    }
+}
+
+/// A snooping bus, which broadcasts every message to every endpoint, unless
+/// its snoop filter knows better.
+pub struct Broadcast {
+    caches: usize,
+    filter: Option<SnoopFilter>,
+}
+
+impl Broadcast {
+    /// Create a snooping bus for the given system, with the given snoop
+    /// filter, if any.
+    pub fn new(system: &SystemConfig, filter: Option<SnoopFilterKind>) -> Broadcast {
+        Broadcast {
+            caches: system.number_of_caches,
+            filter: filter.map(|kind| SnoopFilter::new(kind, system)),
+        }
+    }
+
+    /// Get the bus' snoop filter, if it has one.
+    pub fn filter(&self) -> Option<&SnoopFilter> {
+        self.filter.as_ref()
+    }
+}
+
+impl Topology for Broadcast {
+    fn name(&self) -> &'static str {
+        "snooping bus"
+    }
+
+    fn route(&mut self, msg: BusMessage, stats: &BusStats) -> Vec<(Endpoint, BusMessage)> {
+        match self.filter {
+            Some(ref mut filter) => filter.route(msg, stats),
+            None => broadcast(self.caches, msg),
+        }
+    }
+}
+
+/// Deliver `msg` to main memory and each of the given number of caches.
+fn broadcast(caches: usize, msg: BusMessage) -> Vec<(Endpoint, BusMessage)> {
+    Endpoint::all(caches).into_iter().map(|endpoint| (endpoint, msg.clone())).collect()
+}
+
+/// A message waiting for the bus.
+struct Request {
+    msg: BusMessage,
+    // When it was sent, and its position among every message sent.
+    sent: Cycle,
+    arrival: u64,
+}
+
 /// The bus that connects the memory caches to main memory and each other.
-pub struct Bus {
-    incoming: mpsc::Receiver<BusMessage>,
-    outgoing: Vec<mpsc::Sender<BusMessage>>,
+pub struct Bus<T: Topology> {
+    arbitration: usize,
+    transfer: usize,
+    block_transfer: usize,
+    block_size: usize,
+    topology: T,
+    arbiter: Arbiter,
+    network: Network,
+    // Per endpoint, in the order of `Endpoint::all`: the messages waiting for
+    // the bus, whether one is being sent, and when the last one delivered to
+    // it arrived.
+    queues: Vec<VecDeque<Request>>,
+    sending: Vec<bool>,
+    delivered: Vec<Cycle>,
+    arrivals: u64,
+    stats: Rc<BusStats>,
 }
 
-impl Bus {
-    /// Create the bus, in its own thread.
-    pub fn spawn(incoming: mpsc::Receiver<BusMessage>, outgoing: Vec<mpsc::Sender<BusMessage>>)
-    {
-        let bus = Bus {
-            incoming: incoming,
-            outgoing: outgoing,
-        };
+impl<T: Topology> Bus<T> {
+    /// Create the bus for the given `system`, which routes messages as
+    /// `topology` decides, lets them go as `arbiter` decides, and carries
+    /// them over `network`. Every message forwarded is recorded in `stats`.
+    pub fn new(system: &SystemConfig,
+               topology: T,
+               arbiter: Arbiter,
+               network: Network,
+               stats: Rc<BusStats>)
+               -> Bus<T> {
+        let endpoints = system.number_of_caches + 1;
+        Bus {
+            arbitration: system.bus_arbitration,
+            transfer: system.bus_transfer,
+            block_transfer: system.bus_block_transfer,
+            block_size: system.block_size,
+            topology,
+            arbiter,
+            network,
+            queues: (0..endpoints).map(|_| VecDeque::new()).collect(),
+            sending: vec![false; endpoints],
+            delivered: vec![0; endpoints],
+            arrivals: 0,
+            stats,
+        }
+    }
 
-        thread::spawn(move || bus.run());
+    /// Get the bus' arbiter.
+    pub fn arbiter(&self) -> &Arbiter {
+        &self.arbiter
     }
 
-    /// Run the bus' main loop, which forwards messages to each memory cache and
-    /// main memory.
-    pub fn run(mut self) {
-        for msg in self.incoming {
-            for out in &mut self.outgoing {
-                ignore(out.send(msg.clone()));
+    /// Get the network the bus carries messages over.
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    /// Is the bus a single shared bus, rather than a point-to-point network?
+    fn shared(&self) -> bool {
+        self.network.kind() == NetworkKind::Bus
+    }
+
+    /// Ask for the bus to send `msg` from `from`, at cycle `now`. It waits
+    /// behind whatever `from` sent before it.
+    pub fn request(&mut self, from: Endpoint, msg: BusMessage, now: Cycle) {
+        self.queues[from.index()].push_back(Request {
+            msg,
+            sent: now,
+            arrival: self.arrivals,
+        });
+        self.arrivals += 1;
+    }
+
+    /// Let the message the arbiter chooses go at cycle `now`, if any may.
+    /// Returns it, along with its sender and the number of cycles until it is
+    /// ordered after every message sent before it, when it is forwarded.
+    ///
+    /// A shared bus lets one message go at a time, holding it for its
+    /// occupancy. A point-to-point network lets one go from each endpoint at
+    /// a time, and orders it right away.
+    pub fn grant(&mut self, now: Cycle) -> Option<(Endpoint, BusMessage, usize)> {
+        if self.shared() && self.sending.contains(&true) {
+            return None;
+        }
+
+        let waiting: Vec<_> = self.queues.iter().zip(&self.sending)
+            .map(|(queue, &sending)| if sending { None } else { queue.front().map(|request| request.arrival) })
+            .collect();
+        let index = self.arbiter.grant(&waiting)?;
+        let request = self.queues[index].pop_front().unwrap();
+        self.sending[index] = true;
+
+        let endpoint = Endpoint::from_index(index);
+        if !self.shared() {
+            return Some((endpoint, request.msg, 0));
+        }
+        let occupancy = self.occupancy(&request.msg);
+        self.stats.record_grant(endpoint, now - request.sent, occupancy);
+        Some((endpoint, request.msg, occupancy))
+    }
+
+    /// Get the number of cycles sending `msg` takes, from winning the bus
+    /// until it is delivered. Nothing else can be sent meanwhile.
+    pub fn occupancy(&self, msg: &BusMessage) -> usize {
+        self.arbitration + if msg.carries_block() { self.block_transfer } else { self.transfer }
+    }
+
+    /// Forward a message `from` sent over the bus, at cycle `now`. Returns
+    /// each message to deliver, along with its destination, as routed by the
+    /// topology, and the number of cycles until it arrives there. On a
+    /// point-to-point network, every message the topology routes on goes from
+    /// `from`, and each endpoint receives messages in the order they were
+    /// forwarded.
+    pub fn forward(&mut self, from: Endpoint, msg: BusMessage, now: Cycle) -> Vec<(Cycle, Endpoint, BusMessage)> {
+        self.sending[from.index()] = false;
+        self.stats.record(&msg);
+        let bytes = network::HEADER_SIZE + if msg.carries_block() { self.block_size } else { 0 };
+        let deliveries = self.topology.route(msg, &self.stats);
+
+        let mut waited = 0;
+        let mut occupied = 0;
+        let deliveries = deliveries.into_iter().map(|(to, msg)| {
+            self.stats.record_delivery();
+            if self.shared() {
+                return (0, to, msg);
             }
+
+            let traversal = self.network.send(from, to, bytes, now);
+            let arrival = traversal.arrival.max(self.delivered[to.index()]);
+            self.delivered[to.index()] = arrival;
+            waited = waited.max(traversal.stalled + arrival - traversal.arrival);
+            occupied += traversal.hops * self.network.serialization(bytes) as usize;
+            self.stats.record_traversal(traversal.hops, arrival - now);
+            (arrival - now, to, msg)
+        }).collect();
+
+        if !self.shared() {
+            self.stats.record_grant(from, waited, occupied);
         }
+        deliveries
     }
 }
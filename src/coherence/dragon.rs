@@ -0,0 +1,139 @@
+//! The Dragon cache coherence protocol.
+//!
+//! Unlike the invalidation-based protocols, Dragon never invalidates other
+//! caches' copies of a line. Instead, writes to a shared line are broadcast to
+//! the other sharers so they can update their copies in place. Once no other
+//! cache raises the bus' shared line for an update, the writer holds the only
+//! copy, and stops broadcasting its writes.
+
+use bus;
+use coherence::{CoherenceProtocol, LineState, Snoop, SnoopReaction, WriteAction};
+
+/// The current Dragon state of a cache line.
+///
+/// Dragon has no invalid state of its own; `Invalid` only stands in for lines
+/// that are not present in the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DragonState {
+    /// The cache line is present only in the current cache, and is clean.
+    Exclusive,
+
+    /// Shared clean: the cache line may be present in other caches, and this
+    /// cache is not responsible for writing it back to main memory.
+    SharedClean,
+
+    /// Shared modified: the cache line may be present in other caches, and is
+    /// dirty. This cache is its owner, responsible for supplying it to other
+    /// caches and writing it back to main memory.
+    SharedModified,
+
+    /// The cache line is present only in the current cache, and is dirty.
+    Modified,
+
+    /// The cache line is not present.
+    Invalid,
+}
+
+impl LineState for DragonState {
+    fn invalid() -> DragonState {
+        DragonState::Invalid
+    }
+
+    fn is_valid(&self) -> bool {
+        *self != DragonState::Invalid
+    }
+
+    fn is_writable(&self) -> bool {
+        *self == DragonState::Modified || *self == DragonState::SharedModified
+    }
+
+    fn is_dirty(&self) -> bool {
+        *self == DragonState::Modified || *self == DragonState::SharedModified
+    }
+}
+
+/// The Dragon protocol.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Dragon;
+
+impl CoherenceProtocol for Dragon {
+    type State = DragonState;
+
+    fn name(&self) -> &'static str {
+        "Dragon"
+    }
+
+    fn on_write(&self, state: DragonState) -> WriteAction<DragonState> {
+        match state {
+            DragonState::Exclusive | DragonState::Modified => WriteAction::Hit(DragonState::Modified),
+            // We do not know whether the other sharers still hold the line, so
+            // stay shared and become its owner until the update is ordered.
+            DragonState::SharedClean |
+            DragonState::SharedModified => WriteAction::Update(DragonState::SharedModified),
+            DragonState::Invalid => WriteAction::Fill,
+        }
+    }
+
+    fn on_read_fill(&self, from: bus::ResponseSender, shared: bool) -> DragonState {
+        match from {
+            bus::ResponseSender::MainMemory if !shared => DragonState::Exclusive,
+            _ => DragonState::SharedClean,
+        }
+    }
+
+    fn on_read_refill(&self, state: DragonState, _: bus::ResponseSender) -> DragonState {
+        match state {
+            DragonState::Exclusive => DragonState::SharedClean,
+            _ => state,
+        }
+    }
+
+    fn on_exclusive_fill(&self) -> DragonState {
+        DragonState::Modified
+    }
+
+    fn on_upgrade(&self) -> DragonState {
+        DragonState::Modified
+    }
+
+    // If no other cache raised the shared line for our update, we hold the
+    // only copy, and later writes need not be broadcast.
+    fn on_update(&self, state: DragonState, shared: bool) -> DragonState {
+        match state {
+            DragonState::SharedModified if !shared => DragonState::Modified,
+            _ => state,
+        }
+    }
+
+    fn on_snoop(&self, state: DragonState, snoop: Snoop) -> SnoopReaction<DragonState> {
+        match (snoop, state) {
+            (_, DragonState::Invalid) => SnoopReaction::silent(DragonState::Invalid),
+
+            // The owner supplies the data, since main memory's copy is stale.
+            (Snoop::Read, DragonState::Modified) |
+            (Snoop::Read, DragonState::SharedModified) => SnoopReaction {
+                next: DragonState::SharedModified,
+                supply: true,
+                write_back: false,
+            },
+            (Snoop::Read, DragonState::Exclusive) |
+            (Snoop::Read, DragonState::SharedClean) => SnoopReaction::silent(DragonState::SharedClean),
+
+            // Dragon never sends these itself, but invalidate and write back
+            // like any other protocol if it ever sees them.
+            (Snoop::ReadExclusive, _) |
+            (Snoop::Invalidate, _) => SnoopReaction {
+                next: DragonState::Invalid,
+                supply: false,
+                write_back: state.is_dirty(),
+            },
+
+            (Snoop::ReadResponse, DragonState::Exclusive) => SnoopReaction::silent(DragonState::SharedClean),
+            (Snoop::ReadResponse, _) => SnoopReaction::silent(state),
+
+            // The writer becomes the line's owner, and our copy is updated in
+            // place.
+            (Snoop::Update, _) => SnoopReaction::silent(DragonState::SharedClean),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! The MESI cache coherence protocol.
+
+use bus;
+use coherence::{CoherenceProtocol, LineState, Snoop, SnoopReaction, WriteAction};
+
+/// The current MESI state of a cache line.
+///
+/// Descriptions of individual states quoted [from
+/// Wikipedia](https://en.wikipedia.org/wiki/MESI_protocol).
+///
+/// <pre>
+///   M E S I
+/// M ✗ ✗ ✗ ✓
+/// E ✗ ✗ ✗ ✓
+/// S ✗ ✗ ✓ ✓
+/// I ✓ ✓ ✓ ✓
+/// </pre>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MesiState {
+    /// "The cache line is present only in the current cache, and is dirty; it
+    /// has been modified from the value in main memory. The cache is required
+    /// to write the data back to main memory at some time in the future, before
+    /// permitting any other read of the (no longer valid) main memory
+    /// state. The write-back changes the line to the Shared state."
+    Modified,
+
+    /// "The cache line is present only in the current cache, but is clean; it
+    /// matches main memory. It may be changed to the Shared state at any time,
+    /// in response to a read request. Alternatively, it may be changed to the
+    /// Modified state when writing to it."
+    Exclusive,
+
+    /// "Indicates that this cache line may be stored in other caches of the
+    /// machine and is clean; it matches the main memory. The line may be
+    /// discarded (changed to the Invalid state) at any time."
+    Shared,
+
+    /// "Indicates that this cache line is invalid (unused)."
+    Invalid,
+}
+
+impl LineState for MesiState {
+    fn invalid() -> MesiState {
+        MesiState::Invalid
+    }
+
+    fn is_valid(&self) -> bool {
+        *self != MesiState::Invalid
+    }
+
+    fn is_writable(&self) -> bool {
+        *self == MesiState::Modified
+    }
+
+    fn is_dirty(&self) -> bool {
+        *self == MesiState::Modified
+    }
+}
+
+/// The MESI protocol.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Mesi;
+
+impl CoherenceProtocol for Mesi {
+    type State = MesiState;
+
+    fn name(&self) -> &'static str {
+        "MESI"
+    }
+
+    fn on_write(&self, state: MesiState) -> WriteAction<MesiState> {
+        match state {
+            MesiState::Modified | MesiState::Exclusive => WriteAction::Hit(MesiState::Modified),
+            MesiState::Shared => WriteAction::Upgrade,
+            MesiState::Invalid => WriteAction::Miss,
+        }
+    }
+
+    fn on_read_fill(&self, from: bus::ResponseSender, _: bool) -> MesiState {
+        match from {
+            bus::ResponseSender::MainMemory => MesiState::Exclusive,
+            bus::ResponseSender::Cache => MesiState::Shared,
+        }
+    }
+
+    fn on_read_refill(&self, _: MesiState, _: bus::ResponseSender) -> MesiState {
+        MesiState::Shared
+    }
+
+    fn on_exclusive_fill(&self) -> MesiState {
+        MesiState::Modified
+    }
+
+    fn on_upgrade(&self) -> MesiState {
+        MesiState::Modified
+    }
+
+    fn on_snoop(&self, state: MesiState, snoop: Snoop) -> SnoopReaction<MesiState> {
+        match (snoop, state) {
+            (_, MesiState::Invalid) => SnoopReaction::silent(MesiState::Invalid),
+
+            (Snoop::Read, MesiState::Exclusive) |
+            (Snoop::Read, MesiState::Shared) => SnoopReaction {
+                next: MesiState::Shared,
+                supply: true,
+                write_back: false,
+            },
+            (Snoop::Read, MesiState::Modified) => SnoopReaction {
+                next: MesiState::Shared,
+                supply: true,
+                write_back: true,
+            },
+
+            (Snoop::ReadExclusive, _) |
+            (Snoop::Invalidate, _) => SnoopReaction {
+                next: MesiState::Invalid,
+                supply: false,
+                write_back: state == MesiState::Modified,
+            },
+
+            // Snoop when other caches start reading cache lines that we have
+            // marked exclusive and set our local copy's state to shared.
+            (Snoop::ReadResponse, MesiState::Exclusive) => SnoopReaction::silent(MesiState::Shared),
+            (Snoop::ReadResponse, _) => SnoopReaction::silent(state),
+
+            (Snoop::Update, _) => SnoopReaction::silent(state),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! The MESIF cache coherence protocol.
+
+use bus;
+use coherence::{CoherenceProtocol, LineState, Snoop, SnoopReaction, WriteAction};
+
+/// The current MESIF state of a cache line.
+///
+/// MESIF extends MESI with a Forward state. Of all the caches sharing a clean
+/// line, at most one holds it in the Forward state, and only that cache
+/// answers other caches' reads. The cache that most recently read the line
+/// becomes its forwarder.
+///
+/// <pre>
+///   M E S I F
+/// M ✗ ✗ ✗ ✓ ✗
+/// E ✗ ✗ ✗ ✓ ✗
+/// S ✗ ✗ ✓ ✓ ✓
+/// I ✓ ✓ ✓ ✓ ✓
+/// F ✗ ✗ ✓ ✓ ✗
+/// </pre>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MesifState {
+    /// The cache line is present only in the current cache, and is dirty.
+    Modified,
+
+    /// The cache line is present only in the current cache, and is clean.
+    Exclusive,
+
+    /// The cache line may be present in other caches, and is clean. This
+    /// cache does not answer other caches' reads of the line.
+    Shared,
+
+    /// The cache line is invalid (unused).
+    Invalid,
+
+    /// The cache line may be present in other caches, and is clean. This
+    /// cache is the one designated to answer other caches' reads of the line.
+    Forward,
+}
+
+impl LineState for MesifState {
+    fn invalid() -> MesifState {
+        MesifState::Invalid
+    }
+
+    fn is_valid(&self) -> bool {
+        *self != MesifState::Invalid
+    }
+
+    fn is_writable(&self) -> bool {
+        *self == MesifState::Modified
+    }
+
+    fn is_dirty(&self) -> bool {
+        *self == MesifState::Modified
+    }
+}
+
+/// The MESIF protocol.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Mesif;
+
+impl CoherenceProtocol for Mesif {
+    type State = MesifState;
+
+    fn name(&self) -> &'static str {
+        "MESIF"
+    }
+
+    fn on_write(&self, state: MesifState) -> WriteAction<MesifState> {
+        match state {
+            MesifState::Modified | MesifState::Exclusive => WriteAction::Hit(MesifState::Modified),
+            MesifState::Shared | MesifState::Forward => WriteAction::Upgrade,
+            MesifState::Invalid => WriteAction::Miss,
+        }
+    }
+
+    fn on_read_fill(&self, from: bus::ResponseSender, shared: bool) -> MesifState {
+        // Since shared caches stay quiet, main memory's hint is all that tells
+        // us whether the line is really exclusive when no forwarder answered.
+        match from {
+            bus::ResponseSender::MainMemory if !shared => MesifState::Exclusive,
+            _ => MesifState::Forward,
+        }
+    }
+
+    fn on_read_refill(&self, state: MesifState, _: bus::ResponseSender) -> MesifState {
+        match state {
+            MesifState::Exclusive => MesifState::Forward,
+            _ => state,
+        }
+    }
+
+    fn on_exclusive_fill(&self) -> MesifState {
+        MesifState::Modified
+    }
+
+    fn on_upgrade(&self) -> MesifState {
+        MesifState::Modified
+    }
+
+    fn on_snoop(&self, state: MesifState, snoop: Snoop) -> SnoopReaction<MesifState> {
+        match (snoop, state) {
+            (_, MesifState::Invalid) => SnoopReaction::silent(MesifState::Invalid),
+
+            // Hand the Forward state on to the requester, along with the data.
+            (Snoop::Read, MesifState::Exclusive) |
+            (Snoop::Read, MesifState::Forward) => SnoopReaction {
+                next: MesifState::Shared,
+                supply: true,
+                write_back: false,
+            },
+            (Snoop::Read, MesifState::Modified) => SnoopReaction {
+                next: MesifState::Shared,
+                supply: true,
+                write_back: true,
+            },
+            (Snoop::Read, MesifState::Shared) => SnoopReaction::silent(MesifState::Shared),
+
+            (Snoop::ReadExclusive, _) |
+            (Snoop::Invalidate, _) => SnoopReaction {
+                next: MesifState::Invalid,
+                supply: false,
+                write_back: state == MesifState::Modified,
+            },
+
+            (Snoop::ReadResponse, MesifState::Exclusive) => SnoopReaction::silent(MesifState::Shared),
+            (Snoop::ReadResponse, _) => SnoopReaction::silent(state),
+
+            (Snoop::Update, _) => SnoopReaction::silent(state),
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Cache coherence protocols.
+//!
+//! A `MemoryCache` is generic over a `CoherenceProtocol`, which decides how
+//! the state of each cache line changes in reaction to the processor's reads
+//! and writes, and to the other caches' messages snooped from the bus. The
+//! cache itself is only responsible for sending and receiving the resulting
+//! bus messages.
+
+use std::fmt;
+
+use bus;
+
+pub mod dragon;
+pub mod mesi;
+pub mod mesif;
+pub mod moesi;
+pub mod msi;
+
+/// The state of a single cache line under some coherence protocol.
+pub trait LineState: 'static + Copy + Eq + fmt::Debug + Send {
+    /// The state of a line that holds no valid data.
+    fn invalid() -> Self;
+
+    /// Can the line's data be read?
+    fn is_valid(&self) -> bool;
+
+    /// Can the line's data be written by the processor while in this state?
+    fn is_writable(&self) -> bool;
+
+    /// Must the line's data be written back to main memory before it is
+    /// dropped from the cache?
+    fn is_dirty(&self) -> bool;
+}
+
+/// What the processor must do in order to write to a line that is in some
+/// given state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteAction<S> {
+    /// The write hits, and the line moves to the given state.
+    Hit(S),
+
+    /// The write hits, the line moves to the given state, and the written
+    /// value is broadcast to the other caches so they can update their copies.
+    Update(S),
+
+    /// The line holds valid data, but the other caches' copies must be
+    /// invalidated before writing to it.
+    Upgrade,
+
+    /// The write misses, and the line must be read exclusively before writing
+    /// to it.
+    Miss,
+
+    /// The write misses, and the line must be read with an ordinary read
+    /// before deciding how to write to it.
+    Fill,
+}
+
+/// A bus message snooped from another cache that concerns a line this cache
+/// holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Snoop {
+    /// Another cache wants to read the line.
+    Read,
+
+    /// Another cache wants to read the line with intent to modify it.
+    ReadExclusive,
+
+    /// Another cache is upgrading its copy of the line to write it, and wants
+    /// every other copy invalidated.
+    Invalidate,
+
+    /// Another cache has been sent the line's data.
+    ReadResponse,
+
+    /// Another cache wrote to the line, and broadcast the written value.
+    Update,
+}
+
+/// How a cache line reacts to a snooped bus message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnoopReaction<S> {
+    /// The line's next state.
+    pub next: S,
+
+    /// Whether to send the line's data to the requesting cache.
+    pub supply: bool,
+
+    /// Whether to write the line's data back to main memory.
+    pub write_back: bool,
+}
+
+impl<S> SnoopReaction<S> {
+    /// Move to the `next` state without putting anything on the bus.
+    pub fn silent(next: S) -> SnoopReaction<S> {
+        SnoopReaction {
+            next,
+            supply: false,
+            write_back: false,
+        }
+    }
+}
+
+/// A cache coherence protocol: the states a cache line may be in, and the
+/// transitions between them.
+pub trait CoherenceProtocol: 'static + Send {
+    /// The state of a cache line under this protocol.
+    type State: LineState;
+
+    /// The protocol's name, for reporting.
+    fn name(&self) -> &'static str;
+
+    /// The state a line moves to, or the bus transaction required, when the
+    /// processor writes to a line in the given `state`.
+    fn on_write(&self, state: Self::State) -> WriteAction<Self::State>;
+
+    /// The state of a newly filled line after a read miss, given who supplied
+    /// the data and whether other caches may also hold it.
+    fn on_read_fill(&self, from: bus::ResponseSender, shared: bool) -> Self::State;
+
+    /// The state of a line that is already valid when another response to our
+    /// read request arrives.
+    fn on_read_refill(&self, state: Self::State, from: bus::ResponseSender) -> Self::State;
+
+    /// The state of a newly filled line after a read exclusive miss.
+    fn on_exclusive_fill(&self) -> Self::State;
+
+    /// The state of a line after the other caches' copies have been
+    /// successfully invalidated.
+    fn on_upgrade(&self) -> Self::State;
+
+    /// The state of a line in the given `state` once our update of it has been
+    /// ordered on the bus, given whether any other cache still held the block
+    /// then.
+    fn on_update(&self, state: Self::State, _shared: bool) -> Self::State {
+        // Invalidation-based protocols never broadcast updates.
+        state
+    }
+
+    /// How a line in the given `state` reacts to a snooped message from
+    /// another cache.
+    fn on_snoop(&self, state: Self::State, snoop: Snoop) -> SnoopReaction<Self::State>;
+}
+
+/// The coherence protocols a machine can be built with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolKind {
+    /// See `dragon::Dragon`.
+    Dragon,
+    /// See `mesi::Mesi`.
+    Mesi,
+    /// See `mesif::Mesif`.
+    Mesif,
+    /// See `moesi::Moesi`.
+    Moesi,
+    /// See `msi::Msi`.
+    Msi,
+}
+
+impl ProtocolKind {
+    /// Every protocol, in the order they are listed to the user.
+    pub const ALL: [ProtocolKind; 5] = [
+        ProtocolKind::Dragon,
+        ProtocolKind::Mesi,
+        ProtocolKind::Mesif,
+        ProtocolKind::Moesi,
+        ProtocolKind::Msi,
+    ];
+
+    /// Parse a protocol from its name.
+    pub fn parse(name: &str) -> Result<ProtocolKind, String> {
+        let lowercase = name.to_lowercase();
+        ProtocolKind::ALL.iter()
+            .find(|kind| kind.name().to_lowercase() == lowercase)
+            .cloned()
+            .ok_or_else(|| {
+                let names: Vec<_> = ProtocolKind::ALL.iter().map(|kind| kind.name().to_lowercase()).collect();
+                format!("Unknown coherence protocol: {}\nExpected one of: {}", name, names.join(", "))
+            })
+    }
+
+    /// Get this protocol's name.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ProtocolKind::Dragon => dragon::Dragon.name(),
+            ProtocolKind::Mesi => mesi::Mesi.name(),
+            ProtocolKind::Mesif => mesif::Mesif.name(),
+            ProtocolKind::Moesi => moesi::Moesi.name(),
+            ProtocolKind::Msi => msi::Msi.name(),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! The MOESI cache coherence protocol.
+
+use bus;
+use coherence::{CoherenceProtocol, LineState, Snoop, SnoopReaction, WriteAction};
+
+/// The current MOESI state of a cache line.
+///
+/// MOESI extends MESI with an Owned state, which lets a dirty line be shared
+/// without first writing it back to main memory.
+///
+/// <pre>
+///   M O E S I
+/// M ✗ ✗ ✗ ✗ ✓
+/// O ✗ ✗ ✗ ✓ ✓
+/// E ✗ ✗ ✗ ✗ ✓
+/// S ✗ ✓ ✗ ✓ ✓
+/// I ✓ ✓ ✓ ✓ ✓
+/// </pre>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MoesiState {
+    /// The cache line is present only in the current cache, and is dirty.
+    Modified,
+
+    /// The cache line may be present in other caches in the Shared state, and
+    /// is dirty. This cache is responsible for supplying the line's data to
+    /// other caches that read it, and for eventually writing it back to main
+    /// memory.
+    Owned,
+
+    /// The cache line is present only in the current cache, and is clean.
+    Exclusive,
+
+    /// The cache line may be present in other caches. It is clean unless
+    /// another cache holds it in the Owned state.
+    Shared,
+
+    /// The cache line is invalid (unused).
+    Invalid,
+}
+
+impl LineState for MoesiState {
+    fn invalid() -> MoesiState {
+        MoesiState::Invalid
+    }
+
+    fn is_valid(&self) -> bool {
+        *self != MoesiState::Invalid
+    }
+
+    fn is_writable(&self) -> bool {
+        *self == MoesiState::Modified
+    }
+
+    fn is_dirty(&self) -> bool {
+        *self == MoesiState::Modified || *self == MoesiState::Owned
+    }
+}
+
+/// The MOESI protocol.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Moesi;
+
+impl CoherenceProtocol for Moesi {
+    type State = MoesiState;
+
+    fn name(&self) -> &'static str {
+        "MOESI"
+    }
+
+    fn on_write(&self, state: MoesiState) -> WriteAction<MoesiState> {
+        match state {
+            MoesiState::Modified | MoesiState::Exclusive => WriteAction::Hit(MoesiState::Modified),
+            MoesiState::Owned | MoesiState::Shared => WriteAction::Upgrade,
+            MoesiState::Invalid => WriteAction::Miss,
+        }
+    }
+
+    fn on_read_fill(&self, from: bus::ResponseSender, _: bool) -> MoesiState {
+        match from {
+            bus::ResponseSender::MainMemory => MoesiState::Exclusive,
+            bus::ResponseSender::Cache => MoesiState::Shared,
+        }
+    }
+
+    fn on_read_refill(&self, state: MoesiState, _: bus::ResponseSender) -> MoesiState {
+        match state {
+            MoesiState::Owned => MoesiState::Owned,
+            _ => MoesiState::Shared,
+        }
+    }
+
+    fn on_exclusive_fill(&self) -> MoesiState {
+        MoesiState::Modified
+    }
+
+    fn on_upgrade(&self) -> MoesiState {
+        MoesiState::Modified
+    }
+
+    fn on_snoop(&self, state: MoesiState, snoop: Snoop) -> SnoopReaction<MoesiState> {
+        match (snoop, state) {
+            (_, MoesiState::Invalid) => SnoopReaction::silent(MoesiState::Invalid),
+
+            (Snoop::Read, MoesiState::Exclusive) |
+            (Snoop::Read, MoesiState::Shared) => SnoopReaction {
+                next: MoesiState::Shared,
+                supply: true,
+                write_back: false,
+            },
+
+            // Supply the dirty data cache-to-cache, and keep the
+            // responsibility for writing it back.
+            (Snoop::Read, MoesiState::Modified) |
+            (Snoop::Read, MoesiState::Owned) => SnoopReaction {
+                next: MoesiState::Owned,
+                supply: true,
+                write_back: false,
+            },
+
+            (Snoop::ReadExclusive, _) |
+            (Snoop::Invalidate, _) => SnoopReaction {
+                next: MoesiState::Invalid,
+                supply: false,
+                write_back: state.is_dirty(),
+            },
+
+            (Snoop::ReadResponse, MoesiState::Exclusive) => SnoopReaction::silent(MoesiState::Shared),
+            (Snoop::ReadResponse, _) => SnoopReaction::silent(state),
+
+            (Snoop::Update, _) => SnoopReaction::silent(state),
+        }
+    }
+}
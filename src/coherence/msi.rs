@@ -0,0 +1,107 @@
+//! The MSI cache coherence protocol.
+//!
+//! MSI is MESI without the Exclusive state. Every read miss fills the line in
+//! the Shared state, so the first write to a line always has to invalidate the
+//! other caches' copies over the bus, even when there are none.
+
+use bus;
+use coherence::{CoherenceProtocol, LineState, Snoop, SnoopReaction, WriteAction};
+
+/// The current MSI state of a cache line.
+///
+/// <pre>
+///   M S I
+/// M ✗ ✗ ✓
+/// S ✗ ✓ ✓
+/// I ✓ ✓ ✓
+/// </pre>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsiState {
+    /// The cache line is present only in the current cache, and is dirty.
+    Modified,
+
+    /// The cache line may be present in other caches, and is clean.
+    Shared,
+
+    /// The cache line is invalid (unused).
+    Invalid,
+}
+
+impl LineState for MsiState {
+    fn invalid() -> MsiState {
+        MsiState::Invalid
+    }
+
+    fn is_valid(&self) -> bool {
+        *self != MsiState::Invalid
+    }
+
+    fn is_writable(&self) -> bool {
+        *self == MsiState::Modified
+    }
+
+    fn is_dirty(&self) -> bool {
+        *self == MsiState::Modified
+    }
+}
+
+/// The MSI protocol.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Msi;
+
+impl CoherenceProtocol for Msi {
+    type State = MsiState;
+
+    fn name(&self) -> &'static str {
+        "MSI"
+    }
+
+    fn on_write(&self, state: MsiState) -> WriteAction<MsiState> {
+        match state {
+            MsiState::Modified => WriteAction::Hit(MsiState::Modified),
+            MsiState::Shared => WriteAction::Upgrade,
+            MsiState::Invalid => WriteAction::Miss,
+        }
+    }
+
+    fn on_read_fill(&self, _: bus::ResponseSender, _: bool) -> MsiState {
+        MsiState::Shared
+    }
+
+    fn on_read_refill(&self, state: MsiState, _: bus::ResponseSender) -> MsiState {
+        state
+    }
+
+    fn on_exclusive_fill(&self) -> MsiState {
+        MsiState::Modified
+    }
+
+    fn on_upgrade(&self) -> MsiState {
+        MsiState::Modified
+    }
+
+    fn on_snoop(&self, state: MsiState, snoop: Snoop) -> SnoopReaction<MsiState> {
+        match (snoop, state) {
+            (_, MsiState::Invalid) => SnoopReaction::silent(MsiState::Invalid),
+
+            // Main memory's copy is up to date, so let it answer.
+            (Snoop::Read, MsiState::Shared) => SnoopReaction::silent(MsiState::Shared),
+            (Snoop::Read, MsiState::Modified) => SnoopReaction {
+                next: MsiState::Shared,
+                supply: true,
+                write_back: true,
+            },
+
+            (Snoop::ReadExclusive, _) |
+            (Snoop::Invalidate, _) => SnoopReaction {
+                next: MsiState::Invalid,
+                supply: false,
+                write_back: state == MsiState::Modified,
+            },
+
+            (Snoop::ReadResponse, _) => SnoopReaction::silent(state),
+
+            (Snoop::Update, _) => SnoopReaction::silent(state),
+        }
+    }
+}
@@ -0,0 +1,592 @@
+//! Descriptions of a whole simulated machine, read from a TOML or JSON file,
+//! so that each experiment is a file rather than an edit to `main`.
+//!
+//! Every section, and every key within one, may be left out to keep its
+//! default. In TOML, a machine file looks like this:
+//!
+//! ```toml
+//! [system]
+//! block_size = 32             # bytes, a power of two
+//! main_memory_size = 8192     # bytes, a multiple of the block size
+//! caches = 4
+//!
+//! [caches]
+//! protocol = "moesi"
+//! inclusion = "inclusive"     # inclusive, exclusive or nine
+//! clean_evictions = "announce"  # announce or silent
+//! victim_entries = 2
+//! prefetcher = "stride:2"
+//! outstanding = 4             # misses each cache may wait on at once
+//! snoop_latency = 2           # cycles to respond to a snooped message
+//!
+//! # The levels of each cache's private hierarchy, L1 first. The largest
+//! # number of blocks in any cache's last level is the system's cache size.
+//! [[caches.levels]]
+//! blocks = 8                  # a power of two
+//! ways = 2                    # or "full", the default
+//! replacement = "lru"
+//! latency = 4                 # cycles
+//!
+//! [[caches.levels]]
+//! blocks = 32
+//! latency = 12
+//!
+//! # Classes of caches built differently, all coherent on the same bus. Each
+//! # class may give its own clean evictions, victim entries, prefetcher,
+//! # outstanding misses and levels, and takes the rest from `[caches]`. Their
+//! # counts add up to the system's number of caches. Left out for every cache
+//! # to be built the same way.
+//! [[caches.classes]]
+//! name = "big"
+//! count = 1
+//! prefetcher = "stride:4"
+//!
+//! [[caches.classes.levels]]
+//! blocks = 64
+//! ways = 8
+//!
+//! [[caches.classes]]
+//! name = "little"
+//! count = 3
+//!
+//! [interconnect]
+//! topology = "directory:dir1b"  # or "bus", optionally snoop filtered: "bus:inclusive1024"
+//! arbiter = "round-robin"     # fifo, round-robin, fixed-priority or lottery
+//! network = "mesh"            # bus, ring, mesh or crossbar
+//! arbitration = 1             # cycles to win the bus
+//! transfer = 1                # cycles to carry a message without a block
+//! block_transfer = 4          # cycles to carry a message with a block
+//! hop_latency = 1             # cycles to cross a link of a ring, mesh or crossbar
+//! link_bandwidth = 8          # bytes each of its links carries per cycle
+//!
+//! [memory]
+//! dram_latency = 100          # cycles in the controller, on top of the bank's
+//! page_policy = "open"        # open or closed
+//! address_mapping = "row-interleaved"  # or block-interleaved, permutation
+//! channels = 2                # each a power of two
+//! ranks = 1
+//! banks = 8                   # per rank
+//! row_size = 1024             # bytes, a power of two
+//! t_cas = 40                  # cycles to read an open row
+//! t_rcd = 40                  # cycles to activate a row
+//! t_rp = 40                   # cycles to precharge a row
+//! t_burst = 8                 # cycles to carry a block over the channel
+//!
+//! # A last-level cache shared by every cache, in front of DRAM. Left out
+//! # entirely for none.
+//! [memory.llc]
+//! blocks = 128
+//! ways = 16
+//! latency = 40
+//!
+//! [workload]
+//! phases = ["sequential-read", "shared-chunk-write"]
+//!
+//! # The same seed always gives the same run, cycle for cycle.
+//! [simulation]
+//! seed = 7
+//! trace = "trace.txt"         # every bus message and the cycle it was sent
+//! ```
+//!
+//! In JSON, it is an object with the same keys, and `caches.levels` is an
+//! array of objects. Files ending in `.json` are read as JSON, and any others
+//! as TOML.
+
+extern crate serde_json;
+extern crate toml;
+
+use std::fs;
+use std::path::Path;
+
+use arbiter::ArbiterKind;
+use benchmark::Phase;
+use bus::TopologyKind;
+use coherence::ProtocolKind;
+use dram::{AddressMapping, PagePolicy};
+use hierarchy::{Inclusion, LevelConfig};
+use memory_cache::{self, CacheConfig};
+use network::NetworkKind;
+use prefetch::PrefetcherKind;
+use replacement::ReplacementKind;
+use set_associative::CacheGeometry;
+use system::SystemConfig;
+
+/// The names of the settings that may differ from one class of caches to
+/// another. The protocol and inclusion policy may not, since every cache must
+/// agree with the others and the last-level cache on them, and neither may the
+/// snoop latency, which is the system's.
+const CACHE_KEYS: [&str; 5] = ["clean_evictions", "victim_entries", "prefetcher", "outstanding", "levels"];
+
+/// A group of caches that are all built the same way.
+#[derive(Clone, Debug)]
+pub struct CacheClass {
+    /// The class's name, for reporting.
+    pub name: String,
+    /// The number of caches in the class.
+    pub count: usize,
+    /// How each cache in the class is built.
+    pub config: CacheConfig,
+}
+
+/// Everything needed to build and run a simulated machine.
+#[derive(Clone, Debug)]
+pub struct MachineConfig {
+    /// The size and number of its blocks, main memory and caches.
+    pub system: SystemConfig,
+    /// The coherence protocol every cache follows.
+    pub protocol: ProtocolKind,
+    /// The topology of the bus connecting the caches and main memory.
+    pub topology: TopologyKind,
+    /// How the bus chooses whose message to carry next.
+    pub arbiter: ArbiterKind,
+    /// The interconnect that physically carries the bus' messages.
+    pub network: NetworkKind,
+    /// How the caches are built, class by class. Caches are numbered in the
+    /// order of their classes, so the first class holds cache 0. The counts
+    /// add up to the system's number of caches.
+    pub classes: Vec<CacheClass>,
+    /// The last-level cache shared by every cache, if any.
+    pub llc: Option<LevelConfig>,
+    /// What DRAM's banks do with a row once an access to it is done.
+    pub page_policy: PagePolicy,
+    /// How main memory's blocks are spread over DRAM's banks and rows.
+    pub address_mapping: AddressMapping,
+    /// The benchmark phases to run, in order.
+    pub phases: Vec<Phase>,
+    /// The seed of the processors' random accesses.
+    pub seed: usize,
+    /// The file to write a trace of every bus message to, if any.
+    pub trace: Option<String>,
+}
+
+impl MachineConfig {
+    /// The inclusion policy every class of caches, and the last-level cache,
+    /// follows.
+    pub fn inclusion(&self) -> Inclusion {
+        self.classes[0].config.inclusion
+    }
+
+    /// Read a machine from the file at `path`, as JSON if its extension is
+    /// `.json`, and as TOML otherwise.
+    pub fn load(path: &str) -> Result<MachineConfig, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read machine file {}: {}", path, e))?;
+
+        let is_json = Path::new(path).extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+        let result = if is_json {
+            MachineConfig::from_json(&text)
+        } else {
+            MachineConfig::from_toml(&text)
+        };
+        result.map_err(|e| format!("In machine file {}: {}", path, e))
+    }
+
+    /// Parse a machine from a TOML document.
+    pub fn from_toml(text: &str) -> Result<MachineConfig, String> {
+        let value = text.parse::<toml::Value>().map_err(|e| format!("Invalid TOML: {}", e))?;
+        MachineConfig::from_value(&value)
+    }
+
+    /// Parse a machine from a JSON document.
+    pub fn from_json(text: &str) -> Result<MachineConfig, String> {
+        let value = serde_json::from_str::<toml::Value>(text).map_err(|e| format!("Invalid JSON: {}", e))?;
+        MachineConfig::from_value(&value)
+    }
+
+    /// Build a machine from a parsed document, checking that it describes a
+    /// machine that can be simulated.
+    fn from_value(value: &toml::Value) -> Result<MachineConfig, String> {
+        let root = Table::new(String::new(), value)?;
+        root.check_keys(&["system", "caches", "interconnect", "memory", "workload", "simulation"])?;
+
+        let defaults = SystemConfig::default();
+        let mut system = defaults;
+        let mut caches_given = false;
+        if let Some(table) = root.table("system")? {
+            table.check_keys(&["block_size", "main_memory_size", "caches"])?;
+            system.block_size = table.usize("block_size", defaults.block_size)?;
+            system.main_memory_size = table.usize("main_memory_size", defaults.main_memory_size)?;
+            system.number_of_caches = table.usize("caches", defaults.number_of_caches)?;
+            caches_given = table.get("caches").is_some();
+        }
+
+        let mut protocol = ProtocolKind::Mesi;
+        let mut classes = vec![];
+        let mut cache = CacheConfig::default();
+        if let Some(table) = root.table("caches")? {
+            table.check_keys(&[&["protocol", "inclusion", "snoop_latency", "classes"][..], &CACHE_KEYS[..]].concat())?;
+            protocol = table.parse("protocol", protocol, ProtocolKind::parse)?;
+            system.snoop_latency = table.usize("snoop_latency", defaults.snoop_latency)?;
+            cache.inclusion = table.parse("inclusion", cache.inclusion, Inclusion::parse)?;
+            cache = cache_config(&table, &cache)?;
+
+            for class in table.tables("classes")?.unwrap_or_default() {
+                class.check_keys(&[&["name", "count"][..], &CACHE_KEYS[..]].concat())?;
+                let name = match class.get("name") {
+                    None => return Err(format!("Missing key: {}", class.key_path("name"))),
+                    Some(toml::Value::String(name)) if !name.is_empty() => name.clone(),
+                    Some(other) => return Err(class.type_error("name", "a non-empty string", other)),
+                };
+                if classes.iter().any(|other: &CacheClass| other.name == name) {
+                    return Err(format!("{}: Duplicate class name: {}", class.key_path("name"), name));
+                }
+                let count = class.usize("count", 0)?;
+                if count == 0 {
+                    return Err(format!("{}: Expected a class of at least one cache", class.key_path("count")));
+                }
+                classes.push(CacheClass {
+                    name,
+                    count,
+                    config: cache_config(&class, &cache)?,
+                });
+            }
+
+            if !classes.is_empty() {
+                let count = classes.iter().map(|class| class.count).sum();
+                if caches_given && count != system.number_of_caches {
+                    return Err(format!("system.caches: {} caches, but the classes in caches.classes have {}",
+                                       system.number_of_caches, count));
+                }
+                system.number_of_caches = count;
+            }
+        }
+        if classes.is_empty() {
+            classes.push(CacheClass {
+                name: "default".to_string(),
+                count: system.number_of_caches,
+                config: cache,
+            });
+        }
+        system.cache_size = classes.iter()
+            .map(|class| class.config.levels.last().unwrap().geometry.capacity())
+            .max()
+            .unwrap();
+
+        let mut topology = TopologyKind::Bus(None);
+        let mut arbiter = ArbiterKind::Fifo;
+        let mut network = NetworkKind::Bus;
+        if let Some(table) = root.table("interconnect")? {
+            table.check_keys(&["topology", "arbiter", "network", "arbitration", "transfer", "block_transfer",
+                               "hop_latency", "link_bandwidth"])?;
+            topology = table.parse("topology", topology, TopologyKind::parse)?;
+            arbiter = table.parse("arbiter", arbiter, ArbiterKind::parse)?;
+            network = table.parse("network", network, NetworkKind::parse)?;
+            system.bus_arbitration = table.usize("arbitration", defaults.bus_arbitration)?;
+            system.bus_transfer = table.usize("transfer", defaults.bus_transfer)?;
+            system.bus_block_transfer = table.usize("block_transfer", defaults.bus_block_transfer)?;
+            system.hop_latency = table.usize("hop_latency", defaults.hop_latency)?;
+            system.link_bandwidth = table.usize("link_bandwidth", defaults.link_bandwidth)?;
+        }
+
+        let mut llc = None;
+        let mut page_policy = PagePolicy::Open;
+        let mut address_mapping = AddressMapping::RowInterleaved;
+        if let Some(table) = root.table("memory")? {
+            table.check_keys(&["dram_latency", "page_policy", "address_mapping", "channels", "ranks", "banks",
+                               "row_size", "t_cas", "t_rcd", "t_rp", "t_burst", "llc"])?;
+            system.dram_latency = table.usize("dram_latency", defaults.dram_latency)?;
+            page_policy = table.parse("page_policy", page_policy, PagePolicy::parse)?;
+            address_mapping = table.parse("address_mapping", address_mapping, AddressMapping::parse)?;
+            system.dram_channels = table.usize("channels", defaults.dram_channels)?;
+            system.dram_ranks = table.usize("ranks", defaults.dram_ranks)?;
+            system.dram_banks = table.usize("banks", defaults.dram_banks)?;
+            system.dram_row_size = table.usize("row_size", defaults.dram_row_size)?;
+            system.t_cas = table.usize("t_cas", defaults.t_cas)?;
+            system.t_rcd = table.usize("t_rcd", defaults.t_rcd)?;
+            system.t_rp = table.usize("t_rp", defaults.t_rp)?;
+            system.t_burst = table.usize("t_burst", defaults.t_burst)?;
+            llc = table.table("llc")?.map(|table| level(&table)).transpose()?;
+        }
+
+        let mut phases = Phase::ALL.to_vec();
+        if let Some(table) = root.table("workload")? {
+            table.check_keys(&["phases"])?;
+            if let Some(names) = table.strings("phases")? {
+                if names.is_empty() {
+                    return Err(format!("{}: Expected at least one phase", table.key_path("phases")));
+                }
+                phases = names.iter()
+                    .map(|name| Phase::parse(name).map_err(|e| format!("{}: {}", table.key_path("phases"), e)))
+                    .collect::<Result<_, _>>()?;
+            }
+        }
+
+        let mut seed = 0;
+        let mut trace = None;
+        if let Some(table) = root.table("simulation")? {
+            table.check_keys(&["seed", "trace"])?;
+            seed = table.usize("seed", seed)?;
+            trace = table.parse("trace", trace, |path| Ok(Some(path.to_string())))?;
+        }
+
+        system.validate().map_err(|e| format!("system: {}", e))?;
+
+        Ok(MachineConfig {
+            system,
+            protocol,
+            topology,
+            arbiter,
+            network,
+            classes,
+            llc,
+            page_policy,
+            address_mapping,
+            phases,
+            seed,
+            trace,
+        })
+    }
+}
+
+/// Apply the cache settings in `table` on top of `base`.
+fn cache_config(table: &Table, base: &CacheConfig) -> Result<CacheConfig, String> {
+    let mut cache = base.clone();
+    cache.announce_clean_evictions = table.parse("clean_evictions", base.announce_clean_evictions, |mode| {
+        match &mode.to_lowercase()[..] {
+            "announce" => Ok(true),
+            "silent" => Ok(false),
+            _ => Err(format!("Unknown clean eviction mode: {}\nExpected one of: announce, silent", mode)),
+        }
+    })?;
+    cache.victim_entries = table.usize("victim_entries", base.victim_entries)?;
+    cache.prefetcher = table.parse("prefetcher", base.prefetcher, PrefetcherKind::parse)?;
+    cache.outstanding = table.usize("outstanding", base.outstanding)?;
+    if cache.outstanding == 0 {
+        return Err(format!("{}: Expected at least one outstanding miss", table.key_path("outstanding")));
+    }
+
+    if let Some(levels) = table.tables("levels")? {
+        if levels.is_empty() {
+            return Err(format!("{}: Expected at least one level", table.key_path("levels")));
+        }
+        cache.levels = levels.iter().map(level).collect::<Result<_, _>>()?;
+    }
+    Ok(cache)
+}
+
+/// Build one cache level from its table.
+fn level(table: &Table) -> Result<LevelConfig, String> {
+    table.check_keys(&["blocks", "ways", "replacement", "latency"])?;
+
+    if table.get("blocks").is_none() {
+        return Err(format!("Missing key: {}", table.key_path("blocks")));
+    }
+    let blocks = table.usize("blocks", 0)?;
+    if !blocks.is_power_of_two() {
+        return Err(format!("{}: Invalid number of blocks: {}\nExpected a power of two",
+                           table.key_path("blocks"), blocks));
+    }
+
+    // The ways may be given as a number, or as the word `full`.
+    let ways = match table.get("ways") {
+        None => "full".to_string(),
+        Some(&toml::Value::Integer(ways)) => ways.to_string(),
+        Some(toml::Value::String(ways)) => ways.clone(),
+        Some(other) => return Err(table.type_error("ways", "an integer or \"full\"", other)),
+    };
+    let geometry = CacheGeometry::parse(&ways, blocks).map_err(|e| format!("{}: {}", table.key_path("ways"), e))?;
+
+    Ok(LevelConfig {
+        geometry,
+        replacement: table.parse("replacement", ReplacementKind::Lru, ReplacementKind::parse)?,
+        latency: table.usize("latency", memory_cache::DEFAULT_LATENCY)?,
+    })
+}
+
+/// A table in a machine file, along with where it is, for error messages.
+struct Table<'a> {
+    path: String,
+    entries: &'a toml::value::Table,
+}
+
+impl<'a> Table<'a> {
+    fn new(path: String, value: &'a toml::Value) -> Result<Table<'a>, String> {
+        match *value {
+            toml::Value::Table(ref entries) => Ok(Table { path, entries }),
+            ref other => Err(format!("{}: Expected a table, found {}",
+                                     if path.is_empty() { "machine" } else { &path[..] }, other.type_str())),
+        }
+    }
+
+    /// The full path of one of this table's keys.
+    fn key_path(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    /// Check that the table has no keys other than the `expected` ones, which
+    /// would otherwise be silently ignored.
+    fn check_keys(&self, expected: &[&str]) -> Result<(), String> {
+        match self.entries.keys().find(|key| !expected.contains(&&key[..])) {
+            Some(key) => Err(format!("Unknown key: {}\nExpected one of: {}", self.key_path(key), expected.join(", "))),
+            None => Ok(()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a toml::Value> {
+        self.entries.get(key)
+    }
+
+    fn type_error(&self, key: &str, expected: &str, found: &toml::Value) -> String {
+        format!("{}: Expected {}, found {}", self.key_path(key), expected, found.type_str())
+    }
+
+    /// The table under `key`, if there is one.
+    fn table(&self, key: &str) -> Result<Option<Table<'a>>, String> {
+        self.get(key).map(|value| Table::new(self.key_path(key), value)).transpose()
+    }
+
+    /// The array of tables under `key`, if there is one.
+    fn tables(&self, key: &str) -> Result<Option<Vec<Table<'a>>>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(toml::Value::Array(values)) => {
+                values.iter().enumerate()
+                    .map(|(index, value)| Table::new(format!("{}[{}]", self.key_path(key), index), value))
+                    .collect::<Result<_, _>>()
+                    .map(Some)
+            },
+            Some(other) => Err(self.type_error(key, "an array of tables", other)),
+        }
+    }
+
+    /// The non-negative integer under `key`, or `default` if there is none.
+    fn usize(&self, key: &str, default: usize) -> Result<usize, String> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(&toml::Value::Integer(value)) if value >= 0 => Ok(value as usize),
+            Some(&toml::Value::Integer(value)) => {
+                Err(format!("{}: Expected a non-negative integer, found {}", self.key_path(key), value))
+            },
+            Some(other) => Err(self.type_error(key, "a non-negative integer", other)),
+        }
+    }
+
+    /// The string under `key` parsed with `parse`, or `default` if there is
+    /// none.
+    fn parse<T, F>(&self, key: &str, default: T, parse: F) -> Result<T, String>
+        where F: FnOnce(&str) -> Result<T, String>
+    {
+        match self.get(key) {
+            None => Ok(default),
+            Some(toml::Value::String(value)) => parse(value).map_err(|e| format!("{}: {}", self.key_path(key), e)),
+            Some(other) => Err(self.type_error(key, "a string", other)),
+        }
+    }
+
+    /// The array of strings under `key`, if there is one.
+    fn strings(&self, key: &str) -> Result<Option<Vec<String>>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(toml::Value::Array(values)) => {
+                values.iter()
+                    .map(|value| match *value {
+                        toml::Value::String(ref value) => Ok(value.clone()),
+                        ref other => Err(self.type_error(key, "an array of strings", other)),
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Some)
+            },
+            Some(other) => Err(self.type_error(key, "an array of strings", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that every machine, given in TOML and then in JSON, is rejected
+    /// with the expected error.
+    fn assert_rejected(cases: &[(&str, &str, &str)]) {
+        for &(toml, json, expected) in cases {
+            for (format, result) in [("TOML", MachineConfig::from_toml(toml)), ("JSON", MachineConfig::from_json(json))] {
+                match result {
+                    Ok(_) => panic!("{} machine was accepted, but expected: {}", format, expected),
+                    Err(e) => assert_eq!(e, expected, "{} machine", format),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reads_the_same_machine_from_toml_and_json() {
+        let toml = MachineConfig::from_toml("[system]\ncaches = 2\n[[caches.levels]]\nblocks = 16\nways = 4").unwrap();
+        let json = MachineConfig::from_json(r#"{"system": {"caches": 2}, "caches": {"levels": [{"blocks": 16, "ways": 4}]}}"#)
+            .unwrap();
+        assert_eq!(toml.system, json.system);
+        assert_eq!(toml.system.cache_size, 16);
+        assert_eq!(toml.classes[0].config.levels[0].geometry, CacheGeometry::new(4, 4));
+        assert_eq!(json.classes[0].config.levels[0].geometry, CacheGeometry::new(4, 4));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert_rejected(&[
+            ("cache = 1",
+             r#"{"cache": 1}"#,
+             "Unknown key: cache\nExpected one of: system, caches, interconnect, memory, workload, simulation"),
+            ("[system]\ncaches = 2\nways = 4",
+             r#"{"system": {"caches": 2, "ways": 4}}"#,
+             "Unknown key: system.ways\nExpected one of: block_size, main_memory_size, caches"),
+            ("[[caches.levels]]\nblocks = 8\nsets = 4",
+             r#"{"caches": {"levels": [{"blocks": 8, "sets": 4}]}}"#,
+             "Unknown key: caches.levels[0].sets\nExpected one of: blocks, ways, replacement, latency"),
+        ]);
+    }
+
+    #[test]
+    fn rejects_wrong_types() {
+        assert_rejected(&[
+            ("[caches]\nvictim_entries = \"two\"",
+             r#"{"caches": {"victim_entries": "two"}}"#,
+             "caches.victim_entries: Expected a non-negative integer, found string"),
+            ("[simulation]\nseed = -1",
+             r#"{"simulation": {"seed": -1}}"#,
+             "simulation.seed: Expected a non-negative integer, found -1"),
+            ("[caches]\nprotocol = 1",
+             r#"{"caches": {"protocol": 1}}"#,
+             "caches.protocol: Expected a string, found integer"),
+            ("system = 1",
+             r#"{"system": 1}"#,
+             "system: Expected a table, found integer"),
+            ("[[caches.levels]]\nblocks = 8\nways = true",
+             r#"{"caches": {"levels": [{"blocks": 8, "ways": true}]}}"#,
+             "caches.levels[0].ways: Expected an integer or \"full\", found boolean"),
+        ]);
+    }
+
+    #[test]
+    fn rejects_sizes_that_are_not_powers_of_two() {
+        assert_rejected(&[
+            ("[[caches.levels]]\nblocks = 12",
+             r#"{"caches": {"levels": [{"blocks": 12}]}}"#,
+             "caches.levels[0].blocks: Invalid number of blocks: 12\nExpected a power of two"),
+            ("[memory.llc]\nblocks = 100",
+             r#"{"memory": {"llc": {"blocks": 100}}}"#,
+             "memory.llc.blocks: Invalid number of blocks: 100\nExpected a power of two"),
+        ]);
+    }
+
+    #[test]
+    fn rejects_bad_class_names() {
+        assert_rejected(&[
+            ("[[caches.classes]]\ncount = 1",
+             r#"{"caches": {"classes": [{"count": 1}]}}"#,
+             "Missing key: caches.classes[0].name"),
+            ("[[caches.classes]]\nname = \"\"\ncount = 1",
+             r#"{"caches": {"classes": [{"name": "", "count": 1}]}}"#,
+             "caches.classes[0].name: Expected a non-empty string, found string"),
+            ("[[caches.classes]]\nname = 1\ncount = 1",
+             r#"{"caches": {"classes": [{"name": 1, "count": 1}]}}"#,
+             "caches.classes[0].name: Expected a non-empty string, found integer"),
+            ("[[caches.classes]]\nname = \"big\"\ncount = 1\n[[caches.classes]]\nname = \"big\"\ncount = 3",
+             r#"{"caches": {"classes": [{"name": "big", "count": 1}, {"name": "big", "count": 3}]}}"#,
+             "caches.classes[1].name: Duplicate class name: big"),
+        ]);
+    }
+}
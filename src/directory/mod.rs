@@ -0,0 +1,295 @@
+//! A directory controller, sitting next to main memory, that keeps track of
+//! which memory caches hold each block. Instead of broadcasting every message
+//! on the bus, it sends each one only to the caches involved.
+
+extern crate bit_vec;
+
+use bus::{self, BusMessage, Endpoint};
+use main_memory;
+use memory_cache;
+use system::SystemConfig;
+
+pub mod sharers;
+
+use self::sharers::{SharerEncoding, Sharers};
+
+/// What the directory knows about a single block.
+struct Entry {
+    /// The caches that may hold the block. Unless they are configured to
+    /// announce it, caches drop clean blocks without telling anyone, so this
+    /// is a superset of the real sharers, even when the encoding is precise.
+    sharers: Sharers,
+
+    /// Exactly which caches were added to `sharers`. This costs a full bit
+    /// vector, and is only kept to measure the encoding's imprecision.
+    present: bit_vec::BitVec,
+
+    /// The cache that was most recently given the block exclusively, and has
+    /// not been asked to share it since.
+    owner: Option<memory_cache::MemoryCacheId>,
+
+    /// The caches with a request for the block in flight. They may not hold
+    /// it yet, but must still see every write to it meanwhile, or they would
+    /// fill a stale copy once their response arrives.
+    requesters: bit_vec::BitVec,
+}
+
+impl Entry {
+    fn new(encoding: SharerEncoding, caches: usize) -> Entry {
+        Entry {
+            sharers: encoding.empty(caches),
+            present: bit_vec::BitVec::from_elem(caches, false),
+            owner: None,
+            requesters: bit_vec::BitVec::from_elem(caches, false),
+        }
+    }
+
+    /// Every cache that must be sent a write to the block, other than
+    /// `except`, if any: those that may hold it, and those with a request for
+    /// it in flight.
+    fn writes_to(&self, encoding: SharerEncoding, except: Option<memory_cache::MemoryCacheId>,
+                 stats: &bus::BusStats) -> Vec<Endpoint> {
+        let mut targets = self.holders(encoding, except, stats);
+        for (id, requesting) in self.requesters.iter().enumerate() {
+            let endpoint = Endpoint::Cache(id as memory_cache::MemoryCacheId);
+            if requesting && Some(id as memory_cache::MemoryCacheId) != except && !targets.contains(&endpoint) {
+                targets.push(endpoint);
+            }
+        }
+        targets
+    }
+
+    /// Every cache that must be sent messages about the block, other than
+    /// `who`. Records each one that was only included due to the encoding's
+    /// imprecision in `stats`.
+    fn others(&self, encoding: SharerEncoding, who: memory_cache::MemoryCacheId,
+              stats: &bus::BusStats) -> Vec<Endpoint> {
+        self.holders(encoding, Some(who), stats)
+    }
+
+    /// Every cache that must be sent messages about the block, other than
+    /// `except`, if any. Records each one that was only included due to the
+    /// encoding's imprecision in `stats`.
+    fn holders(&self, encoding: SharerEncoding, except: Option<memory_cache::MemoryCacheId>,
+               stats: &bus::BusStats) -> Vec<Endpoint> {
+        self.sharers.targets(encoding, self.present.len()).into_iter()
+            .filter(|&id| Some(id) != except)
+            .map(|id| {
+                if !self.present[id as usize] {
+                    stats.record_extra_delivery();
+                }
+                Endpoint::Cache(id)
+            })
+            .collect()
+    }
+
+    /// Might any cache other than `who` hold the block?
+    fn has_others(&self, encoding: SharerEncoding, who: memory_cache::MemoryCacheId) -> bool {
+        self.sharers.targets(encoding, self.present.len()).into_iter().any(|id| id != who)
+    }
+
+    /// Add `who` to the caches that may hold the block. Returns the cache
+    /// whose copy must be invalidated to make room for it, if any.
+    fn add_sharer(&mut self, encoding: SharerEncoding, who: memory_cache::MemoryCacheId)
+                  -> Option<memory_cache::MemoryCacheId> {
+        self.present.set(who as usize, true);
+        let evicted = self.sharers.insert(encoding, who);
+        if let Some(evicted) = evicted {
+            self.present.set(evicted as usize, false);
+            if self.owner == Some(evicted) {
+                self.owner = None;
+            }
+        }
+        evicted
+    }
+
+    /// Remove `who` from the caches that may hold the block, after it evicted
+    /// its copy.
+    fn remove_sharer(&mut self, encoding: SharerEncoding, who: memory_cache::MemoryCacheId) {
+        self.present.set(who as usize, false);
+        self.sharers.remove(encoding, who);
+        if self.owner == Some(who) {
+            self.owner = None;
+        }
+    }
+
+    /// Forget every cache that may hold the block.
+    fn clear(&mut self) {
+        self.sharers.clear();
+        self.present.clear();
+        self.owner = None;
+    }
+
+    /// Make `who` the only cache holding the block, and its owner.
+    fn set_exclusive(&mut self, encoding: SharerEncoding, who: memory_cache::MemoryCacheId) {
+        self.clear();
+        self.add_sharer(encoding, who);
+        self.owner = Some(who);
+    }
+}
+
+/// A directory-based topology for the bus.
+pub struct Directory {
+    encoding: SharerEncoding,
+    caches: usize,
+    block_size: usize,
+    entries: Vec<Entry>,
+}
+
+impl Directory {
+    /// Create a directory for the given system that stores each block's
+    /// sharers with the given encoding.
+    pub fn new(system: &SystemConfig, encoding: SharerEncoding) -> Directory {
+        let caches = system.number_of_caches;
+        Directory {
+            encoding,
+            caches,
+            block_size: system.block_size,
+            entries: (0..system.blocks()).map(|_| Entry::new(encoding, caches)).collect(),
+        }
+    }
+
+    /// The total number of bits the directory needs to store every block's
+    /// sharers.
+    pub fn storage_bits(&self) -> usize {
+        self.entries.len() * self.encoding.bits_per_entry(self.caches)
+    }
+}
+
+impl bus::Topology for Directory {
+    fn name(&self) -> &'static str {
+        "directory"
+    }
+
+    fn route(&mut self, msg: BusMessage, stats: &bus::BusStats) -> Vec<(Endpoint, BusMessage)> {
+        let encoding = self.encoding;
+        let mut deliveries = vec![];
+        let mut recalls = vec![];
+
+        match msg {
+            BusMessage::ReadRequest { who, tag: _, block } => {
+                // Only the owner needs to see the read, if there is one.
+                // Otherwise, any of the sharers might be the one that answers.
+                let entry = &mut self.entries[block.0];
+                entry.requesters.set(who as usize, true);
+                match entry.owner {
+                    Some(owner) if owner != who => deliveries.push(Endpoint::Cache(owner)),
+                    _ => deliveries.extend(entry.others(encoding, who, stats)),
+                }
+                deliveries.push(Endpoint::MainMemory);
+            },
+
+            BusMessage::ReadResponse { who, tag: _, from, block, ref data, shared: _ } => {
+                self.entries[block.0].requesters.set(who as usize, false);
+                if data.is_some() {
+                    let entry = &mut self.entries[block.0];
+                    if from == bus::ResponseSender::MainMemory && !entry.has_others(encoding, who) {
+                        entry.set_exclusive(encoding, who);
+                    } else {
+                        recalls.extend(entry.add_sharer(encoding, who).map(|id| (id, block)));
+                        entry.owner = None;
+                    }
+                }
+                deliveries.push(Endpoint::Cache(who));
+            },
+
+            BusMessage::ReadExclusiveRequest { who, tag: _, block } => {
+                let entry = &mut self.entries[block.0];
+                deliveries.extend(entry.writes_to(encoding, Some(who), stats));
+                deliveries.push(Endpoint::MainMemory);
+                entry.requesters.set(who as usize, true);
+            },
+
+            BusMessage::ReadExclusiveResponse { who, tag: _, block, ref data } => {
+                self.entries[block.0].requesters.set(who as usize, false);
+                if data.is_some() {
+                    self.entries[block.0].set_exclusive(encoding, who);
+                }
+                deliveries.push(Endpoint::Cache(who));
+            },
+
+            BusMessage::WriteRequest { block: _, data: _, evicted: _ } => {
+                deliveries.push(Endpoint::MainMemory);
+            },
+
+            BusMessage::UpgradeRequest { who, tag: _, block } => {
+                // Whether or not the upgrade succeeds, every other copy is
+                // gone.
+                let entry = &mut self.entries[block.0];
+                deliveries.extend(entry.writes_to(encoding, Some(who), stats));
+                // The requester learns when its upgrade is ordered after
+                // others.
+                deliveries.push(Endpoint::Cache(who));
+                deliveries.push(Endpoint::MainMemory);
+                entry.set_exclusive(encoding, who);
+            },
+
+            BusMessage::UpgradeResponse { who, tag: _, ok: _ } => {
+                deliveries.push(Endpoint::Cache(who));
+            },
+
+            BusMessage::Update { who, address, value: _, shared: _ } => {
+                let block = main_memory::Block::for_addr(address, self.block_size);
+                let entry = &mut self.entries[block.0];
+                deliveries.extend(entry.writes_to(encoding, Some(who), stats));
+                // The writer learns when its update is ordered after others.
+                deliveries.push(Endpoint::Cache(who));
+                deliveries.push(Endpoint::MainMemory);
+                recalls.extend(entry.add_sharer(encoding, who).map(|id| (id, block)));
+                entry.owner = Some(who);
+            },
+
+            // Main memory needs to see these too, in case its last-level
+            // cache takes clean victims.
+            BusMessage::PutClean { who, block } => {
+                self.entries[block.0].remove_sharer(encoding, who);
+                deliveries.push(Endpoint::MainMemory);
+            },
+
+            // Main memory's last-level cache is taking the block back from
+            // every cache holding it.
+            BusMessage::BackInvalidate { block } => {
+                let entry = &mut self.entries[block.0];
+                deliveries.extend(entry.writes_to(encoding, None, stats));
+                entry.clear();
+            },
+        }
+
+        let mut deliveries: Vec<_> = deliveries.into_iter().map(|endpoint| (endpoint, msg.clone())).collect();
+        for (id, block) in recalls {
+            let recall = BusMessage::BackInvalidate { block };
+            stats.record(&recall);
+            deliveries.push((Endpoint::Cache(id), recall));
+        }
+        deliveries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The number of caches sent a write to a block held by `sharers` among
+    /// eight caches, sent by the first of them, and how many of those were
+    /// recorded as extra deliveries.
+    fn extra_deliveries(encoding: SharerEncoding, sharers: &[memory_cache::MemoryCacheId]) -> (usize, usize) {
+        let stats = bus::BusStats::new(8, 1);
+        let mut entry = Entry::new(encoding, 8);
+        for &id in sharers {
+            entry.add_sharer(encoding, id);
+        }
+        let targets = entry.writes_to(encoding, sharers.first().cloned(), &stats);
+        (targets.len(), stats.extra_deliveries())
+    }
+
+    #[test]
+    fn imprecise_encodings_count_over_invalidations() {
+        assert_eq!(extra_deliveries(SharerEncoding::FullBitVector, &[0, 5]), (1, 0));
+        // Caches 1 and 4 share bits with 0 and 5.
+        assert_eq!(extra_deliveries(SharerEncoding::CoarseVector { group_size: 2 }, &[0, 5]), (3, 2));
+        assert_eq!(extra_deliveries(SharerEncoding::CoarseVector { group_size: 4 }, &[0, 5]), (7, 6));
+        // The third sharer overflows the pointers, so every cache is sent it.
+        assert_eq!(extra_deliveries(SharerEncoding::LimitedBroadcast { pointers: 2 }, &[1, 3]), (1, 0));
+        assert_eq!(extra_deliveries(SharerEncoding::LimitedBroadcast { pointers: 2 }, &[1, 3, 5]), (7, 5));
+    }
+}
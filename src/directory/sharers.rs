@@ -0,0 +1,285 @@
+//! Encodings for the set of caches sharing a block, trading directory storage
+//! for precision.
+
+extern crate bit_vec;
+
+use memory_cache;
+
+/// How a directory entry encodes the caches sharing its block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SharerEncoding {
+    /// One bit per cache. Always precise.
+    FullBitVector,
+
+    /// Up to `pointers` cache ids (Dir_i_B). Once there are more sharers than
+    /// pointers, the entry overflows and every cache is sent every message
+    /// about the block, until it is held exclusively again.
+    LimitedBroadcast {
+        /// The number of cache ids the entry can hold.
+        pointers: usize,
+    },
+
+    /// Up to `pointers` cache ids (Dir_i_NB). Once they are all in use, adding
+    /// another sharer invalidates one of the existing ones to make room.
+    LimitedNoBroadcast {
+        /// The number of cache ids the entry can hold.
+        pointers: usize,
+    },
+
+    /// One bit per group of `group_size` caches, set if any cache in the group
+    /// may be sharing. Every cache in a set group is sent every message about
+    /// the block.
+    CoarseVector {
+        /// The number of caches each bit stands for.
+        group_size: usize,
+    },
+}
+
+impl SharerEncoding {
+    /// Parse an encoding from its name: `full`, `dir<i>b`, `dir<i>nb`, or
+    /// `coarse<k>`.
+    pub fn parse(name: &str) -> Result<SharerEncoding, String> {
+        let number = |digits: &str| -> Result<usize, String> {
+            match digits.parse() {
+                Ok(n) if n > 0 => Ok(n),
+                _ => Err(format!("Expected a positive number in sharer encoding: {}", name)),
+            }
+        };
+
+        let name = name.to_lowercase();
+        if name == "full" {
+            Ok(SharerEncoding::FullBitVector)
+        } else if let Some(pointers) = name.strip_prefix("dir").and_then(|n| n.strip_suffix("nb")) {
+            Ok(SharerEncoding::LimitedNoBroadcast { pointers: number(pointers)? })
+        } else if let Some(pointers) = name.strip_prefix("dir").and_then(|n| n.strip_suffix('b')) {
+            Ok(SharerEncoding::LimitedBroadcast { pointers: number(pointers)? })
+        } else if let Some(group_size) = name.strip_prefix("coarse") {
+            Ok(SharerEncoding::CoarseVector { group_size: number(group_size)? })
+        } else {
+            Err(format!("Unknown sharer encoding: {}\nExpected one of: full, dir<i>b, dir<i>nb, coarse<k>",
+                        name))
+        }
+    }
+
+    /// The number of bits one directory entry needs to store its sharers,
+    /// among the given number of caches.
+    pub fn bits_per_entry(&self, caches: usize) -> usize {
+        let pointer_bits = (caches as f64).log2().ceil().max(1.0) as usize;
+
+        match *self {
+            SharerEncoding::FullBitVector => caches,
+            // One extra bit records whether the pointers have overflowed.
+            SharerEncoding::LimitedBroadcast { pointers } => pointers * pointer_bits + 1,
+            SharerEncoding::LimitedNoBroadcast { pointers } => pointers * pointer_bits,
+            SharerEncoding::CoarseVector { group_size } => caches.div_ceil(group_size),
+        }
+    }
+
+    /// An empty set of sharers in this encoding, among the given number of
+    /// caches.
+    pub fn empty(&self, caches: usize) -> Sharers {
+        match *self {
+            SharerEncoding::FullBitVector => {
+                Sharers::Vector(bit_vec::BitVec::from_elem(caches, false))
+            },
+            SharerEncoding::LimitedBroadcast { pointers } |
+            SharerEncoding::LimitedNoBroadcast { pointers } => {
+                Sharers::Pointers { ids: Vec::with_capacity(pointers), overflowed: false }
+            },
+            SharerEncoding::CoarseVector { group_size } => {
+                let groups = caches.div_ceil(group_size);
+                Sharers::Vector(bit_vec::BitVec::from_elem(groups, false))
+            },
+        }
+    }
+}
+
+/// A set of caches sharing a block, as stored by some `SharerEncoding`.
+pub enum Sharers {
+    /// A bit vector, with one bit per cache or per group of caches.
+    Vector(bit_vec::BitVec),
+
+    /// A limited number of cache ids.
+    Pointers {
+        /// The cache ids.
+        ids: Vec<memory_cache::MemoryCacheId>,
+        /// Whether more caches have been added than there is room for.
+        overflowed: bool,
+    },
+}
+
+impl Sharers {
+    /// Add cache `id` to the set. Returns the cache that had to be removed to
+    /// make room for it, if any. That cache's copy must be invalidated.
+    pub fn insert(&mut self, encoding: SharerEncoding, id: memory_cache::MemoryCacheId)
+                  -> Option<memory_cache::MemoryCacheId> {
+        match (self, encoding) {
+            (Sharers::Vector(bits), SharerEncoding::CoarseVector { group_size }) => {
+                bits.set(id as usize / group_size, true);
+                None
+            },
+            (Sharers::Vector(bits), _) => {
+                bits.set(id as usize, true);
+                None
+            },
+            (Sharers::Pointers { ids, overflowed }, encoding) => {
+                if ids.contains(&id) || *overflowed {
+                    return None;
+                }
+
+                let pointers = match encoding {
+                    SharerEncoding::LimitedBroadcast { pointers } |
+                    SharerEncoding::LimitedNoBroadcast { pointers } => pointers,
+                    _ => unreachable!("Pointers are only used by limited pointer encodings"),
+                };
+
+                if ids.len() < pointers {
+                    ids.push(id);
+                    return None;
+                }
+
+                match encoding {
+                    SharerEncoding::LimitedNoBroadcast { .. } => {
+                        // Evict the oldest sharer.
+                        let evicted = ids.remove(0);
+                        ids.push(id);
+                        Some(evicted)
+                    },
+                    _ => {
+                        *overflowed = true;
+                        None
+                    },
+                }
+            },
+        }
+    }
+
+    /// Remove cache `id` from the set, if the encoding can tell it apart from
+    /// the others. A coarse vector's bit stands for other caches too, and an
+    /// overflowed set of pointers no longer knows who its members are, so
+    /// both stay as they are.
+    pub fn remove(&mut self, encoding: SharerEncoding, id: memory_cache::MemoryCacheId) {
+        match (self, encoding) {
+            (Sharers::Vector(_), SharerEncoding::CoarseVector { .. }) => { },
+            (Sharers::Vector(bits), _) => bits.set(id as usize, false),
+            (Sharers::Pointers { ids, overflowed: false }, _) => ids.retain(|&other| other != id),
+            (Sharers::Pointers { ids: _, overflowed: true }, _) => { },
+        }
+    }
+
+    /// Remove every cache from the set.
+    pub fn clear(&mut self) {
+        match *self {
+            Sharers::Vector(ref mut bits) => bits.clear(),
+            Sharers::Pointers { ref mut ids, ref mut overflowed } => {
+                ids.clear();
+                *overflowed = false;
+            },
+        }
+    }
+
+    /// Every cache, among the given number of caches, that must be sent
+    /// messages about the block. This may include caches that are not really
+    /// sharing it, when the encoding is imprecise.
+    pub fn targets(&self, encoding: SharerEncoding, caches: usize) -> Vec<memory_cache::MemoryCacheId> {
+        let all = 0..caches;
+        let ids: Vec<usize> = match (self, encoding) {
+            (Sharers::Vector(bits), SharerEncoding::CoarseVector { group_size }) => {
+                all.filter(|id| bits[id / group_size]).collect()
+            },
+            (Sharers::Vector(bits), _) => all.filter(|&id| bits[id]).collect(),
+            (Sharers::Pointers { ids: _, overflowed: true }, _) => all.collect(),
+            (Sharers::Pointers { ids, overflowed: false }, _) => {
+                return ids.clone();
+            },
+        };
+
+        ids.into_iter().map(|id| id as memory_cache::MemoryCacheId).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CACHES: usize = 8;
+
+    /// Add each of `ids` to an empty set in the given `encoding`, returning
+    /// the set and every cache evicted along the way.
+    fn sharers_of(encoding: SharerEncoding, ids: &[memory_cache::MemoryCacheId])
+                  -> (Sharers, Vec<memory_cache::MemoryCacheId>) {
+        let mut sharers = encoding.empty(CACHES);
+        let evicted = ids.iter().filter_map(|&id| sharers.insert(encoding, id)).collect();
+        (sharers, evicted)
+    }
+
+    /// The number of targets that are not really sharing the block.
+    fn over_invalidations(sharers: &Sharers, encoding: SharerEncoding, ids: &[memory_cache::MemoryCacheId])
+                          -> usize {
+        sharers.targets(encoding, CACHES).iter().filter(|id| !ids.contains(id)).count()
+    }
+
+    #[test]
+    fn limited_broadcast_overflows_to_every_cache() {
+        let encoding = SharerEncoding::LimitedBroadcast { pointers: 2 };
+
+        let (mut sharers, evicted) = sharers_of(encoding, &[1, 3]);
+        assert!(evicted.is_empty());
+        assert_eq!(sharers.targets(encoding, CACHES), vec![1, 3]);
+
+        // The third sharer overflows the pointers without evicting anyone.
+        assert_eq!(sharers.insert(encoding, 5), None);
+        assert_eq!(sharers.targets(encoding, CACHES), (0..CACHES as u8).collect::<Vec<_>>());
+
+        // Once overflowed, the set no longer knows who to remove.
+        sharers.remove(encoding, 3);
+        assert_eq!(sharers.targets(encoding, CACHES).len(), CACHES);
+        assert_eq!(over_invalidations(&sharers, encoding, &[1, 3, 5]), CACHES - 3);
+
+        // Until the block is held exclusively again.
+        sharers.clear();
+        sharers.insert(encoding, 2);
+        assert_eq!(sharers.targets(encoding, CACHES), vec![2]);
+    }
+
+    #[test]
+    fn limited_broadcast_does_not_overflow_on_a_repeated_sharer() {
+        let encoding = SharerEncoding::LimitedBroadcast { pointers: 2 };
+        let (sharers, _) = sharers_of(encoding, &[1, 3, 3, 1]);
+        assert_eq!(sharers.targets(encoding, CACHES), vec![1, 3]);
+    }
+
+    #[test]
+    fn limited_no_broadcast_evicts_the_oldest_sharer() {
+        let encoding = SharerEncoding::LimitedNoBroadcast { pointers: 2 };
+        let (sharers, evicted) = sharers_of(encoding, &[1, 3, 5, 7]);
+        assert_eq!(evicted, vec![1, 3]);
+        assert_eq!(sharers.targets(encoding, CACHES), vec![5, 7]);
+    }
+
+    #[test]
+    fn coarse_vector_invalidates_whole_groups() {
+        let encoding = SharerEncoding::CoarseVector { group_size: 2 };
+        let (mut sharers, _) = sharers_of(encoding, &[0, 5]);
+        assert_eq!(sharers.targets(encoding, CACHES), vec![0, 1, 4, 5]);
+        assert_eq!(over_invalidations(&sharers, encoding, &[0, 5]), 2);
+
+        // A bit stands for its whole group, so removing one cache leaves it.
+        sharers.remove(encoding, 5);
+        assert_eq!(over_invalidations(&sharers, encoding, &[0]), 3);
+
+        let encoding = SharerEncoding::CoarseVector { group_size: 4 };
+        let (sharers, _) = sharers_of(encoding, &[6]);
+        assert_eq!(sharers.targets(encoding, CACHES), vec![4, 5, 6, 7]);
+        assert_eq!(over_invalidations(&sharers, encoding, &[6]), 3);
+    }
+
+    #[test]
+    fn full_bit_vector_is_precise() {
+        let encoding = SharerEncoding::FullBitVector;
+        let (mut sharers, _) = sharers_of(encoding, &[0, 5, 6]);
+        sharers.remove(encoding, 5);
+        assert_eq!(sharers.targets(encoding, CACHES), vec![0, 6]);
+        assert_eq!(over_invalidations(&sharers, encoding, &[0, 6]), 0);
+    }
+}
@@ -0,0 +1,369 @@
+//! The DRAM behind main memory: channels of ranks of banks, each bank an array
+//! of rows with a row buffer in front of it, as on real memory modules.
+//!
+//! A bank can only read or write the row in its row buffer. Reading a block
+//! out of the open row takes tCAS cycles. If the bank has no row open, it
+//! must first activate the block's row, taking tRCD cycles more, and if it
+//! has another row open, it must first precharge that one back into the
+//! array, taking tRP cycles more still. The block then crosses its channel's
+//! data bus, taking tBURST cycles, and the memory controller, taking the
+//! system's DRAM latency.
+//!
+//! Each bank serves one access at a time, and each channel's data bus carries
+//! one block at a time, but different banks work at once. So memory latency
+//! depends on the access pattern: how often accesses find their row open, and
+//! how evenly the address mapping spreads them over the banks.
+
+use engine::Cycle;
+use main_memory::Block;
+use system::SystemConfig;
+
+/// What a bank does with a row once an access to it is done.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PagePolicy {
+    /// Leave the row open, betting that the next access to the bank is to the
+    /// same row.
+    Open,
+
+    /// Precharge the row at once, so that the next access to the bank never
+    /// waits for a precharge, but always activates its row.
+    Closed,
+}
+
+impl PagePolicy {
+    /// Every page policy.
+    pub const ALL: [PagePolicy; 2] = [PagePolicy::Open, PagePolicy::Closed];
+
+    /// Parse a page policy from its name.
+    pub fn parse(name: &str) -> Result<PagePolicy, String> {
+        let lowercase = name.to_lowercase();
+        PagePolicy::ALL.iter()
+            .find(|policy| policy.name() == lowercase)
+            .cloned()
+            .ok_or_else(|| {
+                let names: Vec<_> = PagePolicy::ALL.iter().map(|policy| policy.name()).collect();
+                format!("Unknown page policy: {}\nExpected one of: {}", name, names.join(", "))
+            })
+    }
+
+    /// Get this policy's name.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PagePolicy::Open => "open",
+            PagePolicy::Closed => "closed",
+        }
+    }
+}
+
+/// How the blocks of main memory are spread over the channels, ranks, banks
+/// and rows of DRAM. Each is named for the order its fields are taken from a
+/// block's index, most significant first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressMapping {
+    /// Row, rank, bank, channel, then column: consecutive blocks fill a row
+    /// before moving on to the next channel's, so a stream of accesses keeps
+    /// finding its row open.
+    RowInterleaved,
+
+    /// Row, column, rank, bank, then channel: consecutive blocks go to each
+    /// channel in turn, then each bank, so a stream of accesses keeps every
+    /// bank busy at once.
+    BlockInterleaved,
+
+    /// Like row interleaving, but with the bank's number XORed with the low
+    /// bits of the row's, so that blocks a multiple of a row apart, which
+    /// would otherwise conflict in the same bank, go to different ones.
+    Permutation,
+}
+
+impl AddressMapping {
+    /// Every address mapping.
+    pub const ALL: [AddressMapping; 3] = [
+        AddressMapping::RowInterleaved,
+        AddressMapping::BlockInterleaved,
+        AddressMapping::Permutation,
+    ];
+
+    /// Parse an address mapping from its name.
+    pub fn parse(name: &str) -> Result<AddressMapping, String> {
+        let lowercase = name.to_lowercase();
+        AddressMapping::ALL.iter()
+            .find(|mapping| mapping.name() == lowercase)
+            .cloned()
+            .ok_or_else(|| {
+                let names: Vec<_> = AddressMapping::ALL.iter().map(|mapping| mapping.name()).collect();
+                format!("Unknown address mapping: {}\nExpected one of: {}", name, names.join(", "))
+            })
+    }
+
+    /// Get this mapping's name.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AddressMapping::RowInterleaved => "row-interleaved",
+            AddressMapping::BlockInterleaved => "block-interleaved",
+            AddressMapping::Permutation => "permutation",
+        }
+    }
+}
+
+/// Where a block lives in DRAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// The channel.
+    pub channel: usize,
+    /// The rank within the channel.
+    pub rank: usize,
+    /// The bank within the rank.
+    pub bank: usize,
+    /// The row within the bank.
+    pub row: usize,
+    /// The block within the row.
+    pub column: usize,
+}
+
+/// What an access found in its bank's row buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RowBuffer {
+    /// The block's row was open.
+    Hit,
+    /// No row was open, so the block's row had to be activated.
+    Miss,
+    /// Another row was open, so it had to be precharged, and the block's row
+    /// activated.
+    Conflict,
+}
+
+/// How one access to DRAM went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DramAccess {
+    /// The cycle the block has been read or written at.
+    pub done: Cycle,
+    /// What it found in the row buffer.
+    pub row_buffer: RowBuffer,
+}
+
+/// A bank's row buffer, and when the bank is next free.
+#[derive(Clone, Copy, Debug, Default)]
+struct Bank {
+    open_row: Option<usize>,
+    free: Cycle,
+}
+
+/// The banks of DRAM, and when each is next free.
+pub struct Dram {
+    policy: PagePolicy,
+    mapping: AddressMapping,
+    channels: usize,
+    ranks: usize,
+    banks_per_rank: usize,
+    // The number of blocks in a row.
+    columns: usize,
+    t_cas: Cycle,
+    t_rcd: Cycle,
+    t_rp: Cycle,
+    t_burst: Cycle,
+    latency: Cycle,
+    // By channel, then rank, then bank.
+    banks: Vec<Bank>,
+    // When each channel's data bus is next free.
+    channel_free: Vec<Cycle>,
+}
+
+impl Dram {
+    /// Create the DRAM of the given `system`, following the page `policy`,
+    /// with its blocks laid out as `mapping` says.
+    pub fn new(policy: PagePolicy, mapping: AddressMapping, system: &SystemConfig) -> Dram {
+        let banks = system.dram_channels * system.dram_ranks * system.dram_banks;
+        Dram {
+            policy,
+            mapping,
+            channels: system.dram_channels,
+            ranks: system.dram_ranks,
+            banks_per_rank: system.dram_banks,
+            columns: system.dram_row_size / system.block_size,
+            t_cas: system.t_cas as Cycle,
+            t_rcd: system.t_rcd as Cycle,
+            t_rp: system.t_rp as Cycle,
+            t_burst: system.t_burst as Cycle,
+            latency: system.dram_latency as Cycle,
+            banks: vec![Bank::default(); banks],
+            channel_free: vec![0; system.dram_channels],
+        }
+    }
+
+    /// Find where `block` lives, as the address mapping says.
+    pub fn locate(&self, block: Block) -> Location {
+        // Take each field off the bottom of the block's index in turn.
+        let mut rest = block.0;
+        let mut take = |count: usize| {
+            let field = rest % count;
+            rest /= count;
+            field
+        };
+
+        let mut location = match self.mapping {
+            AddressMapping::RowInterleaved | AddressMapping::Permutation => {
+                let column = take(self.columns);
+                let channel = take(self.channels);
+                let bank = take(self.banks_per_rank);
+                let rank = take(self.ranks);
+                Location { channel, rank, bank, row: 0, column }
+            },
+            AddressMapping::BlockInterleaved => {
+                let channel = take(self.channels);
+                let bank = take(self.banks_per_rank);
+                let rank = take(self.ranks);
+                let column = take(self.columns);
+                Location { channel, rank, bank, row: 0, column }
+            },
+        };
+        location.row = rest;
+
+        if self.mapping == AddressMapping::Permutation {
+            // The number of banks is a power of two, so this is one of them.
+            location.bank ^= location.row % self.banks_per_rank;
+        }
+        location
+    }
+
+    /// Read or write `block`, starting at cycle `now`. The access waits for
+    /// its bank, and then for its channel's data bus, if either is busy.
+    pub fn access(&mut self, block: Block, now: Cycle) -> DramAccess {
+        let location = self.locate(block);
+        let index = (location.channel * self.ranks + location.rank) * self.banks_per_rank + location.bank;
+        let bank = &mut self.banks[index];
+
+        let start = now.max(bank.free);
+        let (row_buffer, opening) = match bank.open_row {
+            Some(row) if row == location.row => (RowBuffer::Hit, 0),
+            Some(_) => (RowBuffer::Conflict, self.t_rp + self.t_rcd),
+            None => (RowBuffer::Miss, self.t_rcd),
+        };
+
+        let burst = (start + opening + self.t_cas).max(self.channel_free[location.channel]);
+        let done = burst + self.t_burst;
+        self.channel_free[location.channel] = done;
+
+        match self.policy {
+            PagePolicy::Open => {
+                bank.open_row = Some(location.row);
+                bank.free = done;
+            },
+            PagePolicy::Closed => {
+                bank.open_row = None;
+                bank.free = done + self.t_rp;
+            },
+        }
+
+        DramAccess {
+            done: done + self.latency,
+            row_buffer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two channels of two ranks of four banks, with rows of four blocks, and
+    /// a different number of cycles for each step of an access.
+    fn dram(policy: PagePolicy, mapping: AddressMapping) -> Dram {
+        let system = SystemConfig {
+            block_size: 32,
+            dram_channels: 2,
+            dram_ranks: 2,
+            dram_banks: 4,
+            dram_row_size: 128,
+            t_cas: 10,
+            t_rcd: 20,
+            t_rp: 30,
+            t_burst: 4,
+            dram_latency: 100,
+            ..SystemConfig::default()
+        };
+        Dram::new(policy, mapping, &system)
+    }
+
+    /// The index of the block with the given `fields`, least significant
+    /// first, each with the number of values it takes.
+    fn index(fields: &[(usize, usize)]) -> Block {
+        Block(fields.iter().rev().fold(0, |index, &(field, count)| index * count + field))
+    }
+
+    #[test]
+    fn mappings_take_fields_in_order() {
+        let row_interleaved = dram(PagePolicy::Open, AddressMapping::RowInterleaved);
+        let block_interleaved = dram(PagePolicy::Open, AddressMapping::BlockInterleaved);
+        let permutation = dram(PagePolicy::Open, AddressMapping::Permutation);
+
+        for row in 0..8 {
+            for rank in 0..2 {
+                for bank in 0..4 {
+                    for channel in 0..2 {
+                        for column in 0..4 {
+                            let location = Location { channel, rank, bank, row, column };
+                            let block = index(&[(column, 4), (channel, 2), (bank, 4), (rank, 2), (row, 8)]);
+                            assert_eq!(row_interleaved.locate(block), location);
+                            assert_eq!(permutation.locate(block), Location { bank: bank ^ (row % 4), ..location });
+
+                            let block = index(&[(channel, 2), (bank, 4), (rank, 2), (column, 4), (row, 8)]);
+                            assert_eq!(block_interleaved.locate(block), location);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn permutation_spreads_rows_over_banks() {
+        let row_interleaved = dram(PagePolicy::Open, AddressMapping::RowInterleaved);
+        let permutation = dram(PagePolicy::Open, AddressMapping::Permutation);
+
+        // Blocks a row of every channel, bank and rank apart all fall in the
+        // same bank when rows are interleaved, but each in its own when the
+        // banks are permuted.
+        let blocks: Vec<_> = (0..4).map(|row| Block(row * 4 * 2 * 4 * 2)).collect();
+        let banks = |dram: &Dram| -> Vec<usize> { blocks.iter().map(|&block| dram.locate(block).bank).collect() };
+        assert_eq!(banks(&row_interleaved), vec![0, 0, 0, 0]);
+        assert_eq!(banks(&permutation), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn open_pages_time_hits_misses_and_conflicts() {
+        let mut dram = dram(PagePolicy::Open, AddressMapping::RowInterleaved);
+        let next_row = Block(4 * 2 * 4 * 2);
+
+        // tRCD + tCAS + tBURST, and the controller's latency.
+        assert_eq!(dram.access(Block(0), 0), DramAccess { done: 134, row_buffer: RowBuffer::Miss });
+        // tCAS + tBURST.
+        assert_eq!(dram.access(Block(1), 1000), DramAccess { done: 1114, row_buffer: RowBuffer::Hit });
+        // tRP + tRCD + tCAS + tBURST.
+        assert_eq!(dram.access(next_row, 2000), DramAccess { done: 2164, row_buffer: RowBuffer::Conflict });
+    }
+
+    #[test]
+    fn closed_pages_precharge_after_each_access() {
+        let mut dram = dram(PagePolicy::Closed, AddressMapping::RowInterleaved);
+
+        assert_eq!(dram.access(Block(0), 0), DramAccess { done: 134, row_buffer: RowBuffer::Miss });
+        // The same row is closed again, and the bank busy precharging it for
+        // tRP after the burst.
+        assert_eq!(dram.access(Block(1), 34), DramAccess { done: 198, row_buffer: RowBuffer::Miss });
+    }
+
+    #[test]
+    fn banks_and_channels_serve_one_access_at_a_time() {
+        let mut dram = dram(PagePolicy::Open, AddressMapping::RowInterleaved);
+
+        assert_eq!(dram.access(Block(0), 0).done, 134);
+        // The same bank waits for the first access to be done.
+        assert_eq!(dram.access(Block(1), 0).done, 148);
+        // Another bank on the same channel opens its row at once, but waits
+        // for the channel's data bus.
+        assert_eq!(dram.access(Block(8), 0).done, 152);
+        // Another channel is free.
+        assert_eq!(dram.access(Block(4), 0).done, 134);
+    }
+}
@@ -0,0 +1,385 @@
+//! The discrete-event engine that runs a simulated machine on one thread.
+//!
+//! The bus, main memory and each memory cache are actors that never wait:
+//! each takes one step whenever something happens to it, and puts whatever
+//! it sends in its outbox. The engine keeps a virtual clock, counted in
+//! cycles, and a queue of events ordered by the cycle they happen at. Events
+//! at the same cycle happen in the order they were scheduled, so a run only
+//! depends on the machine and its workload, and replays exactly.
+//!
+//! Time passes in the actors as the system's latencies say:
+//!
+//! * A cache looks a block up in as many cycles as the levels it searches
+//!   take, and responds to messages it snoops after the snoop latency. Its
+//!   messages go out in the order it sent them, whatever their latencies.
+//! * The bus carries one message at a time, holding it for its occupancy: the
+//!   arbitration cycles, then the transfer cycles of a message with or without
+//!   a block. Its arbiter chooses which sender's message goes next.
+//! * A point-to-point network instead takes one message at a time from each
+//!   sender, and delivers it to each endpoint once it has crossed the links in
+//!   between. See the `network` module.
+//! * Main memory takes one message at a time, for as long as its last-level
+//!   cache takes to look the block up. Its DRAM accesses then overlap, as the
+//!   `dram` module says, and its responses go out in the order it took their
+//!   requests.
+//!
+//! A read or write is done once its cache holds the block as it needs to. Its
+//! latency, from being made until then, is recorded in its cache. Meanwhile,
+//! the processor carries on with its next access, unless its cache has as
+//! many waiting on the bus as it may, or one to the same block.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::io;
+
+use bus::{Bus, BusMessage, Endpoint, Tag, Topology};
+use coherence::CoherenceProtocol;
+use main_memory::{Address, Block, MainMemory};
+use memory_cache::{MemoryCache, MemoryCacheId};
+
+/// A point in simulated time, counted in cycles from the start of the run.
+pub type Cycle = u64;
+
+/// One access a processor makes to its cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Read the byte at the address.
+    Read(Address),
+    /// Write the value to the byte at the address.
+    Write(Address, u8),
+}
+
+impl Access {
+    /// Get the address accessed.
+    pub fn address(&self) -> Address {
+        match *self {
+            Access::Read(address) |
+            Access::Write(address, _) => address,
+        }
+    }
+}
+
+/// What the processor in front of each cache does.
+pub trait Workload<P: CoherenceProtocol> {
+    /// The next access the processor of cache `id` makes, or `None` if it has
+    /// made all of them. Only called once the previous access has been made,
+    /// and until it returns `None`.
+    fn next_access(&mut self, id: MemoryCacheId) -> Option<Access>;
+
+    /// The processor of cache `id` has read `value` from `address`, now that
+    /// the read is done. Workloads that do not check what they read need not
+    /// listen.
+    fn on_read(&mut self, _id: MemoryCacheId, _address: Address, _value: u8) { }
+
+    /// The processor of `cache` has made all of its accesses, and they are all
+    /// done, at cycle `now`. Whatever the cache sends is put on the bus, and
+    /// it keeps snooping until every other processor is done too.
+    fn finish(&mut self, cache: &mut MemoryCache<P>, now: Cycle);
+}
+
+/// Something that happens to one of the actors.
+enum Event {
+    /// An endpoint sends a message over the bus, and waits for its turn.
+    Send(Endpoint, BusMessage),
+    /// The bus is done carrying a message from an endpoint, and forwards it.
+    BusDone(Endpoint, BusMessage),
+    /// The bus delivers a message to an endpoint.
+    Deliver(Endpoint, BusMessage),
+    /// Main memory is ready to take its next message.
+    MemoryDone,
+    /// The processor of the given cache makes its next access.
+    Issue(MemoryCacheId),
+}
+
+/// An event, and when it happens.
+struct Scheduled {
+    cycle: Cycle,
+    sequence: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Scheduled) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled { }
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Scheduled) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    // Reversed, so that the heap pops the earliest event first.
+    fn cmp(&self, other: &Scheduled) -> Ordering {
+        (other.cycle, other.sequence).cmp(&(self.cycle, self.sequence))
+    }
+}
+
+/// Runs a machine of caches following the protocol `P`, connected to main
+/// memory by a bus of topology `T`.
+pub struct Engine<P: CoherenceProtocol, T: Topology> {
+    now: Cycle,
+    next_sequence: u64,
+    events: BinaryHeap<Scheduled>,
+    bus: Bus<T>,
+    memory: MainMemory,
+    // Messages delivered to main memory that it has not started serving.
+    memory_queue: VecDeque<BusMessage>,
+    memory_busy: bool,
+    caches: Vec<MemoryCache<P>>,
+    // The earliest each cache may send its next message, so that its messages
+    // go out in order.
+    ports: Vec<Cycle>,
+    // Per processor: each of its accesses waiting on the bus and when it made
+    // it, by their transactions, the access it waits to make, whether it waits
+    // for an access to be done, and whether it has made all of them.
+    issued: Vec<BTreeMap<Tag, (Cycle, Access)>>,
+    held: Vec<Option<Access>>,
+    stalled: Vec<bool>,
+    exhausted: Vec<bool>,
+    trace: Option<Box<dyn io::Write>>,
+}
+
+impl<P: CoherenceProtocol, T: Topology> Engine<P, T> {
+    /// Create an engine for the machine made of `bus`, `memory` and `caches`,
+    /// which must be in order of id. If there is a `trace`, every message sent
+    /// over the bus is written to it, along with the cycle it won the bus at.
+    pub fn new(bus: Bus<T>,
+               memory: MainMemory,
+               caches: Vec<MemoryCache<P>>,
+               trace: Option<Box<dyn io::Write>>)
+               -> Engine<P, T> {
+        assert!(caches.iter().enumerate().all(|(index, cache)| cache.id as usize == index),
+                "Caches should be in order of id");
+
+        Engine {
+            now: 0,
+            next_sequence: 0,
+            events: BinaryHeap::new(),
+            bus,
+            memory,
+            memory_queue: VecDeque::new(),
+            memory_busy: false,
+            ports: vec![0; caches.len()],
+            issued: vec![BTreeMap::new(); caches.len()],
+            held: vec![None; caches.len()],
+            stalled: vec![false; caches.len()],
+            exhausted: vec![false; caches.len()],
+            caches,
+            trace,
+        }
+    }
+
+    /// Get the current cycle.
+    pub fn now(&self) -> Cycle {
+        self.now
+    }
+
+    /// Get the caches, in order of id.
+    pub fn caches(&self) -> &[MemoryCache<P>] {
+        &self.caches
+    }
+
+    /// Get the caches, in order of id, to change them between runs. Anything
+    /// they send is put on the bus at the start of the next run.
+    pub fn caches_mut(&mut self) -> &mut [MemoryCache<P>] {
+        &mut self.caches
+    }
+
+    /// Run `workload` until every processor has made all of its accesses, and
+    /// every message they caused has been delivered and served.
+    pub fn run<W: Workload<P>>(&mut self, workload: &mut W) {
+        let mut running = self.caches.len();
+        for index in 0..self.caches.len() {
+            self.exhausted[index] = false;
+            self.post(index, 0);
+            self.schedule(0, Event::Issue(index as MemoryCacheId));
+        }
+
+        while let Some(Scheduled { cycle, sequence: _, event }) = self.events.pop() {
+            self.now = cycle;
+            match event {
+                Event::Send(from, msg) => {
+                    self.bus.request(from, msg, self.now);
+                    self.start_transfers();
+                },
+
+                Event::BusDone(from, msg) => {
+                    let msg = self.raise_shared_line(msg);
+                    for (delay, endpoint, msg) in self.bus.forward(from, msg, self.now) {
+                        self.schedule(delay, Event::Deliver(endpoint, msg));
+                    }
+                    self.start_transfers();
+                },
+
+                Event::Deliver(Endpoint::MainMemory, msg) => {
+                    self.memory_queue.push_back(msg);
+                    if !self.memory_busy {
+                        self.serve_memory();
+                    }
+                },
+
+                Event::Deliver(Endpoint::Cache(id), msg) => {
+                    let index = id as usize;
+                    let done = self.caches[index].receive(&msg);
+                    let snoop_latency = self.caches[index].system().snoop_latency as Cycle;
+                    self.post(index, snoop_latency);
+                    if let Some(done) = done {
+                        let (issued, access) = self.issued[index].remove(&done.tag).unwrap();
+                        self.caches[index].complete((self.now - issued) as usize);
+                        if let Some(value) = done.value {
+                            workload.on_read(id, access.address(), value);
+                        }
+                        if self.stalled[index] {
+                            self.stalled[index] = false;
+                            self.schedule(0, Event::Issue(id));
+                        }
+                    }
+                },
+
+                Event::MemoryDone => {
+                    self.memory_busy = false;
+                    self.serve_memory();
+                },
+
+                Event::Issue(id) => {
+                    if self.issue(workload, id as usize) {
+                        running -= 1;
+                    }
+                },
+            }
+        }
+
+        assert!(running == 0, "Deadlock at cycle {}: {} processors wait on the bus, but nothing is left to happen",
+                self.now, running);
+        if let Some(ref mut trace) = self.trace {
+            trace.flush().expect("Error writing trace");
+        }
+    }
+
+    /// Have the processor of the cache at `index` make its next access, if the
+    /// cache can take it, and otherwise wait for one of its accesses to be
+    /// done. Returns whether it has now made all of them, and they are all
+    /// done.
+    fn issue<W: Workload<P>>(&mut self, workload: &mut W, index: usize) -> bool {
+        let id = index as MemoryCacheId;
+        let access = match self.held[index].take() {
+            Some(access) => Some(access),
+            None if self.exhausted[index] => None,
+            None => workload.next_access(id),
+        };
+
+        let access = match access {
+            Some(access) => access,
+            None => {
+                self.exhausted[index] = true;
+                if self.caches[index].waiting() > 0 {
+                    self.stalled[index] = true;
+                    return false;
+                }
+                workload.finish(&mut self.caches[index], self.now);
+                self.post(index, 0);
+                return true;
+            },
+        };
+
+        if !self.caches[index].can_issue(access.address()) {
+            self.held[index] = Some(access);
+            self.stalled[index] = true;
+            return false;
+        }
+
+        let lookup = match access {
+            Access::Read(address) => self.caches[index].read(address),
+            Access::Write(address, value) => self.caches[index].write(address, value),
+        };
+        // Requests go out once the lookup has missed.
+        let latency = lookup.latency as Cycle;
+        self.post(index, latency);
+        match lookup.waiting {
+            Some(tag) => {
+                self.issued[index].insert(tag, (self.now, access));
+            },
+            None => {
+                self.caches[index].complete(lookup.latency);
+                if let Some(value) = lookup.value {
+                    workload.on_read(id, access.address(), value);
+                }
+            },
+        }
+        self.schedule(latency, Event::Issue(id));
+        false
+    }
+
+    /// Schedule `event` to happen `delay` cycles from now.
+    fn schedule(&mut self, delay: Cycle, event: Event) {
+        self.events.push(Scheduled {
+            cycle: self.now + delay,
+            sequence: self.next_sequence,
+            event,
+        });
+        self.next_sequence += 1;
+    }
+
+    /// Send what the cache at `index` has sent over the bus, `delay` cycles
+    /// from now, but no earlier than what it sent before.
+    fn post(&mut self, index: usize, delay: Cycle) {
+        let messages = self.caches[index].outbox();
+        if messages.is_empty() {
+            return;
+        }
+
+        let at = self.ports[index].max(self.now + delay);
+        self.ports[index] = at;
+        for msg in messages {
+            self.schedule(at - self.now, Event::Send(Endpoint::Cache(index as MemoryCacheId), msg));
+        }
+    }
+
+    /// Raise the shared line for an update going over the bus if any cache
+    /// but its writer holds the block.
+    fn raise_shared_line(&self, msg: BusMessage) -> BusMessage {
+        match msg {
+            BusMessage::Update { who, address, value, shared: _ } => {
+                let block = Block::for_addr(address, self.caches[who as usize].system().block_size);
+                let shared = self.caches.iter().any(|cache| cache.id != who && cache.raises_shared_line(block));
+                BusMessage::Update { who, address, value, shared }
+            },
+            msg => msg,
+        }
+    }
+
+    /// Start carrying every message waiting for the bus that it lets go.
+    fn start_transfers(&mut self) {
+        while let Some((from, msg, cycles)) = self.bus.grant(self.now) {
+            if let Some(ref mut trace) = self.trace {
+                writeln!(trace, "{}: {:?}", self.now, msg).expect("Error writing trace");
+            }
+            self.schedule(cycles as Cycle, Event::BusDone(from, msg));
+        }
+    }
+
+    /// Start serving the next message delivered to main memory, if there is
+    /// one. Messages main memory sends of its own accord go out right away,
+    /// and its response once it is ready.
+    fn serve_memory(&mut self) {
+        let msg = match self.memory_queue.pop_front() {
+            Some(msg) => msg,
+            None => return,
+        };
+
+        let (busy, latency, response) = self.memory.serve(msg, self.now);
+        for msg in self.memory.outbox() {
+            self.schedule(0, Event::Send(Endpoint::MainMemory, msg));
+        }
+        if let Some(response) = response {
+            self.schedule(latency as Cycle, Event::Send(Endpoint::MainMemory, response));
+        }
+        self.memory_busy = true;
+        self.schedule(busy as Cycle, Event::MemoryDone);
+    }
+}
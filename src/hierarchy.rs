@@ -0,0 +1,426 @@
+//! A memory cache's private hierarchy of cache levels, from the level closest
+//! to the processor (L1) down to the level closest to the bus.
+//!
+//! The hierarchy as a whole is what takes part in coherence: each block it
+//! holds has one line, with one coherence state and one copy of the data, no
+//! matter how many of the levels the block is resident in. The levels only
+//! decide where the block is resident, and so how long it takes to reach and
+//! when it has to leave the hierarchy. A block leaves the hierarchy once it is
+//! no longer resident in any level.
+//!
+//! Which levels a block is resident in is governed by the hierarchy's
+//! `Inclusion` policy.
+
+use std::collections::BTreeMap;
+
+use main_memory;
+use replacement::{ReplacementKind, ReplacementStats};
+use set_associative::{CacheGeometry, SetAssociative};
+
+/// How the levels of a cache hierarchy share the blocks they hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Inclusion {
+    /// Every block resident in a level is also resident in every level below
+    /// it. When a lower level evicts a block, it is back-invalidated from the
+    /// levels above. This wastes capacity on duplicates, but the lowest level
+    /// alone can answer whether the hierarchy holds a block.
+    Inclusive,
+
+    /// Every block is resident in exactly one level. Blocks are filled into
+    /// the top level only, move up when they are hit in a lower level, and
+    /// are moved down into the next level when they are evicted (a victim
+    /// fill). No capacity is spent on duplicates.
+    Exclusive,
+
+    /// Non-inclusive, non-exclusive (NINE): blocks are filled into every
+    /// level, and each level evicts them independently.
+    NonInclusive,
+}
+
+impl Inclusion {
+    /// Parse an inclusion policy from its name: `inclusive`, `exclusive`, or
+    /// `nine`.
+    pub fn parse(name: &str) -> Result<Inclusion, String> {
+        match &name.to_lowercase()[..] {
+            "inclusive" => Ok(Inclusion::Inclusive),
+            "exclusive" => Ok(Inclusion::Exclusive),
+            "nine" => Ok(Inclusion::NonInclusive),
+            _ => Err(format!("Unknown inclusion policy: {}\nExpected one of: inclusive, exclusive, nine", name)),
+        }
+    }
+
+    /// Get this policy's name.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Inclusion::Inclusive => "inclusive",
+            Inclusion::Exclusive => "exclusive",
+            Inclusion::NonInclusive => "NINE",
+        }
+    }
+}
+
+/// How one level of a cache hierarchy is built.
+#[derive(Clone, Copy, Debug)]
+pub struct LevelConfig {
+    /// The number of sets and ways the level is organized into.
+    pub geometry: CacheGeometry,
+    /// The policy choosing which block of a full set to evict.
+    pub replacement: ReplacementKind,
+    /// The number of cycles it takes to look a block up in this level.
+    pub latency: usize,
+}
+
+/// Counts of how a level of a cache hierarchy was used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LevelStats {
+    /// The number of accesses that found their block in this level.
+    pub hits: usize,
+    /// The number of accesses that looked for their block in this level, and
+    /// did not find it.
+    pub misses: usize,
+    /// How often blocks were filled into and evicted from this level.
+    pub replacement: ReplacementStats,
+}
+
+/// Counts of how a cache hierarchy's inclusion policy moved blocks between its
+/// levels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InclusionStats {
+    /// The number of times a block was removed from an upper level because a
+    /// lower level evicted it.
+    pub back_invalidations: usize,
+    /// The number of blocks evicted from one level into the next.
+    pub victim_fills: usize,
+    /// The number of blocks currently resident in more than one level.
+    pub duplicated: usize,
+}
+
+/// The outcome of the processor accessing a block.
+pub struct Access<V> {
+    /// The level the block was found in, if any.
+    pub level: Option<usize>,
+    /// The number of cycles spent looking for the block.
+    pub latency: usize,
+    /// Lines that left the hierarchy to make room for the block in the levels
+    /// above the one it was found in.
+    pub evicted: Vec<(main_memory::Block, V)>,
+}
+
+/// One level of the hierarchy, tracking which blocks are resident in it.
+struct Level {
+    config: LevelConfig,
+    resident: SetAssociative<()>,
+    hits: usize,
+    misses: usize,
+}
+
+/// A hierarchy of cache levels holding lines of type `V`.
+pub struct Hierarchy<V> {
+    levels: Vec<Level>,
+    inclusion: Inclusion,
+    lines: BTreeMap<main_memory::Block, V>,
+    back_invalidations: usize,
+    victim_fills: usize,
+}
+
+impl<V> Hierarchy<V> {
+    /// Create an empty hierarchy with the given levels, L1 first, sharing
+    /// blocks as the `inclusion` policy says. Each level's replacement policy
+    /// is seeded with `seed`, followed by the level's number, counting from
+    /// one.
+    pub fn new(configs: &[LevelConfig], inclusion: Inclusion, seed: &[usize]) -> Hierarchy<V> {
+        assert!(!configs.is_empty(), "A cache hierarchy needs at least one level");
+        Hierarchy {
+            inclusion,
+            levels: configs.iter().enumerate().map(|(level, &config)| Level {
+                config,
+                resident: SetAssociative::new(config.geometry, config.replacement, &[seed, &[level + 1]].concat()),
+                hits: 0,
+                misses: 0,
+            }).collect(),
+            lines: BTreeMap::new(),
+            back_invalidations: 0,
+            victim_fills: 0,
+        }
+    }
+
+    /// Get the policy governing which levels blocks are resident in.
+    pub fn inclusion(&self) -> Inclusion {
+        self.inclusion
+    }
+
+    /// Get the inclusion policy's statistics since the last reset.
+    pub fn inclusion_stats(&self) -> InclusionStats {
+        InclusionStats {
+            back_invalidations: self.back_invalidations,
+            victim_fills: self.victim_fills,
+            duplicated: self.lines.keys()
+                .filter(|block| self.levels.iter().filter(|level| level.resident.peek(block).is_some()).count() > 1)
+                .count(),
+        }
+    }
+
+    /// Get each level's configuration, L1 first.
+    pub fn configs(&self) -> Vec<LevelConfig> {
+        self.levels.iter().map(|level| level.config).collect()
+    }
+
+    /// Get each level's statistics since the last reset, L1 first.
+    pub fn stats(&self) -> Vec<LevelStats> {
+        self.levels.iter().map(|level| LevelStats {
+            hits: level.hits,
+            misses: level.misses,
+            replacement: level.resident.stats(),
+        }).collect()
+    }
+
+    /// Reset every level's statistics.
+    pub fn reset_stats(&mut self) {
+        for level in &mut self.levels {
+            level.hits = 0;
+            level.misses = 0;
+            level.resident.reset_stats();
+        }
+        self.back_invalidations = 0;
+        self.victim_fills = 0;
+    }
+
+    /// Remove every line, and forget the replacement policies' history.
+    pub fn clear(&mut self) {
+        for level in &mut self.levels {
+            level.resident.clear();
+        }
+        self.lines.clear();
+    }
+
+    /// Every block held.
+    pub fn blocks(&self) -> Vec<main_memory::Block> {
+        self.lines.keys().cloned().collect()
+    }
+
+    /// Get the line held for `block`, without the access being counted or the
+    /// replacement policies noticing. Used for snooping and bookkeeping.
+    pub fn peek(&self, block: &main_memory::Block) -> Option<&V> {
+        self.lines.get(block)
+    }
+
+    /// Get the line held for `block` mutably, without the access being counted
+    /// or the replacement policies noticing. Used for snooping and
+    /// bookkeeping.
+    pub fn peek_mut(&mut self, block: &main_memory::Block) -> Option<&mut V> {
+        self.lines.get_mut(block)
+    }
+
+    /// Access `block` on behalf of the processor, looking for it one level at
+    /// a time starting from L1. If it is found below L1, it is moved or copied
+    /// up into L1, as the inclusion policy says.
+    pub fn access(&mut self, block: &main_memory::Block) -> Access<V> {
+        let mut latency = 0;
+        let mut found = None;
+
+        for (index, level) in self.levels.iter_mut().enumerate() {
+            latency += level.config.latency;
+            if level.resident.get(block).is_some() {
+                level.hits += 1;
+                found = Some(index);
+                break;
+            }
+            level.misses += 1;
+        }
+
+        let victims = match (found, self.inclusion) {
+            (Some(0), _) | (None, _) => vec![],
+            (Some(index), Inclusion::Exclusive) => {
+                self.levels[index].resident.remove(block);
+                self.place(*block, 0)
+            },
+            (Some(index), _) => self.fill_levels(*block, index),
+        };
+
+        Access { level: found, latency, evicted: self.leave(victims) }
+    }
+
+    /// Hold `line` for `block`, resident in L1 and, unless the hierarchy is
+    /// exclusive, every other level. Returns the lines that left the hierarchy
+    /// to make room for it.
+    pub fn insert(&mut self, block: main_memory::Block, line: V) -> Vec<(main_memory::Block, V)> {
+        let already_held = self.lines.insert(block, line).is_some();
+
+        let victims = match self.inclusion {
+            Inclusion::Exclusive if already_held => vec![],
+            Inclusion::Exclusive => self.place(block, 0),
+            _ => {
+                let levels = self.levels.len();
+                self.fill_levels(block, levels)
+            },
+        };
+
+        self.leave(victims)
+    }
+
+    /// Make `block` resident in every level above `below`, top first. When the
+    /// hierarchy is inclusive, a block evicted from a lower level is also
+    /// removed from the levels above it.
+    fn fill_levels(&mut self, block: main_memory::Block, below: usize) -> Vec<main_memory::Block> {
+        let mut victims = vec![];
+        for index in 0..below {
+            if let Some((victim, ())) = self.levels[index].resident.insert(block, ()) {
+                if self.inclusion == Inclusion::Inclusive {
+                    for upper in &mut self.levels[..index] {
+                        if upper.resident.remove(&victim).is_some() {
+                            self.back_invalidations += 1;
+                        }
+                    }
+                }
+                victims.push(victim);
+            }
+        }
+        victims
+    }
+
+    /// Make `block` resident in level `index`, moving whatever it evicts down
+    /// into the next level, and so on. Returns the block evicted from the
+    /// bottom level, if any.
+    fn place(&mut self, block: main_memory::Block, index: usize) -> Vec<main_memory::Block> {
+        match self.levels[index].resident.insert(block, ()) {
+            Some((victim, ())) if index + 1 < self.levels.len() => {
+                self.victim_fills += 1;
+                self.place(victim, index + 1)
+            },
+            Some((victim, ())) => vec![victim],
+            None => vec![],
+        }
+    }
+
+    /// Remove the lines for those `victims` that are no longer resident in any
+    /// level from the hierarchy, and return them.
+    fn leave(&mut self, victims: Vec<main_memory::Block>) -> Vec<(main_memory::Block, V)> {
+        let mut evicted = vec![];
+        for victim in victims {
+            if self.is_resident(&victim) {
+                continue;
+            }
+            if let Some(line) = self.lines.remove(&victim) {
+                evicted.push((victim, line));
+            }
+        }
+        evicted
+    }
+
+    /// Is `block` resident in any level?
+    fn is_resident(&self, block: &main_memory::Block) -> bool {
+        self.levels.iter().any(|level| level.resident.peek(block).is_some())
+    }
+
+    /// Remove the line held for `block` from every level, if any.
+    pub fn remove(&mut self, block: &main_memory::Block) -> Option<V> {
+        for level in &mut self.levels {
+            level.resident.remove(block);
+        }
+        self.lines.remove(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use main_memory::Block;
+
+    /// A hierarchy of two fully associative LRU levels of two blocks each,
+    /// sharing blocks as the `inclusion` policy says, holding blocks 0 and 1.
+    /// L1 alone has seen block 0 hit since, so each level evicts a different
+    /// block next.
+    fn filled(inclusion: Inclusion) -> Hierarchy<usize> {
+        let level = |latency| LevelConfig {
+            geometry: CacheGeometry::fully_associative(2),
+            replacement: ReplacementKind::Lru,
+            latency,
+        };
+        let mut hierarchy = Hierarchy::new(&[level(1), level(10)], inclusion, &[]);
+        for block in 0..2 {
+            assert!(hierarchy.insert(Block(block), block).is_empty());
+        }
+        assert_eq!(hierarchy.access(&Block(0)).level, Some(0));
+        hierarchy
+    }
+
+    /// The levels `block` is resident in, L1 first.
+    fn levels_of(hierarchy: &Hierarchy<usize>, block: usize) -> Vec<usize> {
+        (0..hierarchy.levels.len())
+            .filter(|&index| hierarchy.levels[index].resident.peek(&Block(block)).is_some())
+            .collect()
+    }
+
+    /// The blocks in `evicted`.
+    fn blocks(evicted: &[(Block, usize)]) -> Vec<usize> {
+        evicted.iter().map(|&(block, _)| block.0).collect()
+    }
+
+    #[test]
+    fn inclusive_evictions_back_invalidate_upper_levels() {
+        let mut hierarchy = filled(Inclusion::Inclusive);
+
+        // L1 evicts block 1, which L2 keeps, and L2 evicts block 0, which
+        // must then leave L1 too, and so the hierarchy.
+        assert_eq!(blocks(&hierarchy.insert(Block(2), 2)), vec![0]);
+        assert!(levels_of(&hierarchy, 0).is_empty());
+        assert_eq!(levels_of(&hierarchy, 1), vec![1]);
+        assert_eq!(levels_of(&hierarchy, 2), vec![0, 1]);
+        assert_eq!(hierarchy.inclusion_stats(), InclusionStats {
+            back_invalidations: 1,
+            victim_fills: 0,
+            duplicated: 1,
+        });
+    }
+
+    #[test]
+    fn exclusive_victims_move_down_and_hits_move_up() {
+        let mut hierarchy = filled(Inclusion::Exclusive);
+        assert_eq!(levels_of(&hierarchy, 0), vec![0]);
+        assert_eq!(levels_of(&hierarchy, 1), vec![0]);
+
+        // L1's victim, block 1, moves down into L2 rather than leaving.
+        assert!(hierarchy.insert(Block(2), 2).is_empty());
+        assert_eq!(levels_of(&hierarchy, 1), vec![1]);
+
+        // A hit in L2 moves block 1 back up, and L1's victim, block 0, down.
+        let access = hierarchy.access(&Block(1));
+        assert_eq!((access.level, access.latency), (Some(1), 11));
+        assert!(access.evicted.is_empty());
+        assert_eq!(levels_of(&hierarchy, 0), vec![1]);
+        assert_eq!(levels_of(&hierarchy, 1), vec![0]);
+        assert_eq!(levels_of(&hierarchy, 2), vec![0]);
+        assert_eq!(hierarchy.inclusion_stats(), InclusionStats {
+            back_invalidations: 0,
+            victim_fills: 2,
+            duplicated: 0,
+        });
+
+        // Once L2 is full too, its own victim leaves the hierarchy.
+        assert!(hierarchy.insert(Block(3), 3).is_empty());
+        assert_eq!(blocks(&hierarchy.insert(Block(4), 4)), vec![0]);
+    }
+
+    #[test]
+    fn non_inclusive_levels_evict_independently() {
+        let mut hierarchy = filled(Inclusion::NonInclusive);
+
+        // L1 evicts block 1 and L2 evicts block 0, but each is still resident
+        // in the other level, so neither leaves the hierarchy.
+        assert!(hierarchy.insert(Block(2), 2).is_empty());
+        assert_eq!(levels_of(&hierarchy, 0), vec![0]);
+        assert_eq!(levels_of(&hierarchy, 1), vec![1]);
+        assert_eq!(levels_of(&hierarchy, 2), vec![0, 1]);
+        assert_eq!(hierarchy.inclusion_stats(), InclusionStats {
+            back_invalidations: 0,
+            victim_fills: 0,
+            duplicated: 1,
+        });
+
+        // A hit in L2 copies block 1 back up into L1, evicting block 0 from
+        // its last level.
+        assert_eq!(blocks(&hierarchy.access(&Block(1)).evicted), vec![0]);
+        assert_eq!(levels_of(&hierarchy, 1), vec![0, 1]);
+    }
+}
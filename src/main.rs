@@ -1,39 +1,401 @@
 #![deny(missing_docs)]
 
-//! Emulator for memory caches, main memory, a bus connecting them all, and the
-//! MESI cache coherence protocol.
+//! Emulator for memory caches, main memory, a bus connecting them all, and
+//! cache coherence protocols such as MESI.
 
-use std::sync::mpsc;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+use std::rc::Rc;
 
+pub mod arbiter;
 pub mod benchmark;
 pub mod bus;
+pub mod coherence;
+pub mod config;
+pub mod directory;
+pub mod dram;
+pub mod engine;
+pub mod hierarchy;
 pub mod main_memory;
 pub mod memory_cache;
+pub mod network;
+pub mod prefetch;
+pub mod replacement;
+pub mod set_associative;
+pub mod snoop_filter;
+pub mod system;
 
-/// Spawn main memory and caches, tie them together with the bus, and then run
-/// the benchmark.
-pub fn main() {
-    let (to_bus, from_bus) = mpsc::channel();
+/// Describe one level of a cache hierarchy.
+fn describe_level(name: &str, level: &hierarchy::LevelConfig) -> String {
+    format!("{}: {} sets x {} ways, {} replacement, {} cycles\n",
+            name, level.geometry.sets, level.geometry.ways, level.replacement.name(), level.latency)
+}
 
-    let mut outgoing = Vec::with_capacity(memory_cache::NUMBER_OF_CACHES + 1);
-    outgoing.push(main_memory::MainMemory::spawn(to_bus.clone()));
+/// Describe how each cache of a class is built.
+fn describe_class(config: &memory_cache::CacheConfig) -> String {
+    let mut description = String::new();
+    for (index, level) in config.levels.iter().enumerate() {
+        description.push_str(&describe_level(&format!("L{}", index + 1), level));
+    }
+    description.push_str(&format!("Clean evictions: {}\nVictim buffer: {} entries\nPrefetcher: {}\nOutstanding misses: {}\n",
+                                  if config.announce_clean_evictions { "announced" } else { "silent" },
+                                  config.victim_entries, config.prefetcher.name(), config.outstanding));
+    description
+}
 
-    let mut handles = Vec::with_capacity(memory_cache::NUMBER_OF_CACHES);
+/// Build the main memory, caches following the given coherence `protocol`,
+/// and a bus of the given `topology` tying them together, all as described by
+/// `machine`, and then run the benchmark. Returns each phase's bus traffic.
+fn run<P, T>(machine: &config::MachineConfig, protocol: P, topology: T) -> Vec<benchmark::PhaseTraffic>
+    where P: coherence::CoherenceProtocol + Clone,
+          T: bus::Topology
+{
+    let system = &machine.system;
 
-    for id in 0..memory_cache::NUMBER_OF_CACHES {
-        let id = id as memory_cache::MemoryCacheId;
+    let mut description = format!("{}Protocol: {}\nTopology: {}\nNetwork: {}\nArbiter: {}\nInclusion: {}\n",
+                                  system.describe(), protocol.name(), topology.name(), machine.network.name(),
+                                  machine.arbiter.name(), machine.inclusion().name());
+    if let [ref class] = machine.classes[..] {
+        description.push_str(&describe_class(&class.config));
+    } else {
+        let mut first = 0;
+        for class in &machine.classes {
+            description.push_str(&format!("Class {}: caches {} to {}\n", class.name, first, first + class.count - 1));
+            for line in describe_class(&class.config).lines() {
+                description.push_str(&format!("\t{}\n", line));
+            }
+            first += class.count;
+        }
+    }
+    if let Some(ref llc) = machine.llc {
+        description.push_str(&describe_level("Shared LLC", llc));
+    }
+    description.push_str(&format!("Page policy: {}\nAddress mapping: {}\n",
+                                  machine.page_policy.name(), machine.address_mapping.name()));
+    let phases: Vec<_> = machine.phases.iter().map(|phase| phase.name()).collect();
+    println!("{}Workload: {}\nSeed: {}\n", description, phases.join(", "), machine.seed);
 
-        let (send, handle) = memory_cache::MemoryCache::spawn(id, to_bus.clone(), move |cache| {
-            benchmark::benchmark(cache);
+    let trace = machine.trace.as_ref().map(|path| {
+        let file = fs::File::create(path).unwrap_or_else(|e| {
+            eprintln!("Could not create trace file {}: {}", path, e);
+            process::exit(1);
         });
+        Box::new(io::BufWriter::new(file)) as Box<dyn io::Write>
+    });
+
+    let network = network::Network::new(machine.network, system);
+    let bus_stats = Rc::new(bus::BusStats::new(system.number_of_caches, network.links()));
+    let memory_stats = Rc::new(main_memory::MemoryStats::new(system.blocks()));
+    let class_stats = benchmark::ClassStats::new(&machine.classes);
+
+    let dram = dram::Dram::new(machine.page_policy, machine.address_mapping, system);
+    let memory = main_memory::MainMemory::new(system, dram, machine.llc, machine.inclusion(), machine.seed,
+                                              memory_stats.clone());
+    let caches = machine.classes.iter()
+        .flat_map(|class| (0..class.count).map(move |_| &class.config))
+        .enumerate()
+        .map(|(id, config)| {
+            let id = id as memory_cache::MemoryCacheId;
+            memory_cache::MemoryCache::new(id, system, protocol.clone(), config.clone(), machine.seed)
+        })
+        .collect();
+
+    let arbiter = arbiter::Arbiter::new(machine.arbiter, machine.seed);
+    let bus = bus::Bus::new(system, topology, arbiter, network, bus_stats.clone());
+    let mut engine = engine::Engine::new(bus, memory, caches, trace);
+    benchmark::benchmark(&mut engine, &machine.phases, machine.seed, &class_stats, &bus_stats, &memory_stats)
+}
+
+/// Run the benchmark on `machine` with the given coherence `protocol`.
+/// Returns each phase's bus traffic.
+fn run_with_protocol<P>(machine: &config::MachineConfig, protocol: P) -> Vec<benchmark::PhaseTraffic>
+    where P: coherence::CoherenceProtocol + Clone
+{
+    let system = &machine.system;
+    match machine.topology {
+        bus::TopologyKind::Bus(filter) => {
+            let broadcast = bus::Broadcast::new(system, filter);
+            if let Some(filter) = broadcast.filter() {
+                println!("Snoop filter: {}\n", filter.kind().name());
+            }
+            run(machine, protocol, broadcast)
+        },
+        bus::TopologyKind::Directory(encoding) => {
+            let directory = directory::Directory::new(system, encoding);
+            println!("Directory: {:?}:\n\t{} bits per entry\n\t{} bytes total\n",
+                     encoding, encoding.bits_per_entry(system.number_of_caches), directory.storage_bits() / 8);
+            run(machine, protocol, directory)
+        },
+    }
+}
+
+/// Run the benchmark on `machine`. Returns each phase's bus traffic.
+fn run_machine(machine: &config::MachineConfig) -> Vec<benchmark::PhaseTraffic> {
+    match machine.protocol {
+        coherence::ProtocolKind::Dragon => run_with_protocol(machine, coherence::dragon::Dragon),
+        coherence::ProtocolKind::Mesi => run_with_protocol(machine, coherence::mesi::Mesi),
+        coherence::ProtocolKind::Mesif => run_with_protocol(machine, coherence::mesif::Mesif),
+        coherence::ProtocolKind::Moesi => run_with_protocol(machine, coherence::moesi::Moesi),
+        coherence::ProtocolKind::Msi => run_with_protocol(machine, coherence::msi::Msi),
+    }
+}
+
+/// Build the cache hierarchy named by `name`: `l1` for each cache to be a single
+/// level, `l2` to put a small private L1 in front of it, or `l3` to also put a
+/// last-level cache shared by every cache in front of main memory. The level
+/// closest to the bus is `last`, and every private level uses its replacement
+/// policy. The other levels are sized to suit the given `system`.
+fn parse_hierarchy(name: &str, last: hierarchy::LevelConfig, system: &system::SystemConfig)
+                   -> Result<(Vec<hierarchy::LevelConfig>, Option<hierarchy::LevelConfig>), String> {
+    // The L1 in front of the last level is a quarter of its size, with two
+    // ways per set, so the last level must be big enough to split that way.
+    let l1 = || -> Result<hierarchy::LevelConfig, String> {
+        let geometry = set_associative::CacheGeometry::parse("2", system.cache_size / 4).map_err(|_| {
+            format!("A cache of {} blocks is too small to have an L1 in front of it\nExpected a power of two of at least 8 blocks",
+                    system.cache_size)
+        })?;
+        Ok(hierarchy::LevelConfig {
+            geometry,
+            replacement: last.replacement,
+            latency: memory_cache::DEFAULT_LATENCY,
+        })
+    };
+    let l2 = hierarchy::LevelConfig {
+        latency: 12,
+        ..last
+    };
 
-        handles.push(handle);
-        outgoing.push(send);
+    match &name.to_lowercase()[..] {
+        "l1" => Ok((vec![last], None)),
+        "l2" => Ok((vec![l1()?, l2], None)),
+        "l3" => {
+            let llc_capacity = (system.cache_size * system.number_of_caches).next_power_of_two().max(16);
+            let llc = hierarchy::LevelConfig {
+                geometry: set_associative::CacheGeometry::with_ways(llc_capacity, 16),
+                replacement: replacement::ReplacementKind::Lru,
+                latency: 40,
+            };
+            Ok((vec![l1()?, l2], Some(llc)))
+        },
+        _ => Err(format!("Unknown cache hierarchy: {}\nExpected one of: l1, l2, l3", name)),
     }
+}
+
+/// The options the command line may give, each followed by its value.
+const OPTIONS: [&str; 17] = [
+    "config", "protocols", "topology", "ways", "replacement", "clean-evictions", "hierarchy", "inclusion",
+    "victim-entries", "prefetcher", "systems", "seed", "arbiter", "outstanding", "network", "page-policy",
+    "address-mapping",
+];
+
+/// Read command line `args` of the form `--<option> <value>`, each option at
+/// most once, into a map from option to value.
+fn parse_options(args: &[String]) -> Result<BTreeMap<String, String>, String> {
+    let mut options = BTreeMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let option = match arg.strip_prefix("--") {
+            Some(option) if OPTIONS.contains(&option) => option,
+            _ => {
+                let names: Vec<_> = OPTIONS.iter().map(|option| format!("--{}", option)).collect();
+                return Err(format!("Unknown option: {}\nExpected one of: {}", arg, names.join(", ")));
+            },
+        };
+        let value = args.next().ok_or_else(|| format!("Missing value after --{}", option))?;
+        if options.insert(option.to_string(), value.clone()).is_some() {
+            return Err(format!("Option given more than once: --{}", option));
+        }
+    }
+    Ok(options)
+}
 
-    bus::Bus::spawn(from_bus, outgoing);
+/// Parse the number of `what` from `value`, which must be a non-negative
+/// integer.
+fn parse_count(value: &str, what: &str) -> Result<usize, String> {
+    value.parse::<usize>()
+        .map_err(|_| format!("Invalid number of {}: {}\nExpected a non-negative integer", what, value))
+}
+
+/// Describe the machines to run the benchmark on from the command line `args`.
+///
+/// With `--config <file>`, the one machine is described by the given TOML or
+/// JSON file, and no other option may be given; see the `config` module.
+/// Otherwise, each option picks one thing about every machine, and defaults
+/// to the value in brackets:
+///
+/// - `--protocols`: the coherence protocols, separated by commas (`mesi`)
+/// - `--topology`: the bus topology (`bus`)
+/// - `--ways`: the number of ways per cache set, or `full` (`full`)
+/// - `--replacement`: the replacement policy (`lru`)
+/// - `--clean-evictions`: `announce` or `silent` (`silent`)
+/// - `--hierarchy`: the cache hierarchy; see `parse_hierarchy` (`l1`)
+/// - `--inclusion`: the inclusion policy (`nine`)
+/// - `--victim-entries`: victim buffer entries behind each cache (`0`)
+/// - `--prefetcher`: the prefetcher (`none`)
+/// - `--systems`: a sweep of system parameters; see
+///   `SystemConfig::parse_sweep` (a single default system)
+/// - `--seed`: the seed of the processors' random accesses (`0`)
+/// - `--arbiter`: the bus arbitration policy (`fifo`)
+/// - `--outstanding`: the misses each cache may have outstanding (`1`)
+/// - `--network`: the interconnect carrying the bus' messages (`bus`)
+/// - `--page-policy`: DRAM's page policy (`open`)
+/// - `--address-mapping`: DRAM's address mapping (`row-interleaved`)
+///
+/// The ways and replacement policy apply to the private level closest to the
+/// bus. An exclusive last-level cache only takes clean victims if caches
+/// announce clean evictions. Every machine runs every phase of the benchmark.
+///
+/// Each protocol is run on every system in turn, and every protocol after the
+/// first has its bus traffic compared with the first's, as with
+/// `--protocols mesi,moesi,mesif` to see how much MOESI and MESIF save over
+/// MESI.
+fn machines_from_args(args: &[String]) -> Result<Vec<config::MachineConfig>, String> {
+    let options = parse_options(args)?;
+    if let Some(path) = options.get("config") {
+        if options.len() > 1 {
+            return Err("A machine file describes the whole machine, so --config takes no other options".to_string());
+        }
+        return Ok(vec![config::MachineConfig::load(path)?]);
+    }
+    let option = |name: &str, default: &'static str| options.get(name).map_or(default, |value| &value[..]);
+
+    let protocols = option("protocols", "mesi").split(',')
+        .map(coherence::ProtocolKind::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    let topology = bus::TopologyKind::parse(option("topology", "bus"))?;
+    let ways = option("ways", "full");
+    let replacement = replacement::ReplacementKind::parse(option("replacement", "lru"))?;
+    let announce_clean_evictions = match &option("clean-evictions", "silent").to_lowercase()[..] {
+        "announce" => true,
+        "silent" => false,
+        other => return Err(format!("Unknown clean eviction mode: {}\nExpected one of: announce, silent", other)),
+    };
+    let hierarchy = option("hierarchy", "l1");
+    let inclusion = hierarchy::Inclusion::parse(option("inclusion", "nine"))?;
+    let victim_entries = parse_count(option("victim-entries", "0"), "victim buffer entries")?;
+    let prefetcher = prefetch::PrefetcherKind::parse(option("prefetcher", "none"))?;
+    let systems = system::SystemConfig::parse_sweep(option("systems", ""))?;
+    let seed = option("seed", "0");
+    let seed = seed.parse::<usize>()
+        .map_err(|_| format!("Invalid seed: {}\nExpected a non-negative integer", seed))?;
+    let arbiter = arbiter::ArbiterKind::parse(option("arbiter", "fifo"))?;
+    let outstanding = match parse_count(option("outstanding", "1"), "outstanding misses")? {
+        0 => return Err("Expected at least one outstanding miss".to_string()),
+        outstanding => outstanding,
+    };
+    let network = network::NetworkKind::parse(option("network", "bus"))?;
+    let page_policy = dram::PagePolicy::parse(option("page-policy", "open"))?;
+    let address_mapping = dram::AddressMapping::parse(option("address-mapping", "row-interleaved"))?;
+
+    let mut machines = vec![];
+    for &protocol in &protocols {
+        for &system in &systems {
+            let last = hierarchy::LevelConfig {
+                geometry: set_associative::CacheGeometry::parse(ways, system.cache_size)?,
+                replacement,
+                latency: memory_cache::DEFAULT_LATENCY,
+            };
+            let (levels, llc) = parse_hierarchy(hierarchy, last, &system)?;
+
+            machines.push(config::MachineConfig {
+                system,
+                protocol,
+                topology,
+                arbiter,
+                network,
+                classes: vec![config::CacheClass {
+                    name: "default".to_string(),
+                    count: system.number_of_caches,
+                    config: memory_cache::CacheConfig {
+                        levels,
+                        inclusion,
+                        announce_clean_evictions,
+                        victim_entries,
+                        prefetcher,
+                        outstanding,
+                    },
+                }],
+                llc,
+                page_policy,
+                address_mapping,
+                phases: benchmark::Phase::ALL.to_vec(),
+                seed,
+                trace: None,
+            });
+        }
+    }
+    Ok(machines)
+}
+
+/// Run the benchmark on each machine described by the command line arguments,
+/// one after another; see `machines_from_args`. Then report how every
+/// protocol but the first did against it.
+pub fn main() {
+    let args: Vec<_> = env::args().skip(1).collect();
+    let machines = machines_from_args(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    let traffic: Vec<_> = machines.iter().map(run_machine).collect();
+
+    // Compare every other protocol's traffic with the first's, on the same
+    // system.
+    let baseline = match machines.first() {
+        Some(machine) => machine.protocol,
+        None => return,
+    };
+    for (machine, machine_traffic) in machines.iter().zip(&traffic) {
+        if machine.protocol == baseline {
+            continue;
+        }
+        let base = machines.iter().position(|other| other.protocol == baseline && other.system == machine.system);
+        if let Some(base) = base {
+            benchmark::report_comparison(machine.protocol.name(), machine_traffic, baseline.name(), &traffic[base]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The machines described by the command line `args`.
+    fn machines(args: &[&str]) -> Result<Vec<config::MachineConfig>, String> {
+        machines_from_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn options_default_to_a_single_mesi_machine() {
+        let machines = machines(&[]).unwrap();
+        assert_eq!(machines.len(), 1);
+        assert_eq!(machines[0].protocol, coherence::ProtocolKind::Mesi);
+        assert_eq!(machines[0].network, network::NetworkKind::Bus);
+        assert_eq!(machines[0].classes[0].config.outstanding, 1);
+    }
+
+    #[test]
+    fn options_may_be_given_in_any_order() {
+        let machines = machines(&["--outstanding", "4", "--protocols", "mesi,msi", "--network", "mesh"]).unwrap();
+        let protocols: Vec<_> = machines.iter().map(|machine| machine.protocol).collect();
+        assert_eq!(protocols, vec![coherence::ProtocolKind::Mesi, coherence::ProtocolKind::Msi]);
+        assert!(machines.iter().all(|machine| machine.network == network::NetworkKind::Mesh));
+        assert!(machines.iter().all(|machine| machine.classes[0].config.outstanding == 4));
+    }
 
-    for handle in handles {
-        handle.join().expect("Could not join thread");
+    #[test]
+    fn bad_options_are_rejected() {
+        let error = |args: &[&str]| machines(args).err().unwrap();
+        assert!(error(&["--bogus", "1"]).starts_with("Unknown option: --bogus\nExpected one of: --config,"));
+        assert!(error(&["mesi"]).starts_with("Unknown option: mesi\n"));
+        assert_eq!(error(&["--seed"]), "Missing value after --seed");
+        assert_eq!(error(&["--seed", "1", "--seed", "2"]), "Option given more than once: --seed");
+        assert_eq!(error(&["--seed", "-1"]), "Invalid seed: -1\nExpected a non-negative integer");
+        assert_eq!(error(&["--outstanding", "0"]), "Expected at least one outstanding miss");
+        assert_eq!(error(&["--config", "machine.toml", "--seed", "1"]),
+                   "A machine file describes the whole machine, so --config takes no other options");
     }
 }
@@ -2,17 +2,17 @@
 
 extern crate bit_vec;
 
+use std::cell::Cell;
 use std::ops;
-use std::sync::mpsc;
-use std::thread;
+use std::rc::Rc;
 
 use bus;
-
-/// The size of a block of memory, in bytes.
-pub const BLOCK_SIZE: usize = 32;
-
-/// The size of main memory, in bytes.
-pub const MAIN_MEMORY_SIZE: usize = 65536;
+use dram::{Dram, RowBuffer};
+use engine::Cycle;
+use hierarchy::{Inclusion, LevelConfig};
+use memory_cache::MemoryCacheId;
+use set_associative::SetAssociative;
+use system::SystemConfig;
 
 /// The address of a byte in memory.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -23,101 +23,473 @@ pub struct Address(pub usize);
 pub struct Block(pub usize);
 
 impl Block {
-    /// Get the block for the given address.
-    pub fn for_addr(addr: Address) -> Block {
-        Block(addr.0 / BLOCK_SIZE)
+    /// Get the block for the given address, with blocks of `block_size`
+    /// bytes.
+    pub fn for_addr(addr: Address, block_size: usize) -> Block {
+        Block(addr.0 / block_size)
     }
 
-    /// Get the range of addresses for this block.
-    pub fn address_range(&self) -> ops::Range<usize> {
+    /// Get the range of addresses for this block, with blocks of `block_size`
+    /// bytes.
+    pub fn address_range(&self, block_size: usize) -> ops::Range<usize> {
         ops::Range {
-            start: self.0 * BLOCK_SIZE,
-            end: (self.0 + 1) * BLOCK_SIZE,
+            start: self.0 * block_size,
+            end: (self.0 + 1) * block_size,
         }
     }
 }
 
+/// Add `count` to `counter`.
+fn add(counter: &Cell<usize>, count: usize) {
+    counter.set(counter.get() + count);
+}
+
+/// Counts of main memory's DRAM and last-level cache accesses. Shared between
+/// main memory, which records them, and whoever is reporting on them.
+pub struct MemoryStats {
+    llc_hits: Cell<usize>,
+    llc_misses: Cell<usize>,
+    llc_back_invalidations: Cell<usize>,
+    llc_victim_fills: Cell<usize>,
+    llc_duplicated: Cell<usize>,
+    llc_resident: Vec<Cell<bool>>,
+    dram_reads: Cell<usize>,
+    dram_writes: Cell<usize>,
+    dram_read_cycles: Cell<usize>,
+    row_hits: Cell<usize>,
+    row_misses: Cell<usize>,
+    row_conflicts: Cell<usize>,
+}
+
+impl MemoryStats {
+    /// Create the counts for a main memory holding `blocks` blocks, all zero.
+    pub fn new(blocks: usize) -> MemoryStats {
+        MemoryStats {
+            llc_hits: Cell::new(0),
+            llc_misses: Cell::new(0),
+            llc_back_invalidations: Cell::new(0),
+            llc_victim_fills: Cell::new(0),
+            llc_duplicated: Cell::new(0),
+            llc_resident: (0..blocks).map(|_| Cell::new(false)).collect(),
+            dram_reads: Cell::new(0),
+            dram_writes: Cell::new(0),
+            dram_read_cycles: Cell::new(0),
+            row_hits: Cell::new(0),
+            row_misses: Cell::new(0),
+            row_conflicts: Cell::new(0),
+        }
+    }
+
+    /// Get the number of reads that found their block in the last-level cache.
+    pub fn llc_hits(&self) -> usize {
+        self.llc_hits.get()
+    }
+
+    /// Get the number of reads that missed the last-level cache.
+    pub fn llc_misses(&self) -> usize {
+        self.llc_misses.get()
+    }
+
+    /// Get the number of blocks the last-level cache evicted that had to be
+    /// back-invalidated from the memory caches, because it is inclusive.
+    pub fn llc_back_invalidations(&self) -> usize {
+        self.llc_back_invalidations.get()
+    }
+
+    /// Get the number of blocks evicted from the memory caches that were filled
+    /// into the last-level cache, because it is exclusive.
+    pub fn llc_victim_fills(&self) -> usize {
+        self.llc_victim_fills.get()
+    }
+
+    /// Is `block` currently held by the last-level cache?
+    pub fn is_in_llc(&self, block: Block) -> bool {
+        self.llc_resident[block.0].get()
+    }
+
+    /// Record that a memory cache holds `count` blocks that are also held by
+    /// the last-level cache.
+    pub fn record_llc_duplicated(&self, count: usize) {
+        add(&self.llc_duplicated, count);
+    }
+
+    /// Get the number of blocks held by both the last-level cache and a memory
+    /// cache, as recorded so far. A block held by several memory caches counts
+    /// once for each.
+    pub fn llc_duplicated(&self) -> usize {
+        self.llc_duplicated.get()
+    }
+
+    /// Get the number of blocks read from DRAM.
+    pub fn dram_reads(&self) -> usize {
+        self.dram_reads.get()
+    }
+
+    /// Get the number of blocks written to DRAM.
+    pub fn dram_writes(&self) -> usize {
+        self.dram_writes.get()
+    }
+
+    /// Get the average number of cycles a DRAM read took, from being started
+    /// to having read its block, including waiting for its bank and channel.
+    pub fn average_dram_read_latency(&self) -> f64 {
+        self.dram_read_cycles.get() as f64 / self.dram_reads().max(1) as f64
+    }
+
+    /// Get the number of DRAM accesses that found their row open.
+    pub fn row_hits(&self) -> usize {
+        self.row_hits.get()
+    }
+
+    /// Get the number of DRAM accesses that found no row open in their bank.
+    pub fn row_misses(&self) -> usize {
+        self.row_misses.get()
+    }
+
+    /// Get the number of DRAM accesses that found another row open in their
+    /// bank.
+    pub fn row_conflicts(&self) -> usize {
+        self.row_conflicts.get()
+    }
+
+    /// Record what a DRAM access found in its bank's row buffer.
+    fn record_row_buffer(&self, row_buffer: RowBuffer) {
+        let count = match row_buffer {
+            RowBuffer::Hit => &self.row_hits,
+            RowBuffer::Miss => &self.row_misses,
+            RowBuffer::Conflict => &self.row_conflicts,
+        };
+        add(count, 1);
+    }
+
+    /// Reset every count to zero.
+    pub fn reset(&self) {
+        self.llc_hits.set(0);
+        self.llc_misses.set(0);
+        self.llc_back_invalidations.set(0);
+        self.llc_victim_fills.set(0);
+        self.llc_duplicated.set(0);
+        self.dram_reads.set(0);
+        self.dram_writes.set(0);
+        self.dram_read_cycles.set(0);
+        self.row_hits.set(0);
+        self.row_misses.set(0);
+        self.row_conflicts.set(0);
+    }
+}
+
+/// A last-level cache shared by every memory cache, sitting between the bus
+/// and DRAM. Main memory always has the current data, so the cache only
+/// tracks which blocks are present, and whether each is newer than DRAM's
+/// copy.
+struct LastLevelCache {
+    latency: usize,
+    inclusion: Inclusion,
+    dirty: SetAssociative<bool>,
+}
+
 /// The main memory.
 pub struct MainMemory {
-    to_bus: mpsc::Sender<bus::BusMessage>,
-    from_bus: mpsc::Receiver<bus::BusMessage>,
+    block_size: usize,
+    dram: Dram,
+    to_bus: bus::Outbox,
+    llc: Option<LastLevelCache>,
+    stats: Rc<MemoryStats>,
     modified: bit_vec::BitVec,
-    data: [u8; MAIN_MEMORY_SIZE]
+    // Per block, the caches that have read it since it was last given to a
+    // cache to write.
+    readers: Vec<bit_vec::BitVec>,
+    // The cycle main memory's last response goes out at.
+    last_response: Cycle,
+    data: Vec<u8>,
 }
 
 impl MainMemory {
-    /// Create the main memory in its own thread.
-    pub fn spawn(bus: mpsc::Sender<bus::BusMessage>) -> mpsc::Sender<bus::BusMessage> {
-        let (send, recv) = mpsc::channel();
-
-        thread::spawn(move || {
-            let memory = Box::new(MainMemory {
-                to_bus: bus,
-                from_bus: recv,
-                modified: bit_vec::BitVec::from_elem(MAIN_MEMORY_SIZE / BLOCK_SIZE, false),
-                data: [0; MAIN_MEMORY_SIZE],
+    /// Create the main memory of the given `system`, keeping its blocks in
+    /// `dram`, with an optional shared last-level cache in front of it, which
+    /// holds blocks with respect to the memory caches as the `inclusion`
+    /// policy says, and whose replacement policy draws its random numbers
+    /// from `seed`. Every DRAM and last-level cache access is recorded in
+    /// `stats`.
+    pub fn new(system: &SystemConfig,
+               dram: Dram,
+               llc: Option<LevelConfig>,
+               inclusion: Inclusion,
+               seed: usize,
+               stats: Rc<MemoryStats>)
+               -> MainMemory {
+        MainMemory {
+            block_size: system.block_size,
+            dram,
+            to_bus: bus::Outbox::default(),
+            llc: llc.map(|config| LastLevelCache {
+                latency: config.latency,
+                inclusion,
+                // After the bus arbiter's `[seed, usize::MAX]`.
+                dirty: SetAssociative::new(config.geometry, config.replacement, &[seed, usize::MAX, 1]),
+            }),
+            stats,
+            modified: bit_vec::BitVec::from_elem(system.blocks(), false),
+            readers: vec![bit_vec::BitVec::from_elem(system.number_of_caches, false); system.blocks()],
+            last_response: 0,
+            data: vec![0; system.main_memory_size],
+        }
+    }
+
+    /// Get the messages main memory has sent of its own accord, rather than in
+    /// response to a request, since the last call.
+    pub fn outbox(&mut self) -> Vec<bus::BusMessage> {
+        self.to_bus.take()
+    }
+
+    /// Read `block` out of DRAM, starting at cycle `now`. Returns the cycle it
+    /// has been read at.
+    fn dram_read(&mut self, block: Block, now: Cycle) -> Cycle {
+        let access = self.dram.access(block, now);
+        self.stats.record_row_buffer(access.row_buffer);
+        add(&self.stats.dram_reads, 1);
+        add(&self.stats.dram_read_cycles, (access.done - now) as usize);
+        access.done
+    }
+
+    /// Write `block` to DRAM, starting at cycle `now`. Nothing waits for the
+    /// write, but it keeps its bank busy.
+    fn dram_write(&mut self, block: Block, now: Cycle) {
+        let access = self.dram.access(block, now);
+        self.stats.record_row_buffer(access.row_buffer);
+        add(&self.stats.dram_writes, 1);
+    }
+
+    /// Fill `block` into the last-level cache at cycle `now`, evicting a victim
+    /// if its set is full.
+    fn llc_fill(&mut self, block: Block, dirty: bool, now: Cycle) {
+        let llc = self.llc.as_mut().expect("Should have a last-level cache to fill");
+        self.stats.llc_resident[block.0].set(true);
+
+        let (victim, victim_dirty) = match llc.dirty.insert(block, dirty) {
+            Some(evicted) => evicted,
+            None => return,
+        };
+        self.stats.llc_resident[victim.0].set(false);
+
+        // The memory caches may not hold what an inclusive last-level cache
+        // does not, so take the victim back from them. Any dirty copies will
+        // be written back straight to DRAM.
+        if llc.inclusion == Inclusion::Inclusive {
+            self.readers[victim.0].clear();
+            add(&self.stats.llc_back_invalidations, 1);
+            self.to_bus.send(bus::BusMessage::BackInvalidate {
+                block: victim,
             });
+        }
+
+        if victim_dirty {
+            self.dram_write(victim, now);
+        }
+    }
+
+    /// Read `block` out of the last-level cache, or out of DRAM if it misses,
+    /// starting at cycle `now`. Returns the cycle it has been read at.
+    fn read_block(&mut self, block: Block, now: Cycle) -> Cycle {
+        let inclusion = match self.llc {
+            Some(ref llc) => llc.inclusion,
+            None => return self.dram_read(block, now),
+        };
+
+        let hit = self.llc.as_mut().unwrap().dirty.get(&block).cloned();
+        match (hit, inclusion) {
+            // The block moves up into the memory cache, and the memory caches
+            // only ever hold clean copies that main memory gives them.
+            (Some(dirty), Inclusion::Exclusive) => {
+                add(&self.stats.llc_hits, 1);
+                self.llc.as_mut().unwrap().dirty.remove(&block);
+                self.stats.llc_resident[block.0].set(false);
+                if dirty {
+                    self.dram_write(block, now);
+                }
+                now
+            },
+            (Some(_), _) => {
+                add(&self.stats.llc_hits, 1);
+                now
+            },
+            (None, Inclusion::Exclusive) => {
+                add(&self.stats.llc_misses, 1);
+                self.dram_read(block, now)
+            },
+            (None, _) => {
+                add(&self.stats.llc_misses, 1);
+                let read = self.dram_read(block, now);
+                self.llc_fill(block, false, now);
+                read
+            },
+        }
+    }
+
+    /// Write `block` into the last-level cache, or into DRAM if there is none,
+    /// starting at cycle `now`.
+    fn write_block(&mut self, block: Block, now: Cycle) {
+        let inclusion = match self.llc {
+            Some(ref llc) => llc.inclusion,
+            None => return self.dram_write(block, now),
+        };
+
+        if let Some(dirty) = self.llc.as_mut().unwrap().dirty.peek_mut(&block) {
+            *dirty = true;
+            return;
+        }
 
-            memory.run();
-        });
-
-        send
-    }
-
-    /// Run the main loop of the main memory thread. Serves up responses to
-    /// requests to read and write memory.
-    pub fn run(mut self) {
-        for msg in self.from_bus {
-            // Simulate how main memory is an order of magnitude slower than
-            // cache with a 100,000 ns sleep.
-            thread::sleep(::std::time::Duration::new(0, 100_000));
-
-            match msg {
-                bus::BusMessage::ReadRequest { who, block } => {
-                    let data = if self.modified.get(block.0).unwrap_or(false) {
-                        None
-                    } else {
-                        let mut data = [0 as u8; BLOCK_SIZE];
-                        data.clone_from_slice(&self.data[block.address_range()]);
-                        Some(data)
-                    };
-
-                    self.to_bus.send(bus::BusMessage::ReadResponse {
-                        who: who,
-                        from: bus::ResponseSender::MainMemory,
-                        block: block,
-                        data: data,
-                    }).expect("Error sending to bus from main memory");
-                },
-
-                bus::BusMessage::ReadExclusiveRequest { who, block } => {
-                    let data = if self.modified.get(block.0).unwrap_or(false) {
-                        None
-                    } else {
-                        self.modified.set(block.0, true);
-                        let mut data = [0 as u8; BLOCK_SIZE];
-                        data.clone_from_slice(&self.data[block.address_range()]);
-                        Some(data)
-                    };
-
-                    self.to_bus.send(bus::BusMessage::ReadExclusiveResponse {
-                        who: who,
-                        block: block,
-                        data: data,
-                    }).expect("Error sending to bus from main memory");
-                },
-
-                bus::BusMessage::WriteRequest { block, data } => {
-                    self.modified.set(block.0, false);
-                    self.data[block.address_range()].clone_from_slice(&data);
-                },
-
-                // Ignored.
-                bus::BusMessage::ReadResponse { who: _, from: _, block: _, data: _ } => { },
-                bus::BusMessage::ReadExclusiveResponse { who: _, block: _, data: _ } => { },
-                bus::BusMessage::InvalidateRequest { who: _, block: _ } => { },
-                bus::BusMessage::InvalidateResponse { who: _, ok: _ } => { },
-            }
+        match inclusion {
+            // The block was back-invalidated when the last-level cache evicted
+            // it, so it goes straight to DRAM.
+            Inclusion::Inclusive => self.dram_write(block, now),
+            Inclusion::Exclusive => {
+                add(&self.stats.llc_victim_fills, 1);
+                self.llc_fill(block, true, now);
+            },
+            Inclusion::NonInclusive => self.llc_fill(block, true, now),
         }
     }
+
+    /// Note that a memory cache evicted its clean copy of `block` at cycle
+    /// `now`. An exclusive last-level cache takes it as a victim fill.
+    fn put_clean(&mut self, block: Block, now: Cycle) {
+        match self.llc {
+            Some(ref llc) if llc.inclusion == Inclusion::Exclusive => {
+                if llc.dirty.peek(&block).is_some() {
+                    return;
+                }
+            },
+            _ => return,
+        }
+
+        add(&self.stats.llc_victim_fills, 1);
+        self.llc_fill(block, false, now);
+    }
+
+    /// Note that cache `who` has been given `block` to write, so that it is
+    /// the block's only reader, and main memory's copy is stale until the
+    /// block is written back.
+    fn grant(&mut self, block: Block, who: MemoryCacheId) {
+        self.modified.set(block.0, true);
+        self.readers[block.0].clear();
+        self.readers[block.0].set(who as usize, true);
+    }
+
+    /// Serve a message delivered to main memory at cycle `now`, such as a
+    /// request to read or write a block. Returns the number of cycles it keeps
+    /// main memory busy for, the number of cycles until its response, and the
+    /// response, if any. Main memory takes one message at a time.
+    pub fn serve(&mut self, msg: bus::BusMessage, now: Cycle) -> (usize, usize, Option<bus::BusMessage>) {
+        // Every request for or of a block is looked up in the last-level
+        // cache, if there is one, which keeps main memory busy. Whatever DRAM
+        // accesses it then needs overlap with the next messages'. Other
+        // messages are only noted, or ignored, at once.
+        let lookup = self.llc.as_ref().map_or(0, |llc| llc.latency);
+        let after_lookup = now + lookup as Cycle;
+        let mut busy = 0;
+        let mut ready = now;
+
+        let response = match msg {
+            bus::BusMessage::ReadRequest { who, tag, block } => {
+                // Blocks that have been read since they were last written
+                // may still be held by other caches. This is conservative,
+                // since caches drop clean blocks without telling us.
+                let shared = self.readers[block.0].any();
+                // Even if the block's owner answers instead of us.
+                self.readers[block.0].set(who as usize, true);
+                busy = lookup;
+                ready = after_lookup;
+
+                let data = if self.modified.get(block.0).unwrap_or(false) {
+                    None
+                } else {
+                    ready = self.read_block(block, after_lookup);
+                    Some(self.data[block.address_range(self.block_size)].to_vec())
+                };
+
+                Some(bus::BusMessage::ReadResponse {
+                    who,
+                    tag,
+                    from: bus::ResponseSender::MainMemory,
+                    block,
+                    data,
+                    shared,
+                })
+            },
+
+            bus::BusMessage::ReadExclusiveRequest { who, tag, block } => {
+                busy = lookup;
+                ready = after_lookup;
+                let data = if self.modified.get(block.0).unwrap_or(false) {
+                    None
+                } else {
+                    self.grant(block, who);
+                    ready = self.read_block(block, after_lookup);
+                    Some(self.data[block.address_range(self.block_size)].to_vec())
+                };
+
+                Some(bus::BusMessage::ReadExclusiveResponse {
+                    who,
+                    tag,
+                    block,
+                    data,
+                })
+            },
+
+            // The requester's copy is still current unless another cache has
+            // been given the block to write since it read it. Other caches
+            // lost their copies to the upgrade either way, so this is the
+            // order in which racing upgrades succeed: only the first one does.
+            bus::BusMessage::UpgradeRequest { who, tag, block } => {
+                let ok = self.readers[block.0][who as usize];
+                if ok {
+                    self.grant(block, who);
+                }
+                Some(bus::BusMessage::UpgradeResponse {
+                    who,
+                    tag,
+                    ok,
+                })
+            },
+
+            bus::BusMessage::WriteRequest { block, data, evicted: _ } => {
+                self.modified.set(block.0, false);
+                self.data[block.address_range(self.block_size)].clone_from_slice(&data);
+                busy = lookup;
+                self.write_block(block, after_lookup);
+                None
+            },
+
+            // Keep our copy of the block current, rather than waiting for its
+            // owner to write it back: a writer may lose the block to another
+            // cache before its update is even ordered on the bus, leaving no
+            // owner at all.
+            bus::BusMessage::Update { who: _, address, value, shared: _ } => {
+                self.data[address.0] = value;
+                None
+            },
+
+            bus::BusMessage::PutClean { who: _, block } => {
+                busy = lookup;
+                self.put_clean(block, after_lookup);
+                None
+            },
+
+            // Ignored.
+            bus::BusMessage::ReadResponse { who: _, tag: _, from: _, block: _, data: _, shared: _ } |
+            bus::BusMessage::ReadExclusiveResponse { who: _, tag: _, block: _, data: _ } |
+            bus::BusMessage::UpgradeResponse { who: _, tag: _, ok: _ } |
+            bus::BusMessage::BackInvalidate { block: _ } => None,
+        };
+
+        // Responses go out in the order their requests came in, so one that
+        // is ready early still waits for those before it.
+        if response.is_some() {
+            ready = ready.max(self.last_response);
+            self.last_response = ready;
+        }
+
+        (busy, (ready - now) as usize, response)
+    }
 }
@@ -1,116 +1,399 @@
 //! Memory cache implementation.
 
-extern crate lru_time_cache;
-use self::lru_time_cache::LruCache;
-
-use std::mem;
-use std::sync::mpsc;
-use std::thread;
+use std::collections::{BTreeMap, BTreeSet};
 
 use bus;
+use coherence::{CoherenceProtocol, LineState, Snoop, SnoopReaction, WriteAction};
 use main_memory;
+use prefetch::{PrefetchStats, Prefetcher, PrefetcherKind};
+use hierarchy::{Hierarchy, Inclusion, InclusionStats, LevelConfig, LevelStats};
+use replacement::{ReplacementKind, ReplacementStats};
+use set_associative::{CacheGeometry, SetAssociative};
+use system::{self, SystemConfig};
 
-/// The number of blocks a cache can hold.
-pub const CACHE_SIZE: usize = main_memory::BLOCK_SIZE;
-
-/// The number of caches to simulate.
-pub const NUMBER_OF_CACHES: usize = 8;
-
-/// The current MESI state of a cache line.
-///
-/// Descriptions of individual states quoted [from
-/// Wikipedia](https://en.wikipedia.org/wiki/MESI_protocol).
-///
-/// <pre>
-///   M E S I
-/// M ✗ ✗ ✗ ✓
-/// E ✗ ✗ ✗ ✓
-/// S ✗ ✗ ✓ ✓
-/// I ✓ ✓ ✓ ✓
-/// </pre>
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub enum MesiState {
-    /// "The cache line is present only in the current cache, and is dirty; it
-    /// has been modified from the value in main memory. The cache is required
-    /// to write the data back to main memory at some time in the future, before
-    /// permitting any other read of the (no longer valid) main memory
-    /// state. The write-back changes the line to the Shared state."
-    Modified,
-
-    /// "The cache line is present only in the current cache, but is clean; it
-    /// matches main memory. It may be changed to the Shared state at any time,
-    /// in response to a read request. Alternatively, it may be changed to the
-    /// Modified state when writing to it."
-    Exclusive,
-
-    /// "Indicates that this cache line may be stored in other caches of the
-    /// machine and is clean; it matches the main memory. The line may be
-    /// discarded (changed to the Invalid state) at any time."
-    Shared,
-
-    /// "Indicates that this cache line is invalid (unused)."
-    Invalid,
-}
+/// The number of cycles it takes to look a block up in a cache, unless
+/// configured otherwise.
+pub const DEFAULT_LATENCY: usize = 4;
 
 /// The id of a memory cache.
 pub type MemoryCacheId = u8;
 
-/// A cache line is a block of data and its associated MESI state.
-#[derive(Clone, Copy)]
-pub struct CacheLine {
-    state: MesiState,
-    data: [u8; main_memory::BLOCK_SIZE],
+/// How a memory cache is built.
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// The levels of the cache's private hierarchy, L1 first. The last level
+    /// is the one closest to the bus.
+    pub levels: Vec<LevelConfig>,
+    /// How the levels share the blocks they hold.
+    pub inclusion: Inclusion,
+    /// Whether to tell the bus when a clean line is evicted, with a
+    /// `PutClean` message. Dirty lines are always written back.
+    pub announce_clean_evictions: bool,
+    /// The number of lines in the fully associative victim buffer, which
+    /// catches lines evicted from the hierarchy. Zero for no victim buffer.
+    pub victim_entries: usize,
+    /// The prefetcher watching the processor's accesses.
+    pub prefetcher: PrefetcherKind,
+    /// The number of the processor's reads and writes that may wait on the
+    /// bus at once, each holding a miss status register. At least one.
+    pub outstanding: usize,
 }
 
-impl CacheLine {
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            levels: vec![LevelConfig {
+                geometry: CacheGeometry::fully_associative(system::DEFAULT_CACHE_SIZE),
+                replacement: ReplacementKind::Lru,
+                latency: DEFAULT_LATENCY,
+            }],
+            inclusion: Inclusion::NonInclusive,
+            announce_clean_evictions: false,
+            victim_entries: 0,
+            prefetcher: PrefetcherKind::None,
+            outstanding: 1,
+        }
+    }
+}
+
+/// A cache line is a block of data and its associated coherence state.
+#[derive(Clone)]
+pub struct CacheLine<S> {
+    state: S,
+    data: Vec<u8>,
+}
+
+impl<S: LineState> CacheLine<S> {
     /// Read a byte from the data in this cache line.
     pub fn read_byte(&self, addr: main_memory::Address) -> u8 {
-        assert!(self.state != MesiState::Invalid);
-        self.data[addr.0 % main_memory::BLOCK_SIZE]
+        assert!(self.state.is_valid());
+        self.data[addr.0 % self.data.len()]
     }
 
     /// Write a byte to the data in this cache line.
     pub fn write_byte(&mut self, addr: main_memory::Address, val: u8) {
-        assert!(self.state == MesiState::Modified);
-        self.data[addr.0 % main_memory::BLOCK_SIZE] = val;
+        assert!(self.state.is_writable());
+        let offset = addr.0 % self.data.len();
+        self.data[offset] = val;
+    }
+}
+
+/// How a processor's read or write went when it was made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lookup {
+    /// The number of cycles spent looking for the block in the hierarchy.
+    pub latency: usize,
+    /// The transaction the access waits on the bus for, or `None` if it is
+    /// done. If it waits, it is done when `MemoryCache::receive` says so.
+    pub waiting: Option<bus::Tag>,
+    /// The byte read, if the access is a read that is done.
+    pub value: Option<u8>,
+}
+
+/// A processor's access that is done, now that the bus has responded to the
+/// transaction it waited on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Completion {
+    /// The transaction.
+    pub tag: bus::Tag,
+    /// The byte read, if the access is a read.
+    pub value: Option<u8>,
+}
+
+/// A processor's access that is waiting on the bus, and what it waits for.
+enum Pending<S> {
+    /// A read of `address`, waiting for the response to a read request.
+    Read {
+        address: main_memory::Address,
+    },
+    /// A write of `value` to `address`, waiting for the response to a read
+    /// request before writing as the protocol says.
+    Fill {
+        address: main_memory::Address,
+        value: u8,
+    },
+    /// A write of `value` to `address`, waiting for the response to an
+    /// upgrade of `line`, ours, before writing to it. `fetched` says whether
+    /// the write has already missed and fetched the block, and `ordered`
+    /// whether the upgrade has been ordered on the bus yet.
+    Upgrade {
+        address: main_memory::Address,
+        value: u8,
+        fetched: bool,
+        line: Box<CacheLine<S>>,
+        ordered: bool,
+    },
+    /// A write of `value` to `address`, waiting for the response to a read
+    /// exclusive request.
+    ReadExclusive {
+        address: main_memory::Address,
+        value: u8,
+    },
+}
+
+impl<S> Pending<S> {
+    /// The address the access is to.
+    fn address(&self) -> main_memory::Address {
+        match *self {
+            Pending::Read { address } |
+            Pending::Fill { address, value: _ } |
+            Pending::Upgrade { address, value: _, fetched: _, line: _, ordered: _ } |
+            Pending::ReadExclusive { address, value: _ } => address,
+        }
     }
 }
 
-/// A memory cache.
-pub struct MemoryCache {
+/// Something another cache did to a block while we were fetching it, after
+/// our request for it was sent, or while we were upgrading it, after our
+/// upgrade was ordered. It is ordered after our request, so it is applied to
+/// the block once our response arrives.
+enum Conflict {
+    /// A request that invalidates other copies of the block.
+    Snoop(MemoryCacheId, Snoop),
+    /// An update of `address` to `value` by cache `who`.
+    Update {
+        who: MemoryCacheId,
+        address: main_memory::Address,
+        value: u8,
+    },
+}
+
+/// A memory cache, kept coherent with the others by the protocol `P`.
+pub struct MemoryCache<P: CoherenceProtocol> {
     /// This cache's unique id.
     pub id: MemoryCacheId,
+    system: SystemConfig,
+    protocol: P,
     miss_count: f64,
     total_count: f64,
-    to_bus: mpsc::Sender<bus::BusMessage>,
-    from_bus: mpsc::Receiver<bus::BusMessage>,
-    cached_lines: LruCache<main_memory::Block, Box<CacheLine>>,
+    lookup_cycles: f64,
+    access_cycles: f64,
+    to_bus: bus::Outbox,
+    // The processor's accesses waiting on the bus, by the transaction they
+    // wait on, and how many may wait at once.
+    pending: BTreeMap<bus::Tag, Pending<P::State>>,
+    outstanding: usize,
+    next_tag: u32,
+    // What other caches did to the blocks we are fetching, since we asked.
+    conflicts: BTreeMap<main_memory::Block, Vec<Conflict>>,
+    conflict_count: usize,
+    exclusive_miss_count: usize,
+    upgrade_count: usize,
+    lost_upgrade_count: usize,
+    announce_clean_evictions: bool,
+    cached_lines: Hierarchy<Box<CacheLine<P::State>>>,
+    victims: Option<SetAssociative<Box<CacheLine<P::State>>>>,
+    victim_hits: usize,
+    prefetcher_kind: PrefetcherKind,
+    prefetcher: Option<Box<dyn Prefetcher>>,
+    // Blocks we have sent prefetch read requests for and not yet heard back
+    // about, their transactions, and whether the processor has since missed
+    // on them.
+    in_flight: BTreeMap<main_memory::Block, (bus::Tag, bool)>,
+    // Blocks that were prefetched and not yet accessed by the processor.
+    prefetched: BTreeSet<main_memory::Block>,
+    prefetch_stats: PrefetchStats,
+    // The addresses the processor wrote whose updates we have broadcast, but
+    // not yet seen come back over the bus, and how many of them.
+    unechoed: BTreeMap<main_memory::Address, usize>,
 }
 
-impl MemoryCache {
-    /// Spawn a MemoryCache thread that uses `accessor` to simulate data access
-    /// patterns.
-    pub fn spawn<F>(id: MemoryCacheId,
-                    bus: mpsc::Sender<bus::BusMessage>,
-                    accessor: F)
-                    -> (mpsc::Sender<bus::BusMessage>, thread::JoinHandle<()>)
-        where F: 'static + Send + FnOnce(MemoryCache)
-    {
-        let (send, recv) = mpsc::channel();
-
-        let th = thread::Builder::new().name(format!("Memory cache {}", id));
-        let handle = th.spawn(move || {
-            accessor(MemoryCache {
-                id: id,
-                miss_count: 0.0,
-                total_count: 0.0,
-                to_bus: bus,
-                from_bus: recv,
-                cached_lines: LruCache::with_capacity(CACHE_SIZE),
-            });
-        });
+impl<P: CoherenceProtocol> MemoryCache<P> {
+    /// Create a memory cache in the given `system`, built as described by
+    /// `config`, whose replacement policies draw their random numbers from
+    /// `seed` and the cache's id.
+    pub fn new(id: MemoryCacheId, system: &SystemConfig, protocol: P, config: CacheConfig, seed: usize)
+               -> MemoryCache<P> {
+        // The processor draws from `[seed, id]`, and each level from after
+        // it, so the victim buffer draws from after the last level.
+        let seed = [seed, id as usize];
+        MemoryCache {
+            id,
+            system: *system,
+            protocol,
+            miss_count: 0.0,
+            total_count: 0.0,
+            lookup_cycles: 0.0,
+            access_cycles: 0.0,
+            to_bus: bus::Outbox::default(),
+            pending: BTreeMap::new(),
+            outstanding: config.outstanding,
+            next_tag: 0,
+            conflicts: BTreeMap::new(),
+            conflict_count: 0,
+            exclusive_miss_count: 0,
+            upgrade_count: 0,
+            lost_upgrade_count: 0,
+            announce_clean_evictions: config.announce_clean_evictions,
+            cached_lines: Hierarchy::new(&config.levels, config.inclusion, &seed),
+            victims: match config.victim_entries {
+                0 => None,
+                entries => Some(SetAssociative::new(CacheGeometry::fully_associative(entries),
+                                                    ReplacementKind::Lru,
+                                                    &[seed[0], seed[1], config.levels.len() + 1])),
+            },
+            victim_hits: 0,
+            prefetcher_kind: config.prefetcher,
+            prefetcher: config.prefetcher.build(),
+            in_flight: BTreeMap::new(),
+            prefetched: BTreeSet::new(),
+            prefetch_stats: PrefetchStats::default(),
+            unechoed: BTreeMap::new(),
+        }
+    }
+
+    /// Get the messages this cache has sent since the last call.
+    pub fn outbox(&mut self) -> Vec<bus::BusMessage> {
+        self.to_bus.take()
+    }
+
+    /// Get the system this cache is part of.
+    pub fn system(&self) -> &SystemConfig {
+        &self.system
+    }
+
+    /// Get the coherence protocol this cache follows.
+    pub fn protocol(&self) -> &P {
+        &self.protocol
+    }
+
+    /// Get the configuration of each level of this cache's hierarchy, L1
+    /// first.
+    pub fn levels(&self) -> Vec<LevelConfig> {
+        self.cached_lines.configs()
+    }
+
+    /// Return how each level of this cache's hierarchy has been used, L1
+    /// first.
+    pub fn level_stats(&self) -> Vec<LevelStats> {
+        self.cached_lines.stats()
+    }
+
+    /// Get the policy governing which levels of this cache's hierarchy blocks
+    /// are resident in.
+    pub fn inclusion(&self) -> Inclusion {
+        self.cached_lines.inclusion()
+    }
+
+    /// Return how the inclusion policy has moved blocks between this cache's
+    /// levels.
+    pub fn inclusion_stats(&self) -> InclusionStats {
+        self.cached_lines.inclusion_stats()
+    }
+
+    /// Return every block this cache currently holds, including those in its
+    /// victim buffer.
+    pub fn blocks(&self) -> Vec<main_memory::Block> {
+        let mut blocks = self.cached_lines.blocks();
+        if let Some(ref victims) = self.victims {
+            blocks.extend(victims.blocks());
+        }
+        blocks
+    }
+
+    /// Return the number of lines this cache's victim buffer holds, or zero if
+    /// it has none.
+    pub fn victim_entries(&self) -> usize {
+        self.victims.as_ref().map_or(0, |victims| victims.geometry().capacity())
+    }
+
+    /// Return how often lines were caught by and evicted from the victim
+    /// buffer, if there is one.
+    pub fn victim_stats(&self) -> Option<ReplacementStats> {
+        self.victims.as_ref().map(|victims| victims.stats())
+    }
+
+    /// Return how many accesses missed the hierarchy but found their line in
+    /// the victim buffer, sparing a bus transaction.
+    pub fn victim_hits(&self) -> usize {
+        self.victim_hits
+    }
+
+    /// Get the number of the processor's reads and writes that may wait on the
+    /// bus at once.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding
+    }
+
+    /// Return how many times another cache's request to write a block, or
+    /// back-invalidation of it, raced with our own request for it.
+    pub fn conflicts(&self) -> usize {
+        self.conflict_count
+    }
+
+    /// Return the number of writes that read their block exclusively, data
+    /// and all, either because they missed, or because their upgrade was
+    /// lost.
+    pub fn exclusive_misses(&self) -> usize {
+        self.exclusive_miss_count
+    }
+
+    /// Return the number of writes that upgraded a block we held, without
+    /// fetching it again.
+    pub fn upgrades(&self) -> usize {
+        self.upgrade_count
+    }
+
+    /// Return the number of upgrades that failed, because another cache was
+    /// given the block to write first.
+    pub fn lost_upgrades(&self) -> usize {
+        self.lost_upgrade_count
+    }
+
+    /// Get the prefetcher watching this cache's processor.
+    pub fn prefetcher(&self) -> PrefetcherKind {
+        self.prefetcher_kind
+    }
+
+    /// Return how useful this cache's prefetches have been.
+    pub fn prefetch_stats(&self) -> PrefetchStats {
+        self.prefetch_stats
+    }
+
+    /// Return the percent of would-be misses that prefetching has turned into
+    /// hits.
+    pub fn prefetch_coverage_percent(&self) -> f64 {
+        self.prefetch_stats.coverage_percent(self.miss_count as usize)
+    }
+
+    /// Return the number of reads and writes.
+    pub fn accesses(&self) -> usize {
+        self.total_count as usize
+    }
+
+    /// Return the number of reads and writes that have missed the cache.
+    pub fn misses(&self) -> usize {
+        self.miss_count as usize
+    }
+
+    /// Return the total number of cycles reads and writes have spent looking
+    /// for their block in this cache's hierarchy.
+    pub fn lookup_cycles(&self) -> usize {
+        self.lookup_cycles as usize
+    }
 
-        (send, handle.expect("Error spawning thread"))
+    /// Return the average number of cycles reads and writes have spent
+    /// looking for their block in this cache's hierarchy.
+    pub fn average_lookup_latency(&self) -> f64 {
+        self.lookup_cycles / self.total_count
+    }
+
+    /// Record that a read or write is done, `cycles` cycles after the
+    /// processor made it.
+    pub fn complete(&mut self, cycles: usize) {
+        self.access_cycles += cycles as f64;
+    }
+
+    /// Return the total number of cycles reads and writes have taken, from
+    /// being made until being done.
+    pub fn access_cycles(&self) -> usize {
+        self.access_cycles as usize
+    }
+
+    /// Return the average number of cycles reads and writes have taken, from
+    /// being made until being done: the average memory access time.
+    pub fn average_access_latency(&self) -> f64 {
+        self.access_cycles / self.total_count
     }
 
     /// Return the percent of reads and writes that have missed the cache.
@@ -123,323 +406,926 @@ impl MemoryCache {
     pub fn reset_stats(&mut self) {
         self.miss_count = 0.0;
         self.total_count = 0.0;
+        self.lookup_cycles = 0.0;
+        self.access_cycles = 0.0;
+        self.victim_hits = 0;
+        self.conflict_count = 0;
+        self.exclusive_miss_count = 0;
+        self.upgrade_count = 0;
+        self.lost_upgrade_count = 0;
+        self.prefetch_stats = PrefetchStats::default();
+        self.cached_lines.reset_stats();
+        if let Some(ref mut victims) = self.victims {
+            victims.reset_stats();
+        }
     }
 
-    /// Empty the cache and write back any modified cache lines that might be
-    /// stored.
+    /// Empty the cache, evicting every cache line that might be stored.
     pub fn empty(&mut self) {
-        self.flush();
-        mem::replace(&mut self.cached_lines, LruCache::with_capacity(CACHE_SIZE));
+        // Whatever is still in flight will be filled as an ordinary line.
+        self.in_flight.clear();
+        self.prefetched.clear();
+        self.conflicts.clear();
+
+        for block in self.blocks() {
+            let cache_line = self.remove_line(&block).unwrap();
+            self.release(block, &cache_line);
+        }
+        self.cached_lines.clear();
+        if let Some(ref mut victims) = self.victims {
+            victims.clear();
+        }
     }
 
-    /// Flush the cache. Writes each `MesiState::Modified` cache line back to
-    /// main memory.
+    /// Flush the cache. Writes each dirty cache line back to main memory.
     pub fn flush(&mut self) {
-        let modified = self.cached_lines.retrieve_all().into_iter()
-            .filter(|&(_, ref c)| c.state == MesiState::Modified);
+        for block in self.blocks() {
+            let dirty = self.line_mut(&block)
+                .is_some_and(|cache_line| cache_line.state.is_dirty());
+            if !dirty {
+                continue;
+            }
+
+            let cache_line = self.remove_line(&block).unwrap();
+            self.to_bus.send(bus::BusMessage::WriteRequest {
+                block,
+                data: cache_line.data.clone(),
+                evicted: Some(self.id),
+            });
+        }
+    }
+
+    /// Get the line for `block`, wherever we hold it: in the hierarchy or in
+    /// the victim buffer.
+    fn line_mut(&mut self, block: &main_memory::Block) -> Option<&mut Box<CacheLine<P::State>>> {
+        if self.cached_lines.peek(block).is_some() {
+            return self.cached_lines.peek_mut(block);
+        }
+        self.victims.as_mut().and_then(|victims| victims.peek_mut(block))
+    }
+
+    /// Remove the line for `block`, wherever we hold it.
+    fn remove_line(&mut self, block: &main_memory::Block) -> Option<Box<CacheLine<P::State>>> {
+        let victim = self.victims.as_mut().and_then(|victims| victims.remove(block));
+        self.cached_lines.remove(block).or(victim)
+    }
+
+    /// Store `cache_line` for `block` in every level, evicting the victims
+    /// the replacement policies choose if their sets are full.
+    fn fill(&mut self, block: main_memory::Block, cache_line: Box<CacheLine<P::State>>) {
+        for (victim, victim_line) in self.cached_lines.insert(block, cache_line) {
+            self.evict(victim, victim_line);
+        }
+    }
+
+    /// Look for `block` in the hierarchy on behalf of the processor, moving it
+    /// up to L1 if it is found in a lower level. If the hierarchy misses, but
+    /// the victim buffer holds the block, move it back into the hierarchy.
+    /// Returns the number of cycles the lookup took.
+    fn access(&mut self, block: main_memory::Block) -> usize {
+        let access = self.cached_lines.access(&block);
+        self.lookup_cycles += access.latency as f64;
+        for (victim, victim_line) in access.evicted {
+            self.evict(victim, victim_line);
+        }
+
+        if access.level.is_none() && self.reclaim(block) {
+            self.victim_hits += 1;
+        }
+        access.latency
+    }
+
+    /// Move the line for `block` out of the victim buffer and back into the
+    /// hierarchy, if the victim buffer holds it. Returns whether it did.
+    fn reclaim(&mut self, block: main_memory::Block) -> bool {
+        match self.victims.as_mut().and_then(|victims| victims.remove(&block)) {
+            Some(cache_line) => {
+                self.fill(block, cache_line);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Count a processor access to `block` that missed the cache, noting if a
+    /// prefetch of it was too late.
+    fn count_miss(&mut self, block: main_memory::Block) {
+        self.miss_count += 1.0;
+        if let Some(&mut (_, ref mut demanded)) = self.in_flight.get_mut(&block) {
+            *demanded = true;
+            self.prefetch_stats.late += 1;
+        }
+    }
+
+    /// Tell the prefetcher about a processor access to `block`, which `hit`
+    /// says did or did not hit the cache, and send read requests for the
+    /// blocks it guesses are next that we neither hold nor are already
+    /// fetching.
+    fn prefetch(&mut self, block: main_memory::Block, hit: bool) {
+        if hit && self.prefetched.remove(&block) {
+            self.prefetch_stats.useful += 1;
+        }
+
+        let guesses = match self.prefetcher {
+            Some(ref mut prefetcher) => prefetcher.on_access(block, hit),
+            None => return,
+        };
+
+        for guess in guesses {
+            if guess.0 >= self.system.blocks() {
+                continue;
+            }
+
+            let held = self.line_mut(&guess).is_some_and(|cache_line| cache_line.state.is_valid());
+            if held || self.in_flight.contains_key(&guess) {
+                continue;
+            }
+
+            let tag = self.next_tag();
+            self.request(guess, bus::BusMessage::ReadRequest {
+                who: self.id,
+                tag,
+                block: guess,
+            });
+            self.in_flight.insert(guess, (tag, false));
+            self.prefetch_stats.issued += 1;
+        }
+    }
+
+    /// Start a new transaction.
+    fn next_tag(&mut self) -> bus::Tag {
+        let tag = bus::Tag(self.next_tag);
+        self.next_tag = self.next_tag.wrapping_add(1);
+        tag
+    }
+
+    /// Send a request about `block`. Whatever other caches did to the block
+    /// that we have already seen is ordered before it, so no longer races
+    /// with it.
+    fn request(&mut self, block: main_memory::Block, msg: bus::BusMessage) {
+        self.conflicts.remove(&block);
+        self.to_bus.send(msg);
+    }
+
+    /// Are we fetching `block`, or upgrading it with an upgrade that has been
+    /// ordered?
+    fn fetching(&self, block: main_memory::Block) -> bool {
+        self.in_flight.contains_key(&block) ||
+            self.pending_for(block).any(|pending| match *pending {
+                Pending::Upgrade { address: _, value: _, fetched: _, line: _, ordered } => ordered,
+                _ => true,
+            })
+    }
+
+    /// Are we upgrading `block`, with an upgrade that has been ordered? Our
+    /// copy is then as good as written, so whatever other caches do to it
+    /// must wait for the upgrade's response.
+    fn upgrading(&self, block: main_memory::Block) -> bool {
+        self.pending_for(block).any(|pending| match *pending {
+            Pending::Upgrade { address: _, value: _, fetched: _, line: _, ordered } => ordered,
+            _ => false,
+        })
+    }
+
+    /// Get the processor's accesses to `block` waiting on the bus.
+    fn pending_for(&self, block: main_memory::Block) -> impl Iterator<Item = &Pending<P::State>> {
+        let block_size = self.system.block_size;
+        self.pending.values()
+            .filter(move |pending| main_memory::Block::for_addr(pending.address(), block_size) == block)
+    }
+
+    /// Note that `conflict` happened to `block`, which we do not hold, to
+    /// apply it once the block arrives, if we are fetching it.
+    fn record_conflict(&mut self, block: main_memory::Block, conflict: Conflict) {
+        if self.fetching(block) {
+            self.conflict_count += 1;
+            self.conflicts.entry(block).or_default().push(conflict);
+        }
+    }
+
+    /// Forget the other caches' upgrades of `block` that we put off while
+    /// upgrading it ourselves, now that ours has succeeded. On a bus, they
+    /// would have been ordered before ours, but on a network, each cache may
+    /// see its own upgrade first. Main memory serves upgrades in order, and
+    /// only the first succeeds, so theirs came after ours, and will fail.
+    fn drop_lost_upgrades(&mut self, block: main_memory::Block) {
+        if let Some(conflicts) = self.conflicts.get_mut(&block) {
+            conflicts.retain(|conflict| !matches!(*conflict, Conflict::Snoop(_, Snoop::Invalidate)));
+        }
+    }
+
+    /// Apply whatever other caches did to `block` while we were fetching it,
+    /// now that it has arrived.
+    fn resolve_conflicts(&mut self, block: main_memory::Block) {
+        for conflict in self.conflicts.remove(&block).unwrap_or_default() {
+            match conflict {
+                Conflict::Snoop(who, snoop) => {
+                    self.snoop(who, None, block, snoop);
+                },
+                Conflict::Update { who, address, value } => {
+                    self.apply_update(who, address, value);
+                },
+            }
+        }
+    }
+
+    /// Catch `cache_line`, which has been evicted from the hierarchy, in the
+    /// victim buffer. If there is no victim buffer, or it evicts a line of its
+    /// own to make room, let go of that line.
+    fn evict(&mut self, block: main_memory::Block, cache_line: Box<CacheLine<P::State>>) {
+        let released = match self.victims {
+            Some(ref mut victims) if cache_line.state.is_valid() => victims.insert(block, cache_line),
+            _ => Some((block, cache_line)),
+        };
+
+        if let Some((block, cache_line)) = released {
+            self.release(block, &cache_line);
+        }
+    }
+
+    /// Let go of `cache_line`, which has been removed from the cache. Writes it
+    /// back if it is dirty, and announces it if it is clean and we are
+    /// configured to.
+    fn release(&mut self, block: main_memory::Block, cache_line: &CacheLine<P::State>) {
+        if self.prefetched.remove(&block) {
+            self.prefetch_stats.evicted_unused += 1;
+        }
+
+        let msg = if cache_line.state.is_dirty() {
+            bus::BusMessage::WriteRequest {
+                block,
+                data: cache_line.data.clone(),
+                evicted: Some(self.id),
+            }
+        } else if cache_line.state.is_valid() && self.announce_clean_evictions {
+            bus::BusMessage::PutClean {
+                who: self.id,
+                block,
+            }
+        } else {
+            return;
+        };
+
+        self.to_bus.send(msg);
+    }
+
+    /// Apply the protocol's reaction to a snooped message from cache `who`
+    /// about `block`, if we hold it in the hierarchy or the victim buffer,
+    /// and are not upgrading it. If the message is a request, `tag` is its
+    /// transaction. Returns the reaction, if any.
+    fn snoop(&mut self, who: MemoryCacheId, tag: Option<bus::Tag>, block: main_memory::Block, snoop: Snoop)
+             -> Option<SnoopReaction<P::State>> {
+        if self.upgrading(block) {
+            return None;
+        }
+
+        // Borrow the stores directly, rather than through `line_mut`, so that
+        // the protocol and bus remain usable while we hold the line.
+        let cache_line = if self.cached_lines.peek(&block).is_some() {
+            self.cached_lines.peek_mut(&block)
+        } else {
+            self.victims.as_mut().and_then(|victims| victims.peek_mut(&block))
+        }?;
 
-        for (block, cache_line) in modified {
+        let reaction = self.protocol.on_snoop(cache_line.state, snoop);
+        cache_line.state = reaction.next;
+
+        if reaction.write_back {
             self.to_bus.send(bus::BusMessage::WriteRequest {
-                block: block,
-                data: cache_line.data,
-            }).expect("Error sending to bus from memory cache");
+                block,
+                data: cache_line.data.clone(),
+                evicted: if reaction.next.is_valid() { None } else { Some(self.id) },
+            });
+        }
 
-            self.cached_lines.remove(&block);
+        if reaction.supply {
+            self.to_bus.send(bus::BusMessage::ReadResponse {
+                who,
+                tag: tag.expect("Only requests are answered with data"),
+                from: bus::ResponseSender::Cache,
+                block,
+                data: Some(cache_line.data.clone()),
+                shared: true,
+            });
         }
+
+        // Free the way for another line, rather than keeping an invalid one
+        // around until the replacement policy gets to it.
+        if !reaction.next.is_valid() {
+            self.remove_line(&block);
+            if who != self.id && self.prefetched.remove(&block) {
+                self.prefetch_stats.invalidated_unused += 1;
+            }
+        }
+
+        Some(reaction)
     }
 
-    /// Flush the cache if adding a new cache line would drop another cache line
-    /// from the cache.
-    fn maybe_flush(&mut self) {
-        if self.cached_lines.len() == CACHE_SIZE {
-            self.flush();
+    /// Apply cache `who`'s update of `address` to `value` to our copy of the
+    /// block, if we hold one. Returns whether we did. If the processor has
+    /// written the address since, and its update has yet to come back, the
+    /// bus orders ours after `who`'s, so our byte is kept.
+    fn apply_update(&mut self, who: MemoryCacheId, address: main_memory::Address, value: u8) -> bool {
+        let block = main_memory::Block::for_addr(address, self.system.block_size);
+        if self.snoop(who, None, block, Snoop::Update).is_none() {
+            return false;
+        }
+        if self.unechoed.contains_key(&address) {
+            return true;
         }
+
+        if let Some(cache_line) = self.line_mut(&block) {
+            if cache_line.state.is_valid() {
+                let offset = address.0 % cache_line.data.len();
+                cache_line.data[offset] = value;
+            }
+        }
+        true
     }
 
     fn handle_bus_message(&mut self, msg: &bus::BusMessage) {
         match *msg {
-            // Snoop on other caches' requests.
+            // Snoop on other caches' requests. Those that write to a block we
+            // are still fetching are applied once it arrives.
 
-            bus::BusMessage::ReadRequest { who, block }
+            bus::BusMessage::ReadRequest { who, tag, block }
             if who != self.id => {
-                if let Some(cache_line) = self.cached_lines.get_mut(&block) {
-                    cache_line.state = match cache_line.state {
-                        MesiState::Invalid => MesiState::Invalid,
-                        MesiState::Exclusive | MesiState::Shared => {
-                            self.to_bus.send(bus::BusMessage::ReadResponse {
-                                who: who,
-                                from: bus::ResponseSender::Cache,
-                                block: block,
-                                data: Some(cache_line.data),
-                            }).expect("Error sending to bus from memory cache");
-                            MesiState::Shared
-                        },
-                        MesiState::Modified => {
-                            self.to_bus.send(bus::BusMessage::WriteRequest {
-                                block: block,
-                                data: cache_line.data,
-                            }).expect("Error sending to bus from memory cache");
-
-                            self.to_bus.send(bus::BusMessage::ReadResponse {
-                                who: who,
-                                from: bus::ResponseSender::Cache,
-                                block: block,
-                                data: Some(cache_line.data),
-                            }).expect("Error sending to bus from memory cache");
-
-                            MesiState::Shared
-                        },
-                    }
+                self.snoop(who, Some(tag), block, Snoop::Read);
+            },
+
+            bus::BusMessage::ReadExclusiveRequest { who, tag, block }
+            if who != self.id => {
+                if self.snoop(who, Some(tag), block, Snoop::ReadExclusive).is_none() {
+                    self.record_conflict(block, Conflict::Snoop(who, Snoop::ReadExclusive));
                 }
             },
 
-            bus::BusMessage::ReadExclusiveRequest { who, block }
+            bus::BusMessage::UpgradeRequest { who, tag, block }
             if who != self.id => {
-                if let Some(cache_line) = self.cached_lines.get_mut(&block) {
-                    if cache_line.state == MesiState::Modified {
-                        self.to_bus.send(bus::BusMessage::WriteRequest {
-                            block: block,
-                            data: cache_line.data,
-                        }).expect("Error sending to bus from memory cache");
-                    }
+                if self.snoop(who, Some(tag), block, Snoop::Invalidate).is_none() {
+                    self.record_conflict(block, Conflict::Snoop(who, Snoop::Invalidate));
+                }
+            },
+
+            // Our upgrade is ordered, so whatever else happens to the block
+            // from now on happens after it.
+            bus::BusMessage::UpgradeRequest { who: _, tag, block: _ } => {
+                if let Some(&mut Pending::Upgrade { address: _, value: _, fetched: _, line: _, ref mut ordered }) =
+                    self.pending.get_mut(&tag) {
+                    *ordered = true;
+                }
+            },
 
-                    cache_line.state = MesiState::Invalid;
+            // A back-invalidation is handled just like another cache reading
+            // the block exclusively.
+            bus::BusMessage::BackInvalidate { block } => {
+                let self_id = self.id;
+                if self.snoop(self_id, None, block, Snoop::ReadExclusive).is_none() {
+                    self.record_conflict(block, Conflict::Snoop(self_id, Snoop::ReadExclusive));
                 }
             },
 
-            bus::BusMessage::InvalidateRequest { who, block }
+            bus::BusMessage::Update { who, address, value, shared: _ }
             if who != self.id => {
-                let mut ok = true;
-
-                if let Some(cache_line) = self.cached_lines.get_mut(&block) {
-                    if cache_line.state == MesiState::Modified {
-                        ok = false;
-                        self.to_bus.send(bus::BusMessage::WriteRequest {
-                            block: block,
-                            data: cache_line.data,
-                        }).expect("Error sending to bus from memory cache");
-                    }
+                if !self.apply_update(who, address, value) {
+                    let block = main_memory::Block::for_addr(address, self.system.block_size);
+                    self.record_conflict(block, Conflict::Update { who, address, value });
+                }
+            },
 
-                    cache_line.state = MesiState::Invalid;
+            // Another cache's update may have won the bus after we wrote the
+            // block but before ours did, taking ownership. Ours is the latest
+            // now, so take ownership back. Our byte was kept, and the
+            // processor may have written it again since, so leave it be. If
+            // no other cache holds the block any more, the protocol may stop
+            // updating.
+            bus::BusMessage::Update { who: _, address, value: _, shared } => {
+                match self.unechoed.get_mut(&address) {
+                    Some(count) if *count > 1 => *count -= 1,
+                    _ => {
+                        self.unechoed.remove(&address);
+                    },
                 }
 
-                self.to_bus.send(bus::BusMessage::InvalidateResponse {
-                    who: who,
-                    ok: ok,
-                }).expect("Error sending to bus from cache");
+                let block = main_memory::Block::for_addr(address, self.system.block_size);
+                if let Some(cache_line) = self.cached_lines.peek_mut(&block) {
+                    if let WriteAction::Update(state) = self.protocol.on_write(cache_line.state) {
+                        cache_line.state = self.protocol.on_update(state, shared);
+                    }
+                }
             },
 
             // Handle responses to our own requests.
 
-            bus::BusMessage::ReadResponse { who, from, block, data }
-            if who == self.id && data.is_some() => {
-                if let Some(cached) = self.cached_lines.get_mut(&block) {
-                    if cached.state != MesiState::Invalid {
-                        cached.state = MesiState::Shared;
+            bus::BusMessage::ReadResponse { who, tag: _, from, block, data: Some(ref data), shared }
+            if who == self.id => {
+                // A second response may arrive after our copy has been
+                // evicted into the victim buffer, so bring it back to refill.
+                self.reclaim(block);
+                let prefetch = self.in_flight.remove(&block).map(|(_, demanded)| demanded);
+                if let Some(cached) = self.cached_lines.peek_mut(&block) {
+                    if cached.state.is_valid() {
+                        // We have since fetched the block ourselves, and our
+                        // copy is at least as new as the prefetched one.
+                        if prefetch.is_some() {
+                            return;
+                        }
+
+                        // Main memory may have answered first with stale data,
+                        // so prefer another cache's copy unless ours is dirty.
+                        if from == bus::ResponseSender::Cache && !cached.state.is_dirty() {
+                            cached.data = data.clone();
+                        }
+                        cached.state = self.protocol.on_read_refill(cached.state, from);
                         return;
                     }
                 }
 
-                self.maybe_flush();
-                self.cached_lines.insert(block, Box::new(CacheLine {
-                    state: match from {
-                        bus::ResponseSender::MainMemory => MesiState::Exclusive,
-                        bus::ResponseSender::Cache => MesiState::Shared,
-                    },
-                    data: data.unwrap(),
-                }));
+                if prefetch == Some(false) {
+                    self.prefetched.insert(block);
+                }
+                let state = self.protocol.on_read_fill(from, shared);
+                self.fill(block, Box::new(CacheLine { state, data: data.clone() }));
             },
 
-            bus::BusMessage::ReadExclusiveResponse { who, block, data }
-            if who == self.id && data.is_some() => {
-                self.maybe_flush();
-                self.cached_lines.insert(block, Box::new(CacheLine {
-                    state: MesiState::Modified,
-                    data: data.unwrap(),
-                }));
+            bus::BusMessage::ReadExclusiveResponse { who, tag: _, block, data: Some(ref data) }
+            if who == self.id => {
+                // Any copy in the victim buffer is superseded.
+                if let Some(ref mut victims) = self.victims {
+                    victims.remove(&block);
+                }
+                let state = self.protocol.on_exclusive_fill();
+                self.fill(block, Box::new(CacheLine { state, data: data.clone() }));
             },
 
-            // Snoop when other caches start reading cache lines that we have
-            // marked exclusive and set our local copy's state to shared.
-            bus::BusMessage::ReadResponse { who, from: _, block, data }
-            if who != self.id && data.is_some() => {
-                if let Some(cache_line) = self.cached_lines.get_mut(&block) {
-                    if cache_line.state == MesiState::Exclusive {
-                        cache_line.state = MesiState::Shared;
-                    }
-                }
+            // Snoop when other caches are sent cache lines that we hold.
+            bus::BusMessage::ReadResponse { who, tag: _, from: _, block, data: Some(_), shared: _ }
+            if who != self.id => {
+                self.snoop(who, None, block, Snoop::ReadResponse);
             },
 
             // Ignore our own requests.
-            bus::BusMessage::InvalidateRequest { who, block: _ } |
-            bus::BusMessage::ReadRequest { who, block: _ } |
-            bus::BusMessage::ReadExclusiveRequest { who, block: _ } => {
+            bus::BusMessage::ReadRequest { who, tag: _, block: _ } |
+            bus::BusMessage::ReadExclusiveRequest { who, tag: _, block: _ } => {
                 assert!(who == self.id);
             },
 
+            // Give up on prefetches that main memory could not answer.
+            bus::BusMessage::ReadResponse { who, tag: _, from: _, block, data: None, shared: _ }
+            if who == self.id => {
+                self.in_flight.remove(&block);
+            },
+
             // Ignore responses that aren't meant for us.
-            bus::BusMessage::ReadResponse { who, from: _, block: _, data } |
-            bus::BusMessage::ReadExclusiveResponse { who, block: _, data } => {
+            bus::BusMessage::ReadResponse { who, tag: _, from: _, block: _, ref data, shared: _ } |
+            bus::BusMessage::ReadExclusiveResponse { who, tag: _, block: _, ref data } => {
                 assert!(who != self.id || data.is_none());
             },
 
-            // Invalidation responses we care about are handled via a
-            // `snoop_until` call in `write`.
-            bus::BusMessage::InvalidateResponse { who: _, ok: _ } => { },
+            // Upgrade responses we care about are handled by `receive`, once
+            // we are done here.
+            bus::BusMessage::UpgradeResponse { who: _, tag: _, ok: _ } => { },
 
-            // Ignore writes, they are only for main memory.
-            bus::BusMessage::WriteRequest { block: _, data: _ } => { },
+            // Ignore writes and eviction notices, they are only for main
+            // memory and the directory.
+            bus::BusMessage::WriteRequest { block: _, data: _, evicted: _ } |
+            bus::BusMessage::PutClean { who: _, block: _ } => { },
         }
     }
 
-    /// Handle the backlog of unprocessed bus messages.
-    fn snoop_backlog(&mut self) {
-        while let Ok(msg) = self.from_bus.try_recv() {
-            self.handle_bus_message(&msg);
+    /// Handle a message the bus delivered to this cache: snoop on other
+    /// caches' requests, take in responses to our own, and carry on with the
+    /// processor's access waiting on the transaction it responds to, if any.
+    /// Returns the access' completion if it is now done.
+    pub fn receive(&mut self, msg: &bus::BusMessage) -> Option<Completion> {
+        self.handle_bus_message(msg);
+
+        let (tag, filled) = match *msg {
+            bus::BusMessage::ReadResponse { who, tag, from: _, block, ref data, shared: _ } |
+            bus::BusMessage::ReadExclusiveResponse { who, tag, block, ref data }
+            if who == self.id => (tag, data.as_ref().map(|_| block)),
+            bus::BusMessage::UpgradeResponse { who, tag, ok: _ }
+            if who == self.id => (tag, None),
+            _ => return None,
+        };
+
+        let done = self.pending.remove(&tag).and_then(|pending| self.advance(tag, pending, msg));
+        if let Some(block) = filled {
+            self.resolve_conflicts(block);
         }
+        done
     }
 
-    /// Keep snooping bus messages until `when` returns true.
-    fn snoop_until<F>(&mut self, mut when: F) where F: FnMut(&bus::BusMessage) -> bool {
-        loop {
-            let msg = self.from_bus.recv().expect("Error receiving bus message");
+    /// Carry on with the processor's access `pending`, which waits on
+    /// transaction `tag`, now that `msg` has responded to it. Returns the
+    /// access' completion if it is done, and otherwise leaves it waiting.
+    fn advance(&mut self, tag: bus::Tag, pending: Pending<P::State>, msg: &bus::BusMessage)
+               -> Option<Completion> {
+        let block_size = self.system.block_size;
+        match (pending, msg) {
+            (Pending::Read { address }, &bus::BusMessage::ReadResponse { who, tag, from: _, block, data: _, shared: _ })
+            if block == main_memory::Block::for_addr(address, block_size) => {
+                if self.holds_valid(block) {
+                    let value = self.finish_read(address, false);
+                    return Some(Completion { tag, value: Some(value) });
+                }
 
-            self.handle_bus_message(&msg);
+                // If we didn't get the cache line successfully, then another
+                // cache must have it in a dirty state. They will have snooped
+                // our read request and issued a write to main memory in
+                // response, so retry the read request.
+                self.request(block, bus::BusMessage::ReadRequest { who, tag, block });
+                self.pending.insert(tag, Pending::Read { address });
+                None
+            },
 
-            if when(&msg) {
-                return;
-            }
+            (Pending::Fill { address, value }, &bus::BusMessage::ReadResponse { who, tag, from: _, block, data: _, shared: _ })
+            if block == main_memory::Block::for_addr(address, block_size) => {
+                if self.holds_valid(block) {
+                    return match self.store(tag, address, value, true) {
+                        Some(_) => None,
+                        None => Some(Completion { tag, value: None }),
+                    };
+                }
+
+                // As for a read.
+                self.request(block, bus::BusMessage::ReadRequest { who, tag, block });
+                self.pending.insert(tag, Pending::Fill { address, value });
+                None
+            },
+
+            (Pending::Upgrade { address, value, fetched, mut line, ordered: _ },
+             &bus::BusMessage::UpgradeResponse { who, tag, ok }) => {
+                let block = main_memory::Block::for_addr(address, block_size);
+                if ok {
+                    self.upgrade_count += 1;
+                    line.state = self.protocol.on_upgrade();
+                    line.write_byte(address, value);
+                    self.fill(block, line);
+                    self.drop_lost_upgrades(block);
+                    self.resolve_conflicts(block);
+                    self.prefetch(block, !fetched);
+                    return Some(Completion { tag, value: None });
+                }
+
+                // Another cache was given the block to write before our
+                // upgrade was ordered. Our copy is stale, and we may have
+                // seen our own upgrade first, and put off invalidating it,
+                // so do so now. Fetch the block again.
+                let self_id = self.id;
+                self.snoop(self_id, None, block, Snoop::Invalidate);
+                self.lost_upgrade_count += 1;
+                self.exclusive_miss_count += 1;
+                if !fetched {
+                    self.count_miss(block);
+                }
+                self.request(block, bus::BusMessage::ReadExclusiveRequest { who, tag, block });
+                self.pending.insert(tag, Pending::ReadExclusive { address, value });
+                None
+            },
+
+            (Pending::ReadExclusive { address, value }, &bus::BusMessage::ReadExclusiveResponse { who, tag, block, data: _ })
+            if block == main_memory::Block::for_addr(address, block_size) => {
+                if let Some(cache_line) = self.cached_lines.peek_mut(&block) {
+                    if cache_line.state.is_writable() {
+                        cache_line.write_byte(address, value);
+                        self.prefetch(block, false);
+                        return Some(Completion { tag, value: None });
+                    }
+                }
+
+                // If we didn't get the cache line successfully, then another
+                // cache must have it in a dirty state. They will have snooped
+                // our read for exclusive access request and issued a write to
+                // main memory in response, so retry the request.
+                self.request(block, bus::BusMessage::ReadExclusiveRequest { who, tag, block });
+                self.pending.insert(tag, Pending::ReadExclusive { address, value });
+                None
+            },
+
+            (pending, _) => {
+                self.pending.insert(tag, pending);
+                None
+            },
         }
     }
 
-    /// Read the byte at the given address.
-    pub fn read(&mut self, addr: main_memory::Address) -> u8 {
+    /// Do we hold a valid copy of `block` in the hierarchy?
+    fn holds_valid(&self, block: main_memory::Block) -> bool {
+        self.cached_lines.peek(&block).is_some_and(|cache_line| cache_line.state.is_valid())
+    }
+
+    /// Do we raise the bus' shared line for another cache's update of
+    /// `block`? We do if we hold a valid copy of it, even in the victim
+    /// buffer, or are fetching it.
+    pub fn raises_shared_line(&self, block: main_memory::Block) -> bool {
+        self.holds_valid(block) ||
+            self.victims.as_ref()
+                .and_then(|victims| victims.peek(&block))
+                .is_some_and(|cache_line| cache_line.state.is_valid()) ||
+            self.fetching(block)
+    }
+
+    /// Can the processor make an access to `address` now? It must wait while
+    /// as many of its accesses as may wait on the bus at once already do, and
+    /// while an earlier access to the same block does, so that its accesses
+    /// to each block happen in order.
+    pub fn can_issue(&self, address: main_memory::Address) -> bool {
+        let block_size = self.system.block_size;
+        let block = main_memory::Block::for_addr(address, block_size);
+        self.pending.len() < self.outstanding &&
+            !self.pending.values().any(|pending| main_memory::Block::for_addr(pending.address(), block_size) == block)
+    }
+
+    /// Return the number of the processor's accesses waiting on the bus.
+    pub fn waiting(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Read the byte at the given address on behalf of the processor. If the
+    /// block misses, it is fetched over the bus, and the read waits for it.
+    pub fn read(&mut self, addr: main_memory::Address) -> Lookup {
+        assert!(self.can_issue(addr), "The processor must wait for a miss register");
         self.total_count += 1.0;
-        self.snoop_backlog();
 
-        let target_block = main_memory::Block::for_addr(addr);
+        let target_block = main_memory::Block::for_addr(addr, self.system.block_size);
+        let latency = self.access(target_block);
 
-        if let Some(cache_line) = self.cached_lines.get(&target_block) {
-            if cache_line.state != MesiState::Invalid {
-                return cache_line.read_byte(addr);
-            }
+        if self.holds_valid(target_block) {
+            let value = self.finish_read(addr, true);
+            return Lookup { latency, waiting: None, value: Some(value) };
         }
 
-        self.miss_count += 1.0;
+        self.count_miss(target_block);
+        // If the block is already being prefetched, wait for that read
+        // request's response rather than sending another.
+        let tag = match self.in_flight.get(&target_block) {
+            Some(&(tag, _)) => tag,
+            None => {
+                let tag = self.next_tag();
+                self.request(target_block, bus::BusMessage::ReadRequest { who: self.id, tag, block: target_block });
+                tag
+            },
+        };
+        self.pending.insert(tag, Pending::Read { address: addr });
+        Lookup { latency, waiting: Some(tag), value: None }
+    }
 
-        loop {
-            self.to_bus.send(bus::BusMessage::ReadRequest {
-                who: self.id,
-                block: target_block,
-            }).expect("Error sending to bus from memory cache");
+    /// Read the byte at the given address out of the block we now hold, which
+    /// `hit` says the read did or did not hit, and return it.
+    fn finish_read(&mut self, addr: main_memory::Address, hit: bool) -> u8 {
+        let target_block = main_memory::Block::for_addr(addr, self.system.block_size);
+        let value = self.cached_lines.peek(&target_block)
+            .expect("Fetched cache line should be present")
+            .read_byte(addr);
+        self.prefetch(target_block, hit);
+        value
+    }
 
-            let self_id = self.id;
-            self.snoop_until(|msg| match *msg {
-                bus::BusMessage::ReadResponse { who, from: _, block, data: _ } => {
-                    who == self_id && block == target_block
-                },
-                _ => false
-            });
+    /// Write the `value` to the given address on behalf of the processor. If
+    /// the block must first be fetched, or other copies of it invalidated, the
+    /// write waits for the bus.
+    pub fn write(&mut self, address: main_memory::Address, value: u8) -> Lookup {
+        assert!(self.can_issue(address), "The processor must wait for a miss register");
+        self.total_count += 1.0;
 
-            if let Some(cache_line) = self.cached_lines.get(&target_block) {
-                if cache_line.state != MesiState::Invalid {
-                    return cache_line.read_byte(addr);
-                }
-            }
+        let target_block = main_memory::Block::for_addr(address, self.system.block_size);
+        let latency = self.access(target_block);
 
-            // If we didn't get the cache line successfully, then another cache
-            // must have it in the Modified state. They will have snooped our
-            // read request and issued a write to main memory in response, so
-            // keep retrying the read request.
-        }
+        let tag = self.next_tag();
+        let waiting = self.store(tag, address, value, false);
+        Lookup { latency, waiting, value: None }
     }
 
-    fn try_invalidate(&mut self, block: main_memory::Block) -> Result<(), ()> {
-        self.to_bus.send(bus::BusMessage::InvalidateRequest {
-            who: self.id,
-            block: block,
-        }).expect("Error sending to bus from memory cache");
-
-        let self_id = self.id;
-        let mut responses_received = 0;
-        let mut invalidation_failed = false;
-        self.snoop_until(|msg| match *msg {
-            bus::BusMessage::InvalidateResponse { who, ok } if who == self_id => {
-                if !ok {
-                    invalidation_failed = true;
+    /// Write the `value` to the given address, or send what the protocol needs
+    /// first as transaction `tag`, fetching the block or invalidating other
+    /// copies of it, and wait for the response. `fetched` says whether the
+    /// write has already missed and fetched the block. Returns the transaction
+    /// the write waits on, or `None` if it is done.
+    fn store(&mut self, tag: bus::Tag, address: main_memory::Address, value: u8, fetched: bool) -> Option<bus::Tag> {
+        let target_block = main_memory::Block::for_addr(address, self.system.block_size);
+        let state = self.cached_lines.peek(&target_block)
+            .map_or_else(P::State::invalid, |cache_line| cache_line.state);
+
+        match self.protocol.on_write(state) {
+            WriteAction::Hit(state) => {
+                let cache_line = self.cached_lines.peek_mut(&target_block).unwrap();
+                cache_line.state = state;
+                cache_line.write_byte(address, value);
+            },
+            WriteAction::Update(state) => {
+                let cache_line = self.cached_lines.peek_mut(&target_block).unwrap();
+                cache_line.state = state;
+                cache_line.write_byte(address, value);
+                self.to_bus.send(bus::BusMessage::Update {
+                    who: self.id,
+                    address,
+                    value,
+                    shared: false,
+                });
+                *self.unechoed.entry(address).or_insert(0) += 1;
+            },
+            WriteAction::Upgrade => {
+                let line = self.cached_lines.peek(&target_block).cloned().unwrap();
+                self.request(target_block, bus::BusMessage::UpgradeRequest { who: self.id, tag, block: target_block });
+                self.pending.insert(tag, Pending::Upgrade { address, value, fetched, line, ordered: false });
+                return Some(tag);
+            },
+            WriteAction::Fill => {
+                if !fetched {
+                    self.count_miss(target_block);
                 }
-                responses_received += 1;
-                responses_received == NUMBER_OF_CACHES
+                // As for a read, wait for a prefetch's response if there is
+                // one.
+                let tag = match self.in_flight.get(&target_block) {
+                    Some(&(prefetch, _)) => prefetch,
+                    None => {
+                        self.request(target_block, bus::BusMessage::ReadRequest { who: self.id, tag, block: target_block });
+                        tag
+                    },
+                };
+                self.pending.insert(tag, Pending::Fill { address, value });
+                return Some(tag);
+            },
+            WriteAction::Miss => {
+                self.exclusive_miss_count += 1;
+                if !fetched {
+                    self.count_miss(target_block);
+                }
+                self.request(target_block, bus::BusMessage::ReadExclusiveRequest { who: self.id, tag, block: target_block });
+                self.pending.insert(tag, Pending::ReadExclusive { address, value });
+                return Some(tag);
             },
-            _ => false,
-        });
-
-        if invalidation_failed {
-            Err(())
-        } else {
-            Ok(())
         }
+
+        self.prefetch(target_block, !fetched);
+        None
     }
+}
 
-    /// Write the `value` to the given address.
-    pub fn write(&mut self, address: main_memory::Address, value: u8) {
-        self.total_count += 1.0;
-        self.snoop_backlog();
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use arbiter::{Arbiter, ArbiterKind};
+    use coherence::dragon::Dragon;
+    use coherence::mesi::Mesi;
+    use coherence::mesif::Mesif;
+    use coherence::moesi::Moesi;
+    use coherence::msi::Msi;
+    use dram::{AddressMapping, Dram, PagePolicy};
+    use engine::{Access, Cycle, Engine, Workload};
+    use network::{Network, NetworkKind};
+
+    /// Each processor makes its own list of accesses, in order, and the values
+    /// it reads are kept. Once it has made them all, if its cache upgraded the
+    /// block it wrote last, it must still hold it to write, with the value it
+    /// wrote: whatever other caches do to the block must wait for the upgrade.
+    struct Script {
+        accesses: Vec<Vec<Access>>,
+        written: Vec<Option<(main_memory::Address, u8)>>,
+        read: Vec<Vec<u8>>,
+    }
 
-        let target_block = main_memory::Block::for_addr(address);
+    impl Script {
+        fn new(accesses: Vec<Vec<Access>>) -> Script {
+            let written = vec![None; accesses.len()];
+            let read = vec![vec![]; accesses.len()];
+            Script { accesses, written, read }
+        }
+    }
 
-        let mut should_try_invalidate = None;
-        if let Some(cache_line) = self.cached_lines.get_mut(&target_block) {
-            match cache_line.state {
-                MesiState::Modified | MesiState::Exclusive => {
-                    cache_line.state = MesiState::Modified;
-                    cache_line.write_byte(address, value);
-                    return;
-                },
-                MesiState::Shared => {
-                    should_try_invalidate = Some(cache_line.clone());
-                },
-                MesiState::Invalid => { },
+    impl<P: CoherenceProtocol> Workload<P> for Script {
+        fn next_access(&mut self, id: MemoryCacheId) -> Option<Access> {
+            let accesses = &mut self.accesses[id as usize];
+            if accesses.is_empty() {
+                return None;
+            }
+            let access = accesses.remove(0);
+            if let Access::Write(address, value) = access {
+                self.written[id as usize] = Some((address, value));
             }
+            Some(access)
         }
 
-        if let Some(mut cache_line) = should_try_invalidate {
-            if let Ok(_) = self.try_invalidate(target_block) {
-                cache_line.state = MesiState::Modified;
-                cache_line.write_byte(address, value);
-                self.maybe_flush();
-                self.cached_lines.insert(target_block, cache_line);
+        fn on_read(&mut self, id: MemoryCacheId, _address: main_memory::Address, value: u8) {
+            self.read[id as usize].push(value);
+        }
+
+        fn finish(&mut self, cache: &mut MemoryCache<P>, _now: Cycle) {
+            if cache.upgrades() == 0 {
                 return;
             }
+            if let Some((address, value)) = self.written[cache.id as usize] {
+                let block = main_memory::Block::for_addr(address, cache.system.block_size);
+                let cache_line = cache.cached_lines.peek(&block)
+                    .unwrap_or_else(|| panic!("Cache {} lost the block it upgraded", cache.id));
+                assert!(cache_line.state.is_writable());
+                assert_eq!(cache_line.read_byte(address), value);
+            }
         }
+    }
 
-        self.miss_count += 1.0;
+    /// A machine of `caches` default caches following `protocol`, on a
+    /// snooping bus carried by a network of the given `kind`.
+    fn machine<P>(protocol: P, kind: NetworkKind, caches: usize) -> Engine<P, bus::Broadcast>
+        where P: CoherenceProtocol + Clone
+    {
+        let system = SystemConfig { number_of_caches: caches, ..SystemConfig::default() };
+        let network = Network::new(kind, &system);
+        let stats = Rc::new(bus::BusStats::new(caches, network.links()));
+        let topology = bus::Broadcast::new(&system, None);
+        let bus = bus::Bus::new(&system, topology, Arbiter::new(ArbiterKind::Fifo, 0), network, stats);
+        let dram = Dram::new(PagePolicy::Open, AddressMapping::RowInterleaved, &system);
+        let memory_stats = Rc::new(main_memory::MemoryStats::new(system.blocks()));
+        let memory = main_memory::MainMemory::new(&system, dram, None, Inclusion::NonInclusive, 0, memory_stats);
+        let caches = (0..caches)
+            .map(|id| MemoryCache::new(id as MemoryCacheId, &system, protocol.clone(), CacheConfig::default(), 0))
+            .collect();
+        Engine::new(bus, memory, caches, None)
+    }
 
-        loop {
-            self.to_bus.send(bus::BusMessage::ReadExclusiveRequest {
-                who: self.id,
-                block: target_block,
-            }).expect("Error sending message to bus from memory cache");
+    /// Have each of `caches` caches on a network of the given `kind` read the
+    /// same block in turn, so that they all share it, and then all write it at
+    /// once, each a different value, so that their upgrades race. Only one
+    /// upgrade may succeed, and the others must be retried, leaving a single
+    /// cache holding the block, with the value it wrote.
+    fn race_upgrades(kind: NetworkKind, caches: usize) {
+        let mut engine = machine(Mesi, kind, caches);
+        let address = main_memory::Address(0);
+        let block = main_memory::Block::for_addr(address, engine.caches()[0].system.block_size);
+        for reader in 0..caches {
+            let mut reads = vec![vec![]; caches];
+            reads[reader].push(Access::Read(address));
+            engine.run(&mut Script::new(reads));
+        }
+        engine.run(&mut Script::new((0..caches).map(|id| vec![Access::Write(address, id as u8 + 1)]).collect()));
+
+        let holders: Vec<_> = engine.caches().iter()
+            .filter(|cache| cache.holds_valid(block))
+            .collect();
+        assert_eq!(holders.len(), 1, "Caches holding the block on a {}", kind.name());
+        let cache_line = holders[0].cached_lines.peek(&block).unwrap();
+        assert!(cache_line.state.is_writable());
+        assert_eq!(cache_line.read_byte(address), holders[0].id + 1);
+
+        let upgrades: usize = engine.caches().iter().map(|cache| cache.upgrades()).sum();
+        let lost: usize = engine.caches().iter().map(|cache| cache.lost_upgrades()).sum();
+        assert_eq!((upgrades, lost), (1, caches - 1));
+    }
 
-            let self_id = self.id;
-            self.snoop_until(|msg| match *msg {
-                bus::BusMessage::ReadExclusiveResponse { who, block, data: _ } => {
-                    who == self_id && block == target_block
-                },
-                _ => false
-            });
+    #[test]
+    fn racing_upgrades_leave_a_single_writer_on_a_ring() {
+        race_upgrades(NetworkKind::Ring, 2);
+        race_upgrades(NetworkKind::Ring, 4);
+    }
 
-            if let Some(cache_line) = self.cached_lines.get_mut(&target_block) {
-                if cache_line.state == MesiState::Modified {
-                    cache_line.write_byte(address, value);
-                    return;
-                }
-            }
+    #[test]
+    fn racing_upgrades_leave_a_single_writer_on_a_mesh() {
+        race_upgrades(NetworkKind::Mesh, 4);
+        race_upgrades(NetworkKind::Mesh, 5);
+    }
+
+    /// Run each processor's `accesses` on `engine`, and return what each read.
+    fn run<P: CoherenceProtocol>(engine: &mut Engine<P, bus::Broadcast>, accesses: Vec<Vec<Access>>) -> Vec<Vec<u8>> {
+        let mut script = Script::new(accesses);
+        engine.run(&mut script);
+        script.read
+    }
+
+    /// On three caches following `protocol`, on a network of the given
+    /// `kind`, have one cache write a byte and the others then read it, and
+    /// again with another writer and a byte the readers already hold. Then
+    /// have a cache write a byte others share twice, and read it back at once.
+    fn reads_see_writes<P>(protocol: P, kind: NetworkKind)
+        where P: CoherenceProtocol + Clone
+    {
+        let mut engine = machine(protocol, kind, 3);
+        let address = main_memory::Address(5);
+        let read = vec![Access::Read(address)];
+
+        run(&mut engine, vec![vec![Access::Write(address, 42)], vec![], vec![]]);
+        assert_eq!(run(&mut engine, vec![vec![], read.clone(), read.clone()]), vec![vec![], vec![42], vec![42]]);
+
+        run(&mut engine, vec![vec![], vec![], vec![Access::Write(address, 7)]]);
+        assert_eq!(run(&mut engine, vec![read.clone(), read.clone(), vec![]]), vec![vec![7], vec![7], vec![]]);
+
+        let writes = vec![Access::Write(address, 1), Access::Write(address, 2), Access::Read(address),
+                          Access::Read(address), Access::Read(address)];
+        assert_eq!(run(&mut engine, vec![writes, vec![], vec![]])[0], vec![2, 2, 2]);
+        assert_eq!(run(&mut engine, vec![vec![], read.clone(), read]), vec![vec![], vec![2], vec![2]],
+                   "Reads after two writes on a {}", kind.name());
+    }
 
-            // If we didn't get the cache line successfully, then another cache
-            // must have it in the Modified state. They will have snooped our
-            // read for exclusive access request and issued a write to main
-            // memory in response, so keep retrying the request.
+    #[test]
+    fn reads_see_other_caches_writes() {
+        for &kind in &[NetworkKind::Bus, NetworkKind::Ring] {
+            reads_see_writes(Dragon, kind);
+            reads_see_writes(Mesi, kind);
+            reads_see_writes(Mesif, kind);
+            reads_see_writes(Moesi, kind);
+            reads_see_writes(Msi, kind);
         }
     }
 }
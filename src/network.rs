@@ -0,0 +1,310 @@
+//! The physical interconnect that carries the bus' messages: either a single
+//! shared bus, or a point-to-point network of links between routers, like the
+//! networks-on-chip that tie the cores of a large chip together.
+//!
+//! Every endpoint sits at its own node of the network: main memory at node
+//! zero, then each cache in order of id, as in `bus::Endpoint::all`. A message
+//! crosses one link per hop, taking the hop latency to do so, and holds each
+//! link it crosses for as long as it takes to push its bytes through at the
+//! link's bandwidth. Messages that need a busy link wait for it.
+//!
+//! A point-to-point network has no single wire that puts messages in order, so
+//! messages are ordered as they enter the network, as if by a global
+//! sequencer, and each endpoint receives them in that order. Coherence works
+//! as on the bus, with whatever delay the links add.
+
+use bus::Endpoint;
+use engine::Cycle;
+use system::SystemConfig;
+
+/// The number of bytes of every message that are not a block's data: its
+/// kind, requester, transaction and address.
+pub const HEADER_SIZE: usize = 8;
+
+/// The interconnects a machine can be built with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetworkKind {
+    /// A single bus, which carries one message at a time to every endpoint at
+    /// once, as its arbiter and the bus' timings say.
+    Bus,
+
+    /// A bidirectional ring through every endpoint in turn, each message going
+    /// the shorter way round.
+    Ring,
+
+    /// A 2D mesh of routers, as close to square as fits every endpoint, filled
+    /// row by row. Each message goes along its row first, then its column.
+    Mesh,
+
+    /// A crossbar switch, with a link into it from every endpoint, and one out
+    /// of it to every endpoint. Each message crosses two links.
+    Crossbar,
+}
+
+impl NetworkKind {
+    /// Every interconnect.
+    pub const ALL: [NetworkKind; 4] = [
+        NetworkKind::Bus,
+        NetworkKind::Ring,
+        NetworkKind::Mesh,
+        NetworkKind::Crossbar,
+    ];
+
+    /// Parse an interconnect from its name.
+    pub fn parse(name: &str) -> Result<NetworkKind, String> {
+        let lowercase = name.to_lowercase();
+        NetworkKind::ALL.iter()
+            .find(|kind| kind.name() == lowercase)
+            .cloned()
+            .ok_or_else(|| {
+                let names: Vec<_> = NetworkKind::ALL.iter().map(|kind| kind.name()).collect();
+                format!("Unknown network: {}\nExpected one of: {}", name, names.join(", "))
+            })
+    }
+
+    /// Get this interconnect's name.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            NetworkKind::Bus => "bus",
+            NetworkKind::Ring => "ring",
+            NetworkKind::Mesh => "mesh",
+            NetworkKind::Crossbar => "crossbar",
+        }
+    }
+}
+
+/// How one message crossed the network to one endpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Traversal {
+    /// The cycle it arrives at.
+    pub arrival: Cycle,
+    /// The number of links it crossed.
+    pub hops: usize,
+    /// The number of cycles it spent waiting for busy links.
+    pub stalled: Cycle,
+}
+
+/// The links of a point-to-point network, and when each is next free.
+pub struct Network {
+    kind: NetworkKind,
+    nodes: usize,
+    columns: usize,
+    links: usize,
+    hop_latency: Cycle,
+    link_bandwidth: usize,
+    // When each link is next free, by the link's number. The numbering depends
+    // on the kind of network, and may skip some numbers.
+    free: Vec<Cycle>,
+}
+
+impl Network {
+    /// Create the interconnect `kind` for the given `system`.
+    pub fn new(kind: NetworkKind, system: &SystemConfig) -> Network {
+        let nodes = system.number_of_caches + 1;
+        let columns = (1..).find(|columns| columns * columns >= nodes).unwrap();
+        let rows = nodes.div_ceil(columns);
+
+        // Links a ring numbers clockwise from each node, then anticlockwise;
+        // a mesh each router's east, west, south and north link in turn; and
+        // a crossbar each endpoint's link in, then each one's link out. A
+        // mesh's last row may be partial, so only count the links between the
+        // routers it has: each one's and its east neighbour's, and each one's
+        // and its south neighbour's.
+        let (links, numbers) = match kind {
+            NetworkKind::Bus => (1, 0),
+            NetworkKind::Ring => (2 * nodes, 2 * nodes),
+            NetworkKind::Mesh => {
+                let across = (0..nodes - 1).filter(|node| (node + 1) % columns != 0).count();
+                let down = nodes.saturating_sub(columns);
+                (2 * (across + down), 4 * rows * columns)
+            },
+            NetworkKind::Crossbar => (2 * nodes, 2 * nodes),
+        };
+
+        Network {
+            kind,
+            nodes,
+            columns,
+            links,
+            hop_latency: system.hop_latency as Cycle,
+            link_bandwidth: system.link_bandwidth,
+            free: vec![0; numbers],
+        }
+    }
+
+    /// Get the kind of interconnect.
+    pub fn kind(&self) -> NetworkKind {
+        self.kind
+    }
+
+    /// Get the number of links, each carrying messages one way. A bus counts
+    /// as a single link.
+    pub fn links(&self) -> usize {
+        self.links
+    }
+
+    /// Get the number of cycles a message of `bytes` bytes holds each link it
+    /// crosses for.
+    pub fn serialization(&self, bytes: usize) -> Cycle {
+        bytes.div_ceil(self.link_bandwidth) as Cycle
+    }
+
+    /// Send a message of `bytes` bytes from `from` to `to` over a
+    /// point-to-point network, entering it at cycle `now`. Each link it
+    /// crosses is busy with it until the whole message has gone through.
+    pub fn send(&mut self, from: Endpoint, to: Endpoint, bytes: usize, now: Cycle) -> Traversal {
+        let path = self.route(from.index(), to.index());
+        let serialization = self.serialization(bytes);
+
+        let mut at = now;
+        let mut stalled = 0;
+        for &link in &path {
+            let start = at.max(self.free[link]);
+            stalled += start - at;
+            self.free[link] = start + serialization;
+            at = start + self.hop_latency;
+        }
+
+        Traversal {
+            // The rest of the message follows its first bytes in.
+            arrival: if path.is_empty() { now } else { at + serialization },
+            hops: path.len(),
+            stalled,
+        }
+    }
+
+    /// Get the numbers of the links a message crosses from node `from` to
+    /// node `to`, in order.
+    fn route(&self, from: usize, to: usize) -> Vec<usize> {
+        if from == to {
+            return vec![];
+        }
+
+        match self.kind {
+            NetworkKind::Bus => unreachable!("A bus has no links to route over"),
+
+            NetworkKind::Ring => {
+                let n = self.nodes;
+                let clockwise = (to + n - from) % n;
+                if clockwise <= n - clockwise {
+                    (0..clockwise).map(|hop| (from + hop) % n).collect()
+                } else {
+                    (0..n - clockwise).map(|hop| n + (from + n - hop) % n).collect()
+                }
+            },
+
+            NetworkKind::Mesh => {
+                let (mut x, mut y) = (from % self.columns, from / self.columns);
+                let (to_x, to_y) = (to % self.columns, to / self.columns);
+                // Along X, then along Y, unless the last row is partial and
+                // the corner is past its end. Then along Y first, since every
+                // row above the last is full.
+                let y_first = y * self.columns + to_x >= self.nodes;
+                let mut path = vec![];
+                while (x, y) != (to_x, to_y) {
+                    let router = y * self.columns + x;
+                    let along_x = x != to_x && !(y_first && y != to_y);
+                    let direction = if along_x && x < to_x {
+                        x += 1;
+                        0
+                    } else if along_x {
+                        x -= 1;
+                        1
+                    } else if y < to_y {
+                        y += 1;
+                        2
+                    } else {
+                        y -= 1;
+                        3
+                    };
+                    path.push(4 * router + direction);
+                }
+                path
+            },
+
+            NetworkKind::Crossbar => vec![from, self.nodes + to],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The interconnect `kind` joining main memory and the given number of
+    /// caches.
+    fn network(kind: NetworkKind, caches: usize) -> Network {
+        let system = SystemConfig {
+            number_of_caches: caches,
+            ..SystemConfig::default()
+        };
+        Network::new(kind, &system)
+    }
+
+    #[test]
+    fn ring_takes_the_shorter_way_round() {
+        let ring = network(NetworkKind::Ring, 4);
+        assert_eq!(ring.links(), 10);
+        assert_eq!(ring.route(0, 2), vec![0, 1]);
+        // Anticlockwise links are numbered after the clockwise ones.
+        assert_eq!(ring.route(0, 3), vec![5, 9]);
+        assert_eq!(ring.route(0, 4), vec![5]);
+        assert_eq!(ring.route(3, 0), vec![3, 4]);
+        assert!(ring.route(2, 2).is_empty());
+    }
+
+    #[test]
+    fn crossbar_is_one_link_in_and_one_out() {
+        let crossbar = network(NetworkKind::Crossbar, 4);
+        assert_eq!(crossbar.links(), 10);
+        for from in 0..5 {
+            for to in (0..5).filter(|&to| to != from) {
+                assert_eq!(crossbar.route(from, to), vec![from, 5 + to]);
+            }
+        }
+    }
+
+    #[test]
+    fn mesh_routes_around_a_partial_last_row() {
+        // Five nodes in a 3x2 grid, with no router at (2, 1).
+        let mesh = network(NetworkKind::Mesh, 4);
+        assert_eq!(mesh.links(), 10);
+        // North from router 4 to 1, then east to 2, rather than through the
+        // missing router.
+        assert_eq!(mesh.route(4, 2), vec![4 * 4 + 3, 4]);
+        // West from router 2 to 1, then south to 4.
+        assert_eq!(mesh.route(2, 4), vec![4 * 2 + 1, 4 + 2]);
+    }
+
+    #[test]
+    fn mesh_routes_take_the_shortest_path_over_routers_that_exist() {
+        for caches in 1..16 {
+            let mesh = network(NetworkKind::Mesh, caches);
+            let nodes = caches + 1;
+            let columns = mesh.columns;
+            let mut used = vec![false; 4 * nodes.div_ceil(columns) * columns];
+
+            for from in 0..nodes {
+                for to in 0..nodes {
+                    let path = mesh.route(from, to);
+                    let distance = (from % columns).abs_diff(to % columns) + (from / columns).abs_diff(to / columns);
+                    assert_eq!(path.len(), distance, "{} caches: {} to {}", caches, from, to);
+
+                    for &link in &path {
+                        let router = link / 4;
+                        let next = match link % 4 {
+                            0 => router + 1,
+                            1 => router - 1,
+                            2 => router + columns,
+                            _ => router - columns,
+                        };
+                        assert!(router < nodes && next < nodes, "{} caches: {} to {} crosses {}", caches, from, to, link);
+                        used[link] = true;
+                    }
+                }
+            }
+            // Every link is on some route.
+            assert_eq!(used.iter().filter(|&&used| used).count(), mesh.links(), "{} caches", caches);
+        }
+    }
+}
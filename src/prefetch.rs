@@ -0,0 +1,353 @@
+//! Hardware prefetchers, which watch the blocks a cache's processor accesses
+//! and guess which blocks it will access next.
+//!
+//! A prefetcher only makes guesses. The cache it is attached to decides which
+//! guesses are worth fetching, and fetches them with ordinary read requests,
+//! so prefetched lines take part in coherence like any other.
+
+use main_memory;
+
+/// Guesses which blocks a cache's processor will access next.
+pub trait Prefetcher: Send {
+    /// The processor accessed `block`, which `hit` says was or was not
+    /// already held by the cache. Returns the blocks to prefetch, most urgent
+    /// first.
+    fn on_access(&mut self, block: main_memory::Block, hit: bool) -> Vec<main_memory::Block>;
+}
+
+/// The prefetchers a cache can be constructed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrefetcherKind {
+    /// Never prefetch.
+    None,
+
+    /// On every access, prefetch the `degree` blocks following it.
+    NextLine {
+        /// How many blocks to prefetch.
+        degree: usize,
+    },
+
+    /// Without knowing which instruction made an access, detect a constant
+    /// stride between consecutively accessed blocks, and once it has been seen
+    /// twice in a row, prefetch the `degree` blocks further along it.
+    Stride {
+        /// How many blocks to prefetch.
+        degree: usize,
+    },
+
+    /// Track up to `streams` streams of misses to consecutive blocks, in
+    /// either direction, like a set of stream buffers. A miss that continues a
+    /// stream runs `depth` blocks ahead of it.
+    Stream {
+        /// How many streams to track at once.
+        streams: usize,
+        /// How far ahead of a stream to prefetch.
+        depth: usize,
+    },
+}
+
+/// The degree a prefetcher is built with, unless configured otherwise.
+pub const DEFAULT_DEGREE: usize = 2;
+
+/// The number of streams a stream prefetcher tracks, unless configured
+/// otherwise.
+pub const DEFAULT_STREAMS: usize = 4;
+
+impl PrefetcherKind {
+    /// Parse a prefetcher from its name, optionally followed by a colon and
+    /// its degree: `none`, `next-line[:<degree>]`, `stride[:<degree>]`, or
+    /// `stream[:<depth>]`.
+    pub fn parse(name: &str) -> Result<PrefetcherKind, String> {
+        let error = || format!("Unknown prefetcher: {}\nExpected one of: none, next-line[:<degree>], stride[:<degree>], stream[:<depth>]",
+                               name);
+
+        let mut parts = name.splitn(2, ':');
+        let kind = parts.next().unwrap().to_lowercase();
+        let degree = match parts.next() {
+            Some(degree) => match degree.parse::<usize>() {
+                Ok(degree) if degree > 0 => degree,
+                _ => return Err(error()),
+            },
+            None => DEFAULT_DEGREE,
+        };
+
+        match &kind[..] {
+            "none" => Ok(PrefetcherKind::None),
+            "next-line" => Ok(PrefetcherKind::NextLine { degree }),
+            "stride" => Ok(PrefetcherKind::Stride { degree }),
+            "stream" => Ok(PrefetcherKind::Stream { streams: DEFAULT_STREAMS, depth: degree }),
+            _ => Err(error()),
+        }
+    }
+
+    /// Get this prefetcher's name.
+    pub fn name(&self) -> String {
+        match *self {
+            PrefetcherKind::None => "none".to_string(),
+            PrefetcherKind::NextLine { degree } => format!("next-{}-line", degree),
+            PrefetcherKind::Stride { degree } => format!("stride (degree {})", degree),
+            PrefetcherKind::Stream { streams, depth } => format!("{} streams (depth {})", streams, depth),
+        }
+    }
+
+    /// Create the state this prefetcher needs, or `None` if it never
+    /// prefetches.
+    pub fn build(&self) -> Option<Box<dyn Prefetcher>> {
+        match *self {
+            PrefetcherKind::None => None,
+            PrefetcherKind::NextLine { degree } => Some(Box::new(NextLine { degree })),
+            PrefetcherKind::Stride { degree } => Some(Box::new(Stride::new(degree))),
+            PrefetcherKind::Stream { streams, depth } => Some(Box::new(Stream::new(streams, depth))),
+        }
+    }
+}
+
+/// Counts of how useful a cache's prefetches were.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrefetchStats {
+    /// The number of read requests sent for prefetches.
+    pub issued: usize,
+
+    /// The number of prefetched lines the processor went on to access.
+    pub useful: usize,
+
+    /// The number of times the processor missed on a block whose prefetch
+    /// was still in flight.
+    pub late: usize,
+
+    /// The number of prefetched lines evicted before the processor accessed
+    /// them, each of which took the place of a line that might have been.
+    pub evicted_unused: usize,
+
+    /// The number of prefetched lines invalidated by another cache before the
+    /// processor accessed them. Each is a copy the other cache had to
+    /// invalidate for nothing.
+    pub invalidated_unused: usize,
+}
+
+impl PrefetchStats {
+    /// The percent of issued prefetches the processor went on to access,
+    /// whether or not they arrived in time.
+    pub fn accuracy_percent(&self) -> f64 {
+        percent(self.useful + self.late, self.issued)
+    }
+
+    /// The percent of would-be misses that prefetching turned into hits,
+    /// given how many `misses` remained.
+    pub fn coverage_percent(&self, misses: usize) -> f64 {
+        percent(self.useful, self.useful + misses)
+    }
+}
+
+/// `part` as a percent of `whole`, or zero if `whole` is zero.
+fn percent(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64 * 100.0
+    }
+}
+
+/// The `count` blocks after `block`, `stride` blocks apart, stopping at the
+/// start of memory. The cache ignores any past the end of memory.
+fn along(block: main_memory::Block, stride: isize, count: usize) -> Vec<main_memory::Block> {
+    (1..count as isize + 1)
+        .map(|step| block.0 as isize + stride * step)
+        .take_while(|&next| 0 <= next)
+        .map(|next| main_memory::Block(next as usize))
+        .collect()
+}
+
+/// No state at all.
+struct NextLine {
+    degree: usize,
+}
+
+impl Prefetcher for NextLine {
+    fn on_access(&mut self, block: main_memory::Block, _: bool) -> Vec<main_memory::Block> {
+        along(block, 1, self.degree)
+    }
+}
+
+/// The last block accessed, and the stride from the one before it.
+struct Stride {
+    degree: usize,
+    last: Option<main_memory::Block>,
+    stride: isize,
+}
+
+impl Stride {
+    fn new(degree: usize) -> Stride {
+        Stride { degree, last: None, stride: 0 }
+    }
+}
+
+impl Prefetcher for Stride {
+    fn on_access(&mut self, block: main_memory::Block, _: bool) -> Vec<main_memory::Block> {
+        let last = match self.last {
+            // Accesses within the same block say nothing about the stride.
+            Some(last) if last == block => return vec![],
+            Some(last) => last,
+            None => {
+                self.last = Some(block);
+                return vec![];
+            },
+        };
+
+        let stride = block.0 as isize - last.0 as isize;
+        let confirmed = stride == self.stride;
+        self.stride = stride;
+        self.last = Some(block);
+
+        if confirmed {
+            along(block, stride, self.degree)
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// One tracked stream: the last block it missed on, and which way it is
+/// heading, once known.
+#[derive(Clone, Copy)]
+struct StreamEntry {
+    last: main_memory::Block,
+    direction: Option<isize>,
+    last_used: u64,
+}
+
+/// A table of streams, replaced least recently used first.
+struct Stream {
+    depth: usize,
+    capacity: usize,
+    clock: u64,
+    streams: Vec<StreamEntry>,
+}
+
+impl Stream {
+    fn new(capacity: usize, depth: usize) -> Stream {
+        Stream { depth, capacity, clock: 0, streams: Vec::with_capacity(capacity) }
+    }
+}
+
+impl Prefetcher for Stream {
+    fn on_access(&mut self, block: main_memory::Block, hit: bool) -> Vec<main_memory::Block> {
+        // Streams are trained on misses, and hits in the blocks they already
+        // prefetched keep them running ahead.
+        self.clock += 1;
+        let clock = self.clock;
+
+        // Accesses within a block a stream is already at say nothing new.
+        if self.streams.iter().any(|entry| entry.last == block) {
+            return vec![];
+        }
+
+        let continues = |entry: &StreamEntry, direction: isize| {
+            entry.direction.is_none_or(|d| d == direction) &&
+                block.0 as isize == entry.last.0 as isize + direction
+        };
+
+        for entry in &mut self.streams {
+            let direction = if continues(entry, 1) {
+                1
+            } else if continues(entry, -1) {
+                -1
+            } else {
+                continue;
+            };
+
+            entry.last = block;
+            entry.direction = Some(direction);
+            entry.last_used = clock;
+            return along(block, direction, self.depth);
+        }
+
+        if hit {
+            return vec![];
+        }
+
+        let entry = StreamEntry { last: block, direction: None, last_used: clock };
+        if self.streams.len() < self.capacity {
+            self.streams.push(entry);
+        } else {
+            let oldest = (0..self.streams.len()).min_by_key(|&i| self.streams[i].last_used).unwrap();
+            self.streams[oldest] = entry;
+        }
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tell `prefetcher` about an access to `block`, which `hit` says did or
+    /// did not hit, and return the blocks it guesses.
+    fn guesses(prefetcher: &mut dyn Prefetcher, block: usize, hit: bool) -> Vec<usize> {
+        prefetcher.on_access(main_memory::Block(block), hit).iter().map(|block| block.0).collect()
+    }
+
+    #[test]
+    fn stride_prefetches_once_a_stride_repeats() {
+        let mut stride = Stride::new(2);
+        assert!(guesses(&mut stride, 10, false).is_empty());
+        assert!(guesses(&mut stride, 13, false).is_empty());
+        assert_eq!(guesses(&mut stride, 16, false), vec![19, 22]);
+        // Another access within the same block does not break the stride.
+        assert!(guesses(&mut stride, 16, true).is_empty());
+        assert_eq!(guesses(&mut stride, 19, true), vec![22, 25]);
+
+        // A new stride must be seen twice in a row too.
+        assert!(guesses(&mut stride, 23, false).is_empty());
+        assert_eq!(guesses(&mut stride, 27, false), vec![31, 35]);
+    }
+
+    #[test]
+    fn stride_stops_at_the_start_of_memory() {
+        let mut stride = Stride::new(2);
+        assert!(guesses(&mut stride, 6, false).is_empty());
+        assert!(guesses(&mut stride, 4, false).is_empty());
+        assert_eq!(guesses(&mut stride, 2, false), vec![0]);
+    }
+
+    #[test]
+    fn stream_trains_on_misses_and_runs_ahead_on_hits() {
+        let mut stream = Stream::new(2, 2);
+        assert!(guesses(&mut stream, 10, false).is_empty());
+        assert_eq!(guesses(&mut stream, 11, false), vec![12, 13]);
+        assert_eq!(guesses(&mut stream, 12, true), vec![13, 14]);
+        assert!(guesses(&mut stream, 12, true).is_empty());
+    }
+
+    #[test]
+    fn stream_keeps_to_its_direction() {
+        let mut stream = Stream::new(2, 2);
+        assert!(guesses(&mut stream, 50, false).is_empty());
+        assert_eq!(guesses(&mut stream, 49, false), vec![48, 47]);
+        // Going back up starts a stream of its own.
+        assert!(guesses(&mut stream, 50, false).is_empty());
+        assert_eq!(guesses(&mut stream, 51, false), vec![52, 53]);
+        assert_eq!(guesses(&mut stream, 48, false), vec![47, 46]);
+    }
+
+    #[test]
+    fn stream_hits_do_not_allocate() {
+        let mut stream = Stream::new(2, 2);
+        assert!(guesses(&mut stream, 100, true).is_empty());
+        assert!(guesses(&mut stream, 101, false).is_empty());
+        assert_eq!(guesses(&mut stream, 102, false), vec![103, 104]);
+    }
+
+    #[test]
+    fn stream_replaces_the_least_recently_used() {
+        let mut stream = Stream::new(2, 2);
+        assert!(guesses(&mut stream, 10, false).is_empty());
+        assert!(guesses(&mut stream, 50, false).is_empty());
+        assert_eq!(guesses(&mut stream, 11, false), vec![12, 13]);
+
+        // The stream at 50 was used least recently, so it makes way.
+        assert!(guesses(&mut stream, 200, false).is_empty());
+        assert_eq!(guesses(&mut stream, 12, true), vec![13, 14]);
+        assert!(guesses(&mut stream, 51, false).is_empty());
+    }
+}
@@ -0,0 +1,426 @@
+//! Replacement policies, which choose the way of a full cache set to evict.
+//!
+//! Each set has its own policy instance tracking the ways of that set only.
+//! Ways are numbered `0..ways`, and the policy is told whenever a way is
+//! filled, accessed by the processor, or invalidated. Policies that draw
+//! random numbers each draw their own generator when they are built, so that
+//! a run replays exactly for the same seed.
+
+extern crate rand;
+use self::rand::{Rng, XorShiftRng};
+
+/// Chooses which way of a full cache set to evict.
+pub trait ReplacementPolicy: Send {
+    /// A new block was stored in `way`.
+    fn on_fill(&mut self, way: usize);
+
+    /// The processor read or wrote the block in `way`.
+    fn on_hit(&mut self, way: usize);
+
+    /// The block in `way` was removed, leaving it empty.
+    fn on_invalidate(&mut self, _way: usize) { }
+
+    /// Choose a way to evict. Only called when every way is full.
+    fn victim(&mut self) -> usize;
+}
+
+/// The replacement policies a cache can be constructed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplacementKind {
+    /// Evict the least recently used way.
+    Lru,
+
+    /// Evict the way that was filled longest ago, regardless of use.
+    Fifo,
+
+    /// Evict a way at random.
+    Random,
+
+    /// Approximate LRU with a binary tree of bits, one per internal node,
+    /// each pointing away from the most recently used half below it.
+    TreePlru,
+
+    /// Not recently used: evict a way whose reference bit is clear.
+    Nru,
+
+    /// Static re-reference interval prediction: new blocks are predicted to
+    /// be re-referenced in the distant future, and hits promote them to the
+    /// near future.
+    Srrip,
+
+    /// Bimodal re-reference interval prediction: like SRRIP, but new blocks
+    /// are usually predicted to be re-referenced in the distant future, which
+    /// resists thrashing by working sets larger than the cache.
+    Brrip,
+}
+
+impl ReplacementKind {
+    /// Every replacement policy.
+    pub const ALL: [ReplacementKind; 7] = [
+        ReplacementKind::Lru,
+        ReplacementKind::Fifo,
+        ReplacementKind::Random,
+        ReplacementKind::TreePlru,
+        ReplacementKind::Nru,
+        ReplacementKind::Srrip,
+        ReplacementKind::Brrip,
+    ];
+
+    /// Parse a replacement policy from its name.
+    pub fn parse(name: &str) -> Result<ReplacementKind, String> {
+        let lowercase = name.to_lowercase();
+        ReplacementKind::ALL.iter()
+            .find(|kind| kind.name().to_lowercase() == lowercase)
+            .cloned()
+            .ok_or_else(|| {
+                let names: Vec<_> = ReplacementKind::ALL.iter().map(|kind| kind.name().to_lowercase()).collect();
+                format!("Unknown replacement policy: {}\nExpected one of: {}", name, names.join(", "))
+            })
+    }
+
+    /// Get this policy's name.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ReplacementKind::Lru => "LRU",
+            ReplacementKind::Fifo => "FIFO",
+            ReplacementKind::Random => "Random",
+            ReplacementKind::TreePlru => "Tree-PLRU",
+            ReplacementKind::Nru => "NRU",
+            ReplacementKind::Srrip => "SRRIP",
+            ReplacementKind::Brrip => "BRRIP",
+        }
+    }
+
+    /// Create the state this policy needs for one set with the given number
+    /// of ways. A random policy draws its generator from `rng`.
+    pub fn build<R: Rng>(&self, ways: usize, rng: &mut R) -> Box<dyn ReplacementPolicy> {
+        match *self {
+            ReplacementKind::Lru => Box::new(Lru::new(ways)),
+            ReplacementKind::Fifo => Box::new(Fifo::new(ways)),
+            ReplacementKind::Random => Box::new(Random { ways, rng: rng.gen() }),
+            ReplacementKind::TreePlru => Box::new(TreePlru::new(ways)),
+            ReplacementKind::Nru => Box::new(Nru::new(ways)),
+            ReplacementKind::Srrip => Box::new(Rrip::new(ways, false, rng.gen())),
+            ReplacementKind::Brrip => Box::new(Rrip::new(ways, true, rng.gen())),
+        }
+    }
+}
+
+/// Counts of how often a cache's replacement policy was consulted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplacementStats {
+    /// The number of blocks stored.
+    pub fills: usize,
+
+    /// The number of blocks evicted to make room for another.
+    pub evictions: usize,
+}
+
+/// Track the time each way was last used.
+struct Lru {
+    clock: u64,
+    last_used: Vec<u64>,
+}
+
+impl Lru {
+    fn new(ways: usize) -> Lru {
+        Lru { clock: 0, last_used: vec![0; ways] }
+    }
+
+    fn touch(&mut self, way: usize) {
+        self.clock += 1;
+        self.last_used[way] = self.clock;
+    }
+}
+
+impl ReplacementPolicy for Lru {
+    fn on_fill(&mut self, way: usize) {
+        self.touch(way);
+    }
+
+    fn on_hit(&mut self, way: usize) {
+        self.touch(way);
+    }
+
+    fn victim(&mut self) -> usize {
+        oldest(&self.last_used)
+    }
+}
+
+/// Track the time each way was filled.
+struct Fifo {
+    clock: u64,
+    filled: Vec<u64>,
+}
+
+impl Fifo {
+    fn new(ways: usize) -> Fifo {
+        Fifo { clock: 0, filled: vec![0; ways] }
+    }
+}
+
+impl ReplacementPolicy for Fifo {
+    fn on_fill(&mut self, way: usize) {
+        self.clock += 1;
+        self.filled[way] = self.clock;
+    }
+
+    fn on_hit(&mut self, _: usize) { }
+
+    fn victim(&mut self) -> usize {
+        oldest(&self.filled)
+    }
+}
+
+/// The way with the smallest timestamp.
+fn oldest(times: &[u64]) -> usize {
+    (0..times.len()).min_by_key(|&way| times[way]).unwrap()
+}
+
+/// Only a random number generator.
+struct Random {
+    ways: usize,
+    rng: XorShiftRng,
+}
+
+impl ReplacementPolicy for Random {
+    fn on_fill(&mut self, _: usize) { }
+
+    fn on_hit(&mut self, _: usize) { }
+
+    fn victim(&mut self) -> usize {
+        self.rng.gen_range(0, self.ways)
+    }
+}
+
+/// A complete binary tree of `ways - 1` bits, stored heap-style: node `n` has
+/// children `2n + 1` and `2n + 2`, and the ways are its leaves. A clear bit
+/// points to the left subtree, and a set bit to the right.
+struct TreePlru {
+    ways: usize,
+    bits: Vec<bool>,
+}
+
+impl TreePlru {
+    fn new(ways: usize) -> TreePlru {
+        assert!(ways.is_power_of_two(), "Tree-PLRU needs a power of two ways");
+        TreePlru { ways, bits: vec![false; ways - 1] }
+    }
+
+    /// Point every node on the path to `way` away from it.
+    fn touch(&mut self, way: usize) {
+        let mut node = 0;
+        let mut low = 0;
+        let mut size = self.ways;
+        while size > 1 {
+            size /= 2;
+            let right = way >= low + size;
+            self.bits[node] = !right;
+            if right {
+                low += size;
+                node = 2 * node + 2;
+            } else {
+                node = 2 * node + 1;
+            }
+        }
+    }
+}
+
+impl ReplacementPolicy for TreePlru {
+    fn on_fill(&mut self, way: usize) {
+        self.touch(way);
+    }
+
+    fn on_hit(&mut self, way: usize) {
+        self.touch(way);
+    }
+
+    fn victim(&mut self) -> usize {
+        let mut node = 0;
+        let mut low = 0;
+        let mut size = self.ways;
+        while size > 1 {
+            size /= 2;
+            if self.bits[node] {
+                low += size;
+                node = 2 * node + 2;
+            } else {
+                node = 2 * node + 1;
+            }
+        }
+        low
+    }
+}
+
+/// One reference bit per way, set on every use. Once every bit is set, they
+/// are all cleared except for the way just used.
+struct Nru {
+    referenced: Vec<bool>,
+}
+
+impl Nru {
+    fn new(ways: usize) -> Nru {
+        Nru { referenced: vec![false; ways] }
+    }
+
+    fn touch(&mut self, way: usize) {
+        self.referenced[way] = true;
+        if self.referenced.iter().all(|&r| r) {
+            for r in &mut self.referenced {
+                *r = false;
+            }
+            self.referenced[way] = true;
+        }
+    }
+}
+
+impl ReplacementPolicy for Nru {
+    fn on_fill(&mut self, way: usize) {
+        self.touch(way);
+    }
+
+    fn on_hit(&mut self, way: usize) {
+        self.touch(way);
+    }
+
+    fn on_invalidate(&mut self, way: usize) {
+        self.referenced[way] = false;
+    }
+
+    fn victim(&mut self) -> usize {
+        self.referenced.iter().position(|&r| !r).unwrap_or(0)
+    }
+}
+
+/// The largest re-reference prediction value of a 2-bit RRIP counter, meaning
+/// the block is predicted to be re-referenced in the distant future.
+const DISTANT_RRPV: u8 = 3;
+
+/// How often, out of this many fills, BRRIP predicts a long rather than a
+/// distant re-reference interval.
+const BRRIP_LONG_FILL_ODDS: u32 = 32;
+
+/// A re-reference prediction value per way, and for BRRIP, a random number
+/// generator, like `Random`'s.
+struct Rrip {
+    bimodal: bool,
+    rrpv: Vec<u8>,
+    rng: XorShiftRng,
+}
+
+impl Rrip {
+    fn new(ways: usize, bimodal: bool, rng: XorShiftRng) -> Rrip {
+        Rrip { bimodal, rrpv: vec![DISTANT_RRPV; ways], rng }
+    }
+}
+
+impl ReplacementPolicy for Rrip {
+    fn on_fill(&mut self, way: usize) {
+        let long = !self.bimodal || self.rng.gen_range(0, BRRIP_LONG_FILL_ODDS) == 0;
+        self.rrpv[way] = if long { DISTANT_RRPV - 1 } else { DISTANT_RRPV };
+    }
+
+    fn on_hit(&mut self, way: usize) {
+        self.rrpv[way] = 0;
+    }
+
+    fn on_invalidate(&mut self, way: usize) {
+        self.rrpv[way] = DISTANT_RRPV;
+    }
+
+    fn victim(&mut self) -> usize {
+        loop {
+            if let Some(way) = self.rrpv.iter().position(|&rrpv| rrpv == DISTANT_RRPV) {
+                return way;
+            }
+            for rrpv in &mut self.rrpv {
+                *rrpv += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::rand::{SeedableRng, StdRng};
+
+    /// A policy of the given kind for a 4-way set, with every way filled in
+    /// order.
+    fn filled(kind: ReplacementKind, seed: usize) -> Box<dyn ReplacementPolicy> {
+        let mut policy = kind.build(4, &mut StdRng::from_seed(&[seed][..]));
+        for way in 0..4 {
+            policy.on_fill(way);
+        }
+        policy
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_way() {
+        let mut policy = filled(ReplacementKind::Lru, 0);
+        policy.on_hit(0);
+        assert_eq!(policy.victim(), 1);
+        policy.on_fill(1);
+        assert_eq!(policy.victim(), 2);
+        policy.on_hit(2);
+        assert_eq!(policy.victim(), 3);
+    }
+
+    #[test]
+    fn fifo_evicts_the_oldest_fill_regardless_of_use() {
+        let mut policy = filled(ReplacementKind::Fifo, 0);
+        policy.on_hit(0);
+        assert_eq!(policy.victim(), 0);
+        policy.on_fill(0);
+        assert_eq!(policy.victim(), 1);
+    }
+
+    #[test]
+    fn tree_plru_follows_the_bits_away_from_recent_use() {
+        let mut policy = filled(ReplacementKind::TreePlru, 0);
+        assert_eq!(policy.victim(), 0);
+        // The root now points at the right half, whose bit points at way 2.
+        policy.on_hit(0);
+        assert_eq!(policy.victim(), 2);
+        // Back to the left half, whose bit still points away from way 0.
+        policy.on_hit(2);
+        assert_eq!(policy.victim(), 1);
+    }
+
+    #[test]
+    fn nru_evicts_the_first_way_not_recently_used() {
+        // Filling the last way set every bit, so all but its own were cleared.
+        let mut policy = filled(ReplacementKind::Nru, 0);
+        assert_eq!(policy.victim(), 0);
+        policy.on_hit(0);
+        assert_eq!(policy.victim(), 1);
+        policy.on_invalidate(3);
+        policy.on_hit(1);
+        policy.on_hit(2);
+        assert_eq!(policy.victim(), 3);
+    }
+
+    #[test]
+    fn srrip_ages_every_way_until_one_is_distant() {
+        let mut policy = filled(ReplacementKind::Srrip, 0);
+        assert_eq!(policy.victim(), 0);
+        policy.on_fill(0);
+        policy.on_hit(1);
+        assert_eq!(policy.victim(), 2);
+        policy.on_fill(2);
+        assert_eq!(policy.victim(), 3);
+        // Way 1 was hit, so it is aged the least.
+        policy.on_fill(3);
+        assert_eq!(policy.victim(), 0);
+    }
+
+    #[test]
+    fn random_replays_its_seed() {
+        let victims = |seed| {
+            let mut policy = filled(ReplacementKind::Random, seed);
+            (0..32).map(|_| policy.victim()).collect::<Vec<_>>()
+        };
+        assert_eq!(victims(1), victims(1));
+        assert_ne!(victims(1), victims(2));
+    }
+}
@@ -0,0 +1,280 @@
+//! Set-associative storage for cache lines.
+//!
+//! A block's address is split into a tag and a set index: the low
+//! `index_bits` of the block number select the set the block must live in,
+//! and the remaining high bits are the tag that identifies it among the ways
+//! of that set. A direct-mapped cache has one way per set, and a fully
+//! associative cache has a single set. Which way of a full set is evicted is
+//! left to a `ReplacementPolicy`.
+
+extern crate rand;
+use self::rand::{SeedableRng, StdRng};
+
+use main_memory;
+use replacement::{ReplacementKind, ReplacementPolicy, ReplacementStats};
+
+/// The shape of a set-associative cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheGeometry {
+    /// The number of sets. Always a power of two.
+    pub sets: usize,
+    /// The number of ways in each set.
+    pub ways: usize,
+}
+
+impl CacheGeometry {
+    /// A cache with the given number of sets, each with the given number of
+    /// ways.
+    pub fn new(sets: usize, ways: usize) -> CacheGeometry {
+        assert!(sets.is_power_of_two(), "The number of sets must be a power of two");
+        assert!(ways > 0, "There must be at least one way per set");
+        CacheGeometry { sets, ways }
+    }
+
+    /// A cache holding `capacity` blocks, each set having the given number of
+    /// ways.
+    pub fn with_ways(capacity: usize, ways: usize) -> CacheGeometry {
+        assert!(capacity.is_multiple_of(ways), "The number of ways must evenly divide the capacity");
+        CacheGeometry::new(capacity / ways, ways)
+    }
+
+    /// A fully associative cache holding `capacity` blocks.
+    pub fn fully_associative(capacity: usize) -> CacheGeometry {
+        CacheGeometry::new(1, capacity)
+    }
+
+    /// A direct-mapped cache holding `capacity` blocks.
+    pub fn direct_mapped(capacity: usize) -> CacheGeometry {
+        CacheGeometry::new(capacity, 1)
+    }
+
+    /// Parse the number of ways per set of a cache holding `capacity` blocks,
+    /// which must evenly divide it, or `full` for a fully associative cache.
+    pub fn parse(ways: &str, capacity: usize) -> Result<CacheGeometry, String> {
+        if ways.eq_ignore_ascii_case("full") {
+            return Ok(CacheGeometry::fully_associative(capacity));
+        }
+
+        match ways.parse::<usize>() {
+            Ok(ways) if ways > 0 && capacity.is_multiple_of(ways) && (capacity / ways).is_power_of_two() => {
+                Ok(CacheGeometry::with_ways(capacity, ways))
+            },
+            _ => Err(format!("Invalid number of ways: {}\nExpected full, or a power of two from 1 (direct-mapped) to {} (fully associative)",
+                             ways, capacity)),
+        }
+    }
+
+    /// The total number of blocks the cache can hold.
+    pub fn capacity(&self) -> usize {
+        self.sets * self.ways
+    }
+
+    /// The number of address bits that select a set.
+    pub fn index_bits(&self) -> u32 {
+        self.sets.trailing_zeros()
+    }
+
+    /// The set the given block must be stored in.
+    pub fn set_index(&self, block: main_memory::Block) -> usize {
+        block.0 & (self.sets - 1)
+    }
+
+    /// The tag identifying the given block within its set.
+    pub fn tag(&self, block: main_memory::Block) -> usize {
+        block.0 >> self.index_bits()
+    }
+
+    /// The block with the given tag in the given set.
+    pub fn block(&self, set_index: usize, tag: usize) -> main_memory::Block {
+        main_memory::Block((tag << self.index_bits()) | set_index)
+    }
+}
+
+/// One way of a set, holding a block's value.
+struct Way<V> {
+    tag: usize,
+    value: V,
+}
+
+/// The ways of one set, and the replacement policy's state for them.
+struct Set<V> {
+    ways: Vec<Option<Way<V>>>,
+    policy: Box<dyn ReplacementPolicy>,
+}
+
+/// A map from blocks to values, with a set-associative geometry. When a set is
+/// full, inserting another block into it evicts the block its replacement
+/// policy chooses.
+pub struct SetAssociative<V> {
+    geometry: CacheGeometry,
+    replacement: ReplacementKind,
+    seed: Vec<usize>,
+    sets: Vec<Set<V>>,
+    len: usize,
+    stats: ReplacementStats,
+}
+
+impl<V> SetAssociative<V> {
+    /// Create empty storage with the given geometry and replacement policy. A
+    /// random policy draws each set's random numbers from the given `seed`,
+    /// so that a run replays exactly.
+    pub fn new(geometry: CacheGeometry, replacement: ReplacementKind, seed: &[usize]) -> SetAssociative<V> {
+        let mut storage = SetAssociative {
+            geometry,
+            replacement,
+            seed: seed.to_vec(),
+            sets: Vec::with_capacity(geometry.sets),
+            len: 0,
+            stats: ReplacementStats::default(),
+        };
+        storage.clear();
+        storage
+    }
+
+    /// Get this storage's geometry.
+    pub fn geometry(&self) -> CacheGeometry {
+        self.geometry
+    }
+
+    /// Get this storage's replacement policy.
+    pub fn replacement(&self) -> ReplacementKind {
+        self.replacement
+    }
+
+    /// Get the replacement statistics recorded since the last reset.
+    pub fn stats(&self) -> ReplacementStats {
+        self.stats
+    }
+
+    /// Reset the replacement statistics.
+    pub fn reset_stats(&mut self) {
+        self.stats = ReplacementStats::default();
+    }
+
+    /// Remove every block, and forget the replacement policy's history. Its
+    /// random numbers start over from the seed.
+    pub fn clear(&mut self) {
+        let geometry = self.geometry;
+        let replacement = self.replacement;
+        let mut rng = StdRng::from_seed(&self.seed[..]);
+        self.sets = (0..geometry.sets).map(|_| Set {
+            ways: (0..geometry.ways).map(|_| None).collect(),
+            policy: replacement.build(geometry.ways, &mut rng),
+        }).collect();
+        self.len = 0;
+    }
+
+    /// The number of blocks stored.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Is nothing stored?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Every block stored.
+    pub fn blocks(&self) -> Vec<main_memory::Block> {
+        self.sets.iter().enumerate()
+            .flat_map(|(index, set)| {
+                set.ways.iter().flatten().map(move |way| self.geometry.block(index, way.tag))
+            })
+            .collect()
+    }
+
+    fn position(&self, block: &main_memory::Block) -> (usize, Option<usize>) {
+        let index = self.geometry.set_index(*block);
+        let tag = self.geometry.tag(*block);
+        let way = self.sets[index].ways.iter()
+            .position(|way| way.as_ref().is_some_and(|way| way.tag == tag));
+        (index, way)
+    }
+
+    /// Get the value stored for `block`, as an access by the processor that
+    /// the replacement policy takes into account.
+    pub fn get(&mut self, block: &main_memory::Block) -> Option<&V> {
+        self.get_mut(block).map(|value| &*value)
+    }
+
+    /// Get the value stored for `block` mutably, as an access by the processor
+    /// that the replacement policy takes into account.
+    pub fn get_mut(&mut self, block: &main_memory::Block) -> Option<&mut V> {
+        match self.position(block) {
+            (index, Some(way)) => {
+                let set = &mut self.sets[index];
+                set.policy.on_hit(way);
+                set.ways[way].as_mut().map(|way| &mut way.value)
+            },
+            (_, None) => None,
+        }
+    }
+
+    /// Get the value stored for `block`, without the replacement policy
+    /// noticing. Used for snooping and bookkeeping.
+    pub fn peek(&self, block: &main_memory::Block) -> Option<&V> {
+        match self.position(block) {
+            (index, Some(way)) => self.sets[index].ways[way].as_ref().map(|way| &way.value),
+            (_, None) => None,
+        }
+    }
+
+    /// Get the value stored for `block` mutably, without the replacement
+    /// policy noticing. Used for snooping and bookkeeping.
+    pub fn peek_mut(&mut self, block: &main_memory::Block) -> Option<&mut V> {
+        match self.position(block) {
+            (index, Some(way)) => self.sets[index].ways[way].as_mut().map(|way| &mut way.value),
+            (_, None) => None,
+        }
+    }
+
+    /// Store `value` for `block`. Returns the block and value that had to be
+    /// evicted to make room for it, if any.
+    pub fn insert(&mut self, block: main_memory::Block, value: V) -> Option<(main_memory::Block, V)> {
+        let tag = self.geometry.tag(block);
+        let (index, way) = self.position(&block);
+        let geometry = self.geometry;
+        let set = &mut self.sets[index];
+
+        if let Some(way) = way {
+            set.policy.on_hit(way);
+            set.ways[way] = Some(Way { tag, value });
+            return None;
+        }
+
+        self.stats.fills += 1;
+
+        let mut evicted = None;
+        let way = match set.ways.iter().position(Option::is_none) {
+            Some(empty) => {
+                self.len += 1;
+                empty
+            },
+            None => {
+                let victim = set.policy.victim();
+                let old = set.ways[victim].take().unwrap();
+                evicted = Some((geometry.block(index, old.tag), old.value));
+                self.stats.evictions += 1;
+                victim
+            },
+        };
+
+        set.ways[way] = Some(Way { tag, value });
+        set.policy.on_fill(way);
+
+        evicted
+    }
+
+    /// Remove the value stored for `block`, if any.
+    pub fn remove(&mut self, block: &main_memory::Block) -> Option<V> {
+        match self.position(block) {
+            (index, Some(way)) => {
+                let set = &mut self.sets[index];
+                set.policy.on_invalidate(way);
+                self.len -= 1;
+                set.ways[way].take().map(|way| way.value)
+            },
+            (_, None) => None,
+        }
+    }
+}
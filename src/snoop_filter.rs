@@ -0,0 +1,460 @@
+//! Snoop filters, which let a snooping bus skip delivering a message about a
+//! block to the caches that cannot hold it.
+//!
+//! A filter learns which caches may hold each block from the messages on the
+//! bus, just as a directory does, and only ever errs towards delivering too
+//! much: caches drop clean blocks without telling anyone, unless configured
+//! to announce it, so the filter may think a cache still holds a block it has
+//! dropped. Caches with a request for a block in flight always see every
+//! write to it, so that they never fill a stale copy.
+
+extern crate bit_vec;
+
+use bus::{self, BusMessage, Endpoint};
+use main_memory::{self, Block};
+use memory_cache::MemoryCacheId;
+use replacement::ReplacementKind;
+use set_associative::{CacheGeometry, SetAssociative};
+use system::SystemConfig;
+
+/// The number of ways in each set of an inclusive filter, or fewer if it has
+/// fewer entries.
+pub const INCLUSIVE_WAYS: usize = 8;
+
+/// The snoop filters a snooping bus can be built with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnoopFilterKind {
+    /// A filter in front of each cache, counting the blocks the cache may hold
+    /// in each of `entries` buckets, chosen by block number. A cache is sent
+    /// messages about every block that shares a bucket with one it may hold.
+    Presence {
+        /// The number of buckets per cache.
+        entries: usize,
+    },
+
+    /// A single filter by the bus, tracking exactly which caches hold each of
+    /// up to `entries` blocks, and holding every block any cache does. Making
+    /// room for another block back-invalidates the one it evicts from every
+    /// cache holding it.
+    Inclusive {
+        /// The number of blocks the filter can track. A power of two.
+        entries: usize,
+    },
+}
+
+impl SnoopFilterKind {
+    /// Parse a snoop filter from its name: `presence<n>` for a presence filter
+    /// of `n` entries per cache, or `inclusive<n>` for an inclusive filter of
+    /// `n` entries.
+    pub fn parse(name: &str) -> Result<SnoopFilterKind, String> {
+        let number = |digits: &str| -> Result<usize, String> {
+            match digits.parse() {
+                Ok(n) if n > 0 => Ok(n),
+                _ => Err(format!("Expected a positive number of entries in snoop filter: {}", name)),
+            }
+        };
+
+        let name = name.to_lowercase();
+        if let Some(entries) = name.strip_prefix("presence") {
+            Ok(SnoopFilterKind::Presence { entries: number(entries)? })
+        } else if let Some(entries) = name.strip_prefix("inclusive") {
+            let entries = number(entries)?;
+            if !entries.is_power_of_two() {
+                return Err(format!("Invalid number of entries in snoop filter: {}\nExpected a power of two", name));
+            }
+            Ok(SnoopFilterKind::Inclusive { entries })
+        } else {
+            Err(format!("Unknown snoop filter: {}\nExpected one of: presence<n>, inclusive<n>", name))
+        }
+    }
+
+    /// Describe the filter, for reporting.
+    pub fn name(&self) -> String {
+        match *self {
+            SnoopFilterKind::Presence { entries } => format!("presence filter of {} entries per cache", entries),
+            SnoopFilterKind::Inclusive { entries } => format!("inclusive filter of {} entries", entries),
+        }
+    }
+}
+
+/// What a filter stores about which caches may hold each block.
+enum Storage {
+    /// Per cache, the number of blocks it may hold in each bucket. Exactly
+    /// which blocks it may hold is also kept, for the counts to stay right
+    /// as blocks come and go, and to measure how imprecise they are.
+    Presence {
+        counts: Vec<Vec<usize>>,
+        present: Vec<bit_vec::BitVec>,
+    },
+
+    /// The caches that may hold each tracked block.
+    Inclusive(SetAssociative<bit_vec::BitVec>),
+}
+
+/// A snoop filter, deciding which caches a snooping bus delivers each message
+/// to.
+pub struct SnoopFilter {
+    kind: SnoopFilterKind,
+    caches: usize,
+    block_size: usize,
+    storage: Storage,
+    // Per block, the caches with a request for it in flight.
+    requesters: Vec<bit_vec::BitVec>,
+}
+
+impl SnoopFilter {
+    /// Create an empty filter of the given kind for the given system.
+    pub fn new(kind: SnoopFilterKind, system: &SystemConfig) -> SnoopFilter {
+        let caches = system.number_of_caches;
+        let storage = match kind {
+            SnoopFilterKind::Presence { entries } => Storage::Presence {
+                counts: vec![vec![0; entries]; caches],
+                present: vec![bit_vec::BitVec::from_elem(caches, false); system.blocks()],
+            },
+            SnoopFilterKind::Inclusive { entries } => {
+                let geometry = CacheGeometry::with_ways(entries, INCLUSIVE_WAYS.min(entries));
+                // LRU draws no random numbers, so needs no seed.
+                Storage::Inclusive(SetAssociative::new(geometry, ReplacementKind::Lru, &[]))
+            },
+        };
+
+        SnoopFilter {
+            kind,
+            caches,
+            block_size: system.block_size,
+            storage,
+            requesters: vec![bit_vec::BitVec::from_elem(caches, false); system.blocks()],
+        }
+    }
+
+    /// Get the kind of filter.
+    pub fn kind(&self) -> SnoopFilterKind {
+        self.kind
+    }
+
+    /// Route a message sent over the bus, delivering it only where it is
+    /// needed: requests and other messages about a block to main memory and
+    /// every cache that may hold it, responses to their requester, and
+    /// write-backs and eviction notices to main memory alone.
+    pub fn route(&mut self, msg: BusMessage, stats: &bus::BusStats) -> Vec<(Endpoint, BusMessage)> {
+        // Every message a bus without a filter would have delivered, to every
+        // endpoint.
+        let unfiltered = self.caches + 1;
+        let mut deliveries = vec![];
+        let mut recalls = vec![];
+
+        match msg {
+            BusMessage::ReadRequest { who, tag: _, block } => {
+                deliveries.extend(self.holders(block, Some(who), stats));
+                deliveries.push(Endpoint::MainMemory);
+                self.requesters[block.0].set(who as usize, true);
+            },
+
+            // Other caches holding the block snoop it being sent.
+            BusMessage::ReadResponse { who, tag: _, from: _, block, ref data, shared: _ } => {
+                self.requesters[block.0].set(who as usize, false);
+                deliveries.extend(self.holders(block, Some(who), stats));
+                deliveries.push(Endpoint::Cache(who));
+                if data.is_some() {
+                    recalls.extend(self.add(block, who));
+                }
+            },
+
+            BusMessage::ReadExclusiveRequest { who, tag: _, block } => {
+                deliveries.extend(self.writes_to(block, Some(who), stats));
+                deliveries.push(Endpoint::MainMemory);
+                self.clear(block);
+                self.requesters[block.0].set(who as usize, true);
+            },
+
+            BusMessage::ReadExclusiveResponse { who, tag: _, block, ref data } => {
+                self.requesters[block.0].set(who as usize, false);
+                deliveries.push(Endpoint::Cache(who));
+                if data.is_some() {
+                    recalls.extend(self.add(block, who));
+                }
+            },
+
+            // A cache writing back a block it evicted no longer holds it.
+            BusMessage::WriteRequest { block, data: _, evicted } => {
+                if let Some(who) = evicted {
+                    self.remove(block, who);
+                }
+                deliveries.push(Endpoint::MainMemory);
+            },
+
+            // Whether or not the upgrade succeeds, every other copy is gone.
+            BusMessage::UpgradeRequest { who, tag: _, block } => {
+                deliveries.extend(self.writes_to(block, Some(who), stats));
+                // The requester learns when its upgrade is ordered after
+                // others.
+                deliveries.push(Endpoint::Cache(who));
+                deliveries.push(Endpoint::MainMemory);
+                self.clear(block);
+                recalls.extend(self.add(block, who));
+            },
+
+            BusMessage::UpgradeResponse { who, tag: _, ok: _ } => {
+                deliveries.push(Endpoint::Cache(who));
+            },
+
+            BusMessage::Update { who, address, value: _, shared: _ } => {
+                let block = main_memory::Block::for_addr(address, self.block_size);
+                deliveries.extend(self.writes_to(block, Some(who), stats));
+                // The writer learns when its update is ordered after others.
+                deliveries.push(Endpoint::Cache(who));
+                deliveries.push(Endpoint::MainMemory);
+                recalls.extend(self.add(block, who));
+            },
+
+            BusMessage::PutClean { who, block } => {
+                self.remove(block, who);
+                deliveries.push(Endpoint::MainMemory);
+            },
+
+            // Main memory's last-level cache is taking the block back from
+            // every cache holding it.
+            BusMessage::BackInvalidate { block } => {
+                deliveries.extend(self.writes_to(block, None, stats));
+                self.clear(block);
+            },
+        }
+
+        let deliveries = deliveries.into_iter().map(|endpoint| (endpoint, msg.clone())).collect();
+        self.deliver(deliveries, recalls, unfiltered, stats)
+    }
+
+    /// Add a back-invalidation of each block in `recalls` to `deliveries`,
+    /// sent to every cache the filter had to forget holds it, and record what
+    /// the filter saved.
+    fn deliver(&self,
+               mut deliveries: Vec<(Endpoint, BusMessage)>,
+               recalls: Vec<(Block, Vec<Endpoint>)>,
+               unfiltered: usize,
+               stats: &bus::BusStats)
+               -> Vec<(Endpoint, BusMessage)> {
+        stats.record_unfiltered(unfiltered);
+        for (block, holders) in recalls {
+            let recall = BusMessage::BackInvalidate { block };
+            stats.record(&recall);
+            stats.record_filter_back_invalidation();
+            deliveries.extend(holders.into_iter().map(|endpoint| (endpoint, recall.clone())));
+        }
+        deliveries
+    }
+
+    /// Every cache other than `except`, if any, that must be sent a write to
+    /// `block`: those that may hold it, and those with a request for it in
+    /// flight.
+    fn writes_to(&self, block: Block, except: Option<MemoryCacheId>, stats: &bus::BusStats) -> Vec<Endpoint> {
+        let mut targets = self.holders(block, except, stats);
+        for (id, requesting) in self.requesters[block.0].iter().enumerate() {
+            let endpoint = Endpoint::Cache(id as MemoryCacheId);
+            if requesting && Some(id as MemoryCacheId) != except && !targets.contains(&endpoint) {
+                targets.push(endpoint);
+            }
+        }
+        targets
+    }
+
+    /// Every cache other than `except`, if any, that the filter says may hold
+    /// `block`. Records each one that was only included due to the filter's
+    /// imprecision in `stats`.
+    fn holders(&self, block: Block, except: Option<MemoryCacheId>, stats: &bus::BusStats) -> Vec<Endpoint> {
+        (0..self.caches as MemoryCacheId)
+            .filter(|&id| Some(id) != except)
+            .filter(|&id| match self.storage {
+                Storage::Presence { ref counts, ref present } => {
+                    let hit = counts[id as usize][block.0 % counts[id as usize].len()] > 0;
+                    if hit && !present[block.0][id as usize] {
+                        stats.record_extra_delivery();
+                    }
+                    hit
+                },
+                Storage::Inclusive(ref entries) => {
+                    entries.peek(&block).is_some_and(|holders| holders[id as usize])
+                },
+            })
+            .map(Endpoint::Cache)
+            .collect()
+    }
+
+    /// Note that `who` may now hold `block`. Returns the block the filter had
+    /// to forget to make room, if any, along with every cache that must be
+    /// sent a back-invalidation of it.
+    fn add(&mut self, block: Block, who: MemoryCacheId) -> Option<(Block, Vec<Endpoint>)> {
+        let caches = self.caches;
+        let evicted = match self.storage {
+            Storage::Presence { ref mut counts, ref mut present } => {
+                if !present[block.0][who as usize] {
+                    present[block.0].set(who as usize, true);
+                    let bucket = block.0 % counts[who as usize].len();
+                    counts[who as usize][bucket] += 1;
+                }
+                return None;
+            },
+            Storage::Inclusive(ref mut entries) => {
+                if let Some(holders) = entries.get_mut(&block) {
+                    holders.set(who as usize, true);
+                    return None;
+                }
+                let mut holders = bit_vec::BitVec::from_elem(caches, false);
+                holders.set(who as usize, true);
+                entries.insert(block, holders)?
+            },
+        };
+
+        // Caches fetching the evicted block must drop it once it arrives.
+        let (victim, holders) = evicted;
+        let mut targets: Vec<_> = holders.iter().enumerate()
+            .filter(|&(_, holds)| holds)
+            .map(|(id, _)| Endpoint::Cache(id as MemoryCacheId))
+            .collect();
+        for (id, requesting) in self.requesters[victim.0].iter().enumerate() {
+            if requesting && !holders[id] {
+                targets.push(Endpoint::Cache(id as MemoryCacheId));
+            }
+        }
+        Some((victim, targets))
+    }
+
+    /// Note that `who` no longer holds `block`.
+    fn remove(&mut self, block: Block, who: MemoryCacheId) {
+        match self.storage {
+            Storage::Presence { ref mut counts, ref mut present } => {
+                if present[block.0][who as usize] {
+                    present[block.0].set(who as usize, false);
+                    let bucket = block.0 % counts[who as usize].len();
+                    counts[who as usize][bucket] -= 1;
+                }
+            },
+            Storage::Inclusive(ref mut entries) => {
+                let empty = match entries.peek_mut(&block) {
+                    Some(holders) => {
+                        holders.set(who as usize, false);
+                        holders.none()
+                    },
+                    None => false,
+                };
+                if empty {
+                    entries.remove(&block);
+                }
+            },
+        }
+    }
+
+    /// Note that no cache holds `block` any more.
+    fn clear(&mut self, block: Block) {
+        for id in 0..self.caches as MemoryCacheId {
+            self.remove(block, id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CACHES: usize = 4;
+
+    /// An empty filter of the given kind for `CACHES` caches, with statistics
+    /// to record what it does.
+    fn empty(kind: SnoopFilterKind) -> (SnoopFilter, bus::BusStats) {
+        let system = SystemConfig { number_of_caches: CACHES, ..SystemConfig::default() };
+        (SnoopFilter::new(kind, &system), bus::BusStats::new(CACHES, 1))
+    }
+
+    /// Have cache `who` read `block` from main memory, and return the
+    /// back-invalidations the filter sent to make room for it.
+    fn fill(filter: &mut SnoopFilter, stats: &bus::BusStats, who: MemoryCacheId, block: usize)
+            -> Vec<(Endpoint, BusMessage)> {
+        let block = Block(block);
+        filter.route(BusMessage::ReadRequest { who, tag: bus::Tag(0), block }, stats);
+        let response = BusMessage::ReadResponse {
+            who,
+            tag: bus::Tag(0),
+            from: bus::ResponseSender::MainMemory,
+            block,
+            data: Some(vec![0; 4]),
+            shared: false,
+        };
+        filter.route(response, stats).into_iter()
+            .filter(|(_, msg)| msg.kind() == bus::MessageKind::BackInvalidate)
+            .collect()
+    }
+
+    /// Where a read request from cache `who` for `block` is delivered.
+    fn read(filter: &mut SnoopFilter, stats: &bus::BusStats, who: MemoryCacheId, block: usize) -> Vec<Endpoint> {
+        filter.route(BusMessage::ReadRequest { who, tag: bus::Tag(0), block: Block(block) }, stats)
+            .into_iter()
+            .map(|(endpoint, _)| endpoint)
+            .collect()
+    }
+
+    /// A write-back of `block`, by cache `evicted` if it let go of it.
+    fn write_back(block: usize, evicted: Option<MemoryCacheId>) -> BusMessage {
+        BusMessage::WriteRequest { block: Block(block), data: vec![0; 4], evicted }
+    }
+
+    #[test]
+    fn requests_skip_caches_without_the_block() {
+        for &kind in &[SnoopFilterKind::Presence { entries: 16 }, SnoopFilterKind::Inclusive { entries: 16 }] {
+            let (mut filter, stats) = empty(kind);
+            fill(&mut filter, &stats, 0, 3);
+            stats.reset();
+
+            assert_eq!(read(&mut filter, &stats, 1, 3), vec![Endpoint::Cache(0), Endpoint::MainMemory]);
+            assert_eq!(read(&mut filter, &stats, 1, 4), vec![Endpoint::MainMemory]);
+            // Without the filter, both would have gone to every endpoint.
+            assert_eq!(stats.unfiltered(), 2 * (CACHES + 1), "{}", kind.name());
+            assert_eq!(stats.extra_deliveries(), 0);
+        }
+    }
+
+    #[test]
+    fn presence_buckets_alias_blocks() {
+        let (mut filter, stats) = empty(SnoopFilterKind::Presence { entries: 4 });
+        fill(&mut filter, &stats, 0, 1);
+        stats.reset();
+
+        // Block 5 shares a bucket with block 1, so cache 0 may hold it.
+        assert_eq!(read(&mut filter, &stats, 1, 5), vec![Endpoint::Cache(0), Endpoint::MainMemory]);
+        assert_eq!(stats.extra_deliveries(), 1);
+        assert_eq!(read(&mut filter, &stats, 1, 2), vec![Endpoint::MainMemory]);
+        assert_eq!(stats.extra_deliveries(), 1);
+    }
+
+    #[test]
+    fn eviction_write_backs_forget_the_evicting_cache() {
+        for &kind in &[SnoopFilterKind::Presence { entries: 16 }, SnoopFilterKind::Inclusive { entries: 16 }] {
+            let (mut filter, stats) = empty(kind);
+            fill(&mut filter, &stats, 0, 3);
+
+            // Writing the block back while keeping a copy changes nothing.
+            filter.route(write_back(3, None), &stats);
+            assert_eq!(read(&mut filter, &stats, 1, 3), vec![Endpoint::Cache(0), Endpoint::MainMemory]);
+
+            filter.route(write_back(3, Some(0)), &stats);
+            assert_eq!(read(&mut filter, &stats, 1, 3), vec![Endpoint::MainMemory], "{}", kind.name());
+        }
+    }
+
+    #[test]
+    fn inclusive_filter_recalls_the_blocks_it_evicts() {
+        let (mut filter, stats) = empty(SnoopFilterKind::Inclusive { entries: 2 });
+        assert!(fill(&mut filter, &stats, 0, 0).is_empty());
+        assert!(fill(&mut filter, &stats, 1, 0).is_empty());
+        assert!(fill(&mut filter, &stats, 2, 1).is_empty());
+
+        // Block 0 is the least recently used, so goes, from both its holders.
+        let recalls: Vec<_> = fill(&mut filter, &stats, 3, 2).into_iter().map(|(endpoint, _)| endpoint).collect();
+        assert_eq!(recalls, vec![Endpoint::Cache(0), Endpoint::Cache(1)]);
+        assert_eq!(stats.filter_back_invalidations(), 1);
+        assert_eq!(read(&mut filter, &stats, 3, 0), vec![Endpoint::MainMemory]);
+
+        // A block evicted and written back by its only holder leaves room.
+        filter.route(write_back(1, Some(2)), &stats);
+        assert!(fill(&mut filter, &stats, 0, 3).is_empty());
+        assert_eq!(stats.filter_back_invalidations(), 1);
+    }
+}
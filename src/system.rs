@@ -0,0 +1,267 @@
+//! The parameters of the simulated system as a whole: how big its blocks, main
+//! memory and caches are, how many caches there are, and how long the parts
+//! that every cache shares take to do their work.
+//!
+//! These are chosen at run time, and handed to main memory, each memory cache
+//! and the bus as they are built, so that one run can sweep several systems.
+
+use memory_cache;
+
+/// The number of bytes in a block, unless configured otherwise.
+pub const DEFAULT_BLOCK_SIZE: usize = 32;
+
+/// The number of bytes of main memory, unless configured otherwise.
+pub const DEFAULT_MAIN_MEMORY_SIZE: usize = 65536;
+
+/// The number of blocks a cache can hold, unless configured otherwise.
+pub const DEFAULT_CACHE_SIZE: usize = 32;
+
+/// The number of caches to simulate, unless configured otherwise.
+pub const DEFAULT_NUMBER_OF_CACHES: usize = 8;
+
+/// The number of cycles each DRAM access spends in the memory controller and
+/// crossing its channel, on top of its bank's timings, unless configured
+/// otherwise.
+pub const DEFAULT_DRAM_LATENCY: usize = 100;
+
+/// The number of DRAM channels, unless configured otherwise.
+pub const DEFAULT_DRAM_CHANNELS: usize = 1;
+
+/// The number of ranks on each DRAM channel, unless configured otherwise.
+pub const DEFAULT_DRAM_RANKS: usize = 1;
+
+/// The number of banks in each DRAM rank, unless configured otherwise.
+pub const DEFAULT_DRAM_BANKS: usize = 8;
+
+/// The number of bytes in a DRAM row, unless configured otherwise.
+pub const DEFAULT_DRAM_ROW_SIZE: usize = 1024;
+
+/// The number of cycles it takes to read a block out of an open DRAM row
+/// (tCAS), unless configured otherwise.
+pub const DEFAULT_T_CAS: usize = 40;
+
+/// The number of cycles it takes to activate a DRAM row (tRCD), unless
+/// configured otherwise.
+pub const DEFAULT_T_RCD: usize = 40;
+
+/// The number of cycles it takes to precharge a DRAM row (tRP), unless
+/// configured otherwise.
+pub const DEFAULT_T_RP: usize = 40;
+
+/// The number of cycles a block holds its DRAM channel's data bus for
+/// (tBURST), unless configured otherwise.
+pub const DEFAULT_T_BURST: usize = 8;
+
+/// The number of cycles it takes to win the bus, unless configured otherwise.
+pub const DEFAULT_BUS_ARBITRATION: usize = 1;
+
+/// The number of cycles a message without a block holds the bus for, unless
+/// configured otherwise.
+pub const DEFAULT_BUS_TRANSFER: usize = 1;
+
+/// The number of cycles a message carrying a block holds the bus for, unless
+/// configured otherwise.
+pub const DEFAULT_BUS_BLOCK_TRANSFER: usize = 4;
+
+/// The number of cycles it takes a cache to respond to a message it snooped,
+/// unless configured otherwise.
+pub const DEFAULT_SNOOP_LATENCY: usize = 2;
+
+/// The number of cycles a message takes to cross one link of a point-to-point
+/// network, unless configured otherwise.
+pub const DEFAULT_HOP_LATENCY: usize = 1;
+
+/// The number of bytes each link of a point-to-point network carries per
+/// cycle, unless configured otherwise.
+pub const DEFAULT_LINK_BANDWIDTH: usize = 8;
+
+/// The parameters of a simulated system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SystemConfig {
+    /// The number of bytes in a block. Always a power of two.
+    pub block_size: usize,
+    /// The number of bytes of main memory. Always a multiple of the block
+    /// size.
+    pub main_memory_size: usize,
+    /// The number of blocks the level closest to the bus can hold, in the
+    /// largest cache if they differ. Always a power of two.
+    pub cache_size: usize,
+    /// The number of caches.
+    pub number_of_caches: usize,
+    /// The number of cycles each DRAM access spends in the memory controller
+    /// and crossing its channel, on top of its bank's timings.
+    pub dram_latency: usize,
+    /// The number of DRAM channels. Always a power of two.
+    pub dram_channels: usize,
+    /// The number of ranks on each DRAM channel. Always a power of two.
+    pub dram_ranks: usize,
+    /// The number of banks in each DRAM rank. Always a power of two.
+    pub dram_banks: usize,
+    /// The number of bytes in a DRAM row. Always a power of two, and at least
+    /// the block size.
+    pub dram_row_size: usize,
+    /// The number of cycles it takes to read a block out of an open DRAM row.
+    pub t_cas: usize,
+    /// The number of cycles it takes to activate a DRAM row.
+    pub t_rcd: usize,
+    /// The number of cycles it takes to precharge a DRAM row.
+    pub t_rp: usize,
+    /// The number of cycles a block holds its DRAM channel's data bus for.
+    pub t_burst: usize,
+    /// The number of cycles it takes to win the bus, before sending each
+    /// message.
+    pub bus_arbitration: usize,
+    /// The number of cycles a message without a block holds the bus for, once
+    /// it has won it.
+    pub bus_transfer: usize,
+    /// The number of cycles a message carrying a block holds the bus for, once
+    /// it has won it.
+    pub bus_block_transfer: usize,
+    /// The number of cycles it takes a cache to respond to a message it
+    /// snooped.
+    pub snoop_latency: usize,
+    /// The number of cycles a message takes to cross one link of a
+    /// point-to-point network.
+    pub hop_latency: usize,
+    /// The number of bytes each link of a point-to-point network carries per
+    /// cycle. At least one.
+    pub link_bandwidth: usize,
+}
+
+impl Default for SystemConfig {
+    fn default() -> SystemConfig {
+        SystemConfig {
+            block_size: DEFAULT_BLOCK_SIZE,
+            main_memory_size: DEFAULT_MAIN_MEMORY_SIZE,
+            cache_size: DEFAULT_CACHE_SIZE,
+            number_of_caches: DEFAULT_NUMBER_OF_CACHES,
+            dram_latency: DEFAULT_DRAM_LATENCY,
+            dram_channels: DEFAULT_DRAM_CHANNELS,
+            dram_ranks: DEFAULT_DRAM_RANKS,
+            dram_banks: DEFAULT_DRAM_BANKS,
+            dram_row_size: DEFAULT_DRAM_ROW_SIZE,
+            t_cas: DEFAULT_T_CAS,
+            t_rcd: DEFAULT_T_RCD,
+            t_rp: DEFAULT_T_RP,
+            t_burst: DEFAULT_T_BURST,
+            bus_arbitration: DEFAULT_BUS_ARBITRATION,
+            bus_transfer: DEFAULT_BUS_TRANSFER,
+            bus_block_transfer: DEFAULT_BUS_BLOCK_TRANSFER,
+            snoop_latency: DEFAULT_SNOOP_LATENCY,
+            hop_latency: DEFAULT_HOP_LATENCY,
+            link_bandwidth: DEFAULT_LINK_BANDWIDTH,
+        }
+    }
+}
+
+impl SystemConfig {
+    /// The number of blocks in main memory.
+    pub fn blocks(&self) -> usize {
+        self.main_memory_size / self.block_size
+    }
+
+    /// Check that the parameters describe a system that can be simulated.
+    pub fn validate(&self) -> Result<(), String> {
+        let max_caches = memory_cache::MemoryCacheId::MAX as usize + 1;
+        if !self.block_size.is_power_of_two() {
+            Err(format!("Invalid block size: {}\nExpected a power of two", self.block_size))
+        } else if self.main_memory_size == 0 || !self.main_memory_size.is_multiple_of(self.block_size) {
+            Err(format!("Invalid main memory size: {}\nExpected a multiple of the block size, {}",
+                        self.main_memory_size, self.block_size))
+        } else if !self.cache_size.is_power_of_two() {
+            Err(format!("Invalid cache size: {}\nExpected a power of two", self.cache_size))
+        } else if self.number_of_caches == 0 || self.number_of_caches > max_caches {
+            Err(format!("Invalid number of caches: {}\nExpected 1 to {}", self.number_of_caches, max_caches))
+        } else if self.blocks() < self.number_of_caches * self.cache_size {
+            Err(format!("Main memory of {} blocks is too small for {} caches of {} blocks\nExpected it to hold every cache's contents at once",
+                        self.blocks(), self.number_of_caches, self.cache_size))
+        } else if self.link_bandwidth == 0 {
+            Err("Invalid link bandwidth: 0\nExpected at least one byte per cycle".to_string())
+        } else if !self.dram_channels.is_power_of_two() {
+            Err(format!("Invalid number of DRAM channels: {}\nExpected a power of two", self.dram_channels))
+        } else if !self.dram_ranks.is_power_of_two() {
+            Err(format!("Invalid number of DRAM ranks: {}\nExpected a power of two", self.dram_ranks))
+        } else if !self.dram_banks.is_power_of_two() {
+            Err(format!("Invalid number of DRAM banks: {}\nExpected a power of two", self.dram_banks))
+        } else if !self.dram_row_size.is_power_of_two() || self.dram_row_size < self.block_size {
+            Err(format!("Invalid DRAM row size: {}\nExpected a power of two, at least the block size, {}",
+                        self.dram_row_size, self.block_size))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Parse a sweep of systems from a comma-separated list of parameters, each
+    /// `block`, `memory`, `cache`, `caches`, `dram` (the DRAM latency),
+    /// `arbitration`, `transfer` or `block-transfer` (the bus' cycles),
+    /// `snoop` (the snoop latency), `hop` or `link-bandwidth` (a
+    /// point-to-point network's cycles per link and bytes per cycle),
+    /// `channels`, `ranks`, `banks` or `row` (DRAM's geometry, the row in
+    /// bytes), or `tcas`, `trcd`, `trp` or `tburst` (DRAM's timings) followed
+    /// by `=` and one or more values separated by `/`. Returns every
+    /// combination of the values, in order, with unmentioned parameters left
+    /// at their defaults. For example,
+    /// `block=32/64,caches=4` is two systems of four caches each, one with
+    /// 32-byte blocks and one with 64-byte blocks.
+    pub fn parse_sweep(spec: &str) -> Result<Vec<SystemConfig>, String> {
+        let mut systems = vec![SystemConfig::default()];
+
+        for parameter in spec.split(',').filter(|parameter| !parameter.is_empty()) {
+            let mut parts = parameter.splitn(2, '=');
+            let name = parts.next().unwrap().to_lowercase();
+            let values = parts.next()
+                .ok_or_else(|| format!("Missing value for system parameter: {}", name))?
+                .split('/')
+                .map(|value| value.parse::<usize>()
+                     .map_err(|_| format!("Invalid value for system parameter {}: {}", name, value)))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let set: fn(&mut SystemConfig, usize) = match &name[..] {
+                "block" => |system, value| system.block_size = value,
+                "memory" => |system, value| system.main_memory_size = value,
+                "cache" => |system, value| system.cache_size = value,
+                "caches" => |system, value| system.number_of_caches = value,
+                "dram" => |system, value| system.dram_latency = value,
+                "arbitration" => |system, value| system.bus_arbitration = value,
+                "transfer" => |system, value| system.bus_transfer = value,
+                "block-transfer" => |system, value| system.bus_block_transfer = value,
+                "snoop" => |system, value| system.snoop_latency = value,
+                "hop" => |system, value| system.hop_latency = value,
+                "link-bandwidth" => |system, value| system.link_bandwidth = value,
+                "channels" => |system, value| system.dram_channels = value,
+                "ranks" => |system, value| system.dram_ranks = value,
+                "banks" => |system, value| system.dram_banks = value,
+                "row" => |system, value| system.dram_row_size = value,
+                "tcas" => |system, value| system.t_cas = value,
+                "trcd" => |system, value| system.t_rcd = value,
+                "trp" => |system, value| system.t_rp = value,
+                "tburst" => |system, value| system.t_burst = value,
+                _ => return Err(format!("Unknown system parameter: {}\nExpected one of: block, memory, cache, caches, dram, arbitration, transfer, block-transfer, snoop, hop, link-bandwidth, channels, ranks, banks, row, tcas, trcd, trp, tburst",
+                                        name)),
+            };
+
+            systems = systems.into_iter()
+                .flat_map(|system| values.iter().map(move |&value| {
+                    let mut system = system;
+                    set(&mut system, value);
+                    system
+                }))
+                .collect();
+        }
+
+        for system in &systems {
+            system.validate()?;
+        }
+        Ok(systems)
+    }
+
+    /// Describe the system, for reporting.
+    pub fn describe(&self) -> String {
+        format!("System: {} caches of {} blocks, {}-byte blocks, {} bytes of main memory\nTiming: {}-cycle bus arbitration, {}-cycle transfers, {}-cycle block transfers, {}-cycle snoops, {}-cycle hops, {}-byte links\nDRAM: {} channels x {} ranks x {} banks, {}-byte rows, tCAS {}, tRCD {}, tRP {}, tBURST {}, {}-cycle controller\n",
+                self.number_of_caches, self.cache_size, self.block_size, self.main_memory_size,
+                self.bus_arbitration, self.bus_transfer, self.bus_block_transfer, self.snoop_latency,
+                self.hop_latency, self.link_bandwidth,
+                self.dram_channels, self.dram_ranks, self.dram_banks, self.dram_row_size,
+                self.t_cas, self.t_rcd, self.t_rp, self.t_burst, self.dram_latency)
+    }
+}